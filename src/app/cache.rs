@@ -1,5 +1,11 @@
 use dashmap::DashMap;
-use std::{sync::Arc, time::Instant};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
 use tracing::trace;
 
 const EXPIRY_INTERVAL: u64 = 7200;
@@ -9,6 +15,14 @@ const EXPIRY_INTERVAL: u64 = 7200;
 pub struct UsersCache {
     ids: Arc<DashMap<String, (Instant, Option<String>)>>,
     logins: Arc<DashMap<String, (Instant, Option<String>)>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+pub struct CacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
 }
 
 impl UsersCache {
@@ -28,7 +42,18 @@ impl UsersCache {
         }
     }
 
+    /// Caches a login which no longer belongs to the user, without changing the user's current login
+    pub fn insert_previous_login(&self, name: String, id: String) {
+        self.logins.insert(name, (Instant::now(), Some(id)));
+    }
+
     pub fn get_login(&self, id: &str) -> Option<Option<String>> {
+        let result = self.lookup_login(id);
+        self.record_lookup(result.is_some());
+        result
+    }
+
+    fn lookup_login(&self, id: &str) -> Option<Option<String>> {
         if let Some(entry) = self.ids.get(id) {
             if entry.value().0.elapsed().as_secs() > EXPIRY_INTERVAL {
                 drop(entry);
@@ -45,6 +70,12 @@ impl UsersCache {
     }
 
     pub fn get_id(&self, name: &str) -> Option<Option<String>> {
+        let result = self.lookup_id(name);
+        self.record_lookup(result.is_some());
+        result
+    }
+
+    fn lookup_id(&self, name: &str) -> Option<Option<String>> {
         if let Some(entry) = self.logins.get(name) {
             if entry.value().0.elapsed().as_secs() > EXPIRY_INTERVAL {
                 let key = entry.key().clone();
@@ -60,4 +91,17 @@ impl UsersCache {
             None
         }
     }
+
+    fn record_lookup(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.ids.len() + self.logins.len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
 }
@@ -0,0 +1,133 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// How long to back off when Twitch reports that the rate limit was hit anyway,
+/// the bucket of an app access token is refilled within a minute
+pub const RATE_LIMITED_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Limits the amount of concurrent Twitch API requests and their rate
+#[derive(Clone)]
+pub struct HelixLimiter {
+    semaphore: Arc<Semaphore>,
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+impl HelixLimiter {
+    pub fn new(concurrency: usize, requests_per_minute: u32) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(concurrency.max(1))),
+            bucket: Arc::new(Mutex::new(TokenBucket::new(
+                requests_per_minute.max(1),
+                Instant::now(),
+            ))),
+        }
+    }
+
+    /// Waits until a request can be made. The request should be sent while holding the returned permit
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        let permit = self
+            .semaphore
+            .acquire()
+            .await
+            .expect("The semaphore is never closed");
+
+        loop {
+            let result = self.bucket.lock().unwrap().try_take(Instant::now());
+            match result {
+                Ok(()) => return permit,
+                Err(wait) => tokio::time::sleep(wait).await,
+            }
+        }
+    }
+
+    /// Stops requests until the given time has passed, after Twitch rejected one for exceeding the rate limit
+    pub fn pause(&self, duration: Duration) {
+        self.bucket
+            .lock()
+            .unwrap()
+            .drain_until(Instant::now() + duration);
+    }
+}
+
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_second: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(requests_per_minute: u32, now: Instant) -> Self {
+        let capacity = f64::from(requests_per_minute);
+        Self {
+            capacity,
+            tokens: capacity,
+            refill_per_second: capacity / 60.0,
+            last_refill: now,
+        }
+    }
+
+    /// Takes a token, or returns how long to wait until one is available
+    fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        if let Some(elapsed) = now.checked_duration_since(self.last_refill) {
+            self.tokens =
+                (self.tokens + elapsed.as_secs_f64() * self.refill_per_second).min(self.capacity);
+            self.last_refill = now;
+        } else {
+            // Paused until `last_refill`
+            return Err(self.last_refill - now);
+        }
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let missing = 1.0 - self.tokens;
+            Err(Duration::from_secs_f64(missing / self.refill_per_second))
+        }
+    }
+
+    fn drain_until(&mut self, until: Instant) {
+        self.tokens = 0.0;
+        self.last_refill = self.last_refill.max(until);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokenBucket;
+    use pretty_assertions::assert_eq;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(60, start);
+
+        for _ in 0..60 {
+            assert_eq!(Ok(()), bucket.try_take(start));
+        }
+        assert_eq!(Err(Duration::from_secs(1)), bucket.try_take(start));
+        assert_eq!(Ok(()), bucket.try_take(start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn drained_bucket_waits_until_reset() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(60, start);
+
+        bucket.drain_until(start + Duration::from_secs(30));
+        assert_eq!(
+            Err(Duration::from_secs(20)),
+            bucket.try_take(start + Duration::from_secs(10))
+        );
+        assert_eq!(
+            Err(Duration::from_secs(1)),
+            bucket.try_take(start + Duration::from_secs(30))
+        );
+        assert_eq!(Ok(()), bucket.try_take(start + Duration::from_secs(31)));
+    }
+}
@@ -1,27 +1,49 @@
 pub mod cache;
+pub mod helix_limiter;
+pub mod webhooks;
 
-use self::cache::UsersCache;
+use self::{
+    cache::UsersCache,
+    helix_limiter::{HelixLimiter, RATE_LIMITED_BACKOFF},
+    webhooks::Webhooks,
+};
 use crate::{
     config::Config,
-    db::{delete_user_logs, writer::FlushBuffer},
+    db::{delete_user_logs, read_user_id_by_login, writer::FlushBuffer},
     error::Error,
-    Result,
+    Result, ShutdownRx,
 };
 use anyhow::Context;
 use dashmap::DashSet;
-use std::{collections::HashMap, sync::Arc};
-use tracing::{debug, info};
-use twitch_api::{helix::users::GetUsersRequest, twitch_oauth2::AppAccessToken, HelixClient};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+use twitch_api::{
+    helix::{users::GetUsersRequest, ClientRequestError, HelixRequestGetError},
+    twitch_oauth2::AppAccessToken,
+    HelixClient,
+};
+
+const HELIX_REQUEST_ATTEMPTS: u32 = 4;
+const HELIX_RETRY_INITIAL_DELAY: Duration = Duration::from_millis(500);
 
 #[derive(Clone)]
 pub struct App {
     pub helix_client: HelixClient<'static, reqwest::Client>,
+    pub helix_limiter: HelixLimiter,
     pub token: Arc<AppAccessToken>,
     pub users: UsersCache,
     pub optout_codes: Arc<DashSet<String>>,
     pub db: Arc<clickhouse::Client>,
     pub config: Arc<Config>,
+    pub webhooks: Webhooks,
     pub flush_buffer: FlushBuffer,
+    pub started_at: Instant,
+    pub shutdown_rx: ShutdownRx,
 }
 
 impl App {
@@ -66,16 +88,22 @@ impl App {
         for chunk in ids_to_request.chunks(100) {
             debug!("Requesting user info for ids {chunk:?}");
 
-            let request = GetUsersRequest::ids(chunk);
-            let response = self.helix_client.req_get(request, &*self.token).await?;
+            let response = retry_helix(&self.helix_limiter, || {
+                self.helix_client
+                    .req_get(GetUsersRequest::ids(chunk), &*self.token)
+            })
+            .await?;
             new_users.extend(response.data);
         }
 
         for chunk in names_to_request.chunks(100) {
             debug!("Requesting user info for names {chunk:?}");
 
-            let request = GetUsersRequest::logins(chunk);
-            let response = self.helix_client.req_get(request, &*self.token).await?;
+            let response = retry_helix(&self.helix_limiter, || {
+                self.helix_client
+                    .req_get(GetUsersRequest::logins(chunk), &*self.token)
+            })
+            .await?;
             new_users.extend(response.data);
         }
 
@@ -103,13 +131,38 @@ impl App {
         Ok(users)
     }
 
+    /// Resolves the id of a logged channel from the stored channel logins, falling back to the Twitch API
+    pub async fn get_channel_id_by_name(&self, name: &str) -> Result<String> {
+        if let Some(channel_id) = self.config.channel_aliases.get(&name.to_lowercase()) {
+            return Ok(channel_id.value().clone());
+        }
+
+        let pinned_id = self
+            .config
+            .channel_logins
+            .iter()
+            .find(|entry| entry.value().eq_ignore_ascii_case(name))
+            .map(|entry| entry.key().clone());
+
+        match pinned_id {
+            Some(channel_id) => Ok(channel_id),
+            None => match self.get_user_id_by_name(name).await {
+                Err(Error::UserNotFound) => Err(Error::ChannelNotFound),
+                result => result,
+            },
+        }
+    }
+
     pub async fn get_user_id_by_name(&self, name: &str) -> Result<String> {
         match self.users.get_id(name) {
             Some(Some(id)) => Ok(id),
-            Some(None) => Err(Error::NotFound),
+            Some(None) => Err(Error::UserNotFound),
             None => {
-                let request = GetUsersRequest::logins(vec![name]);
-                let response = self.helix_client.req_get(request, &*self.token).await?;
+                let response = retry_helix(&self.helix_limiter, || {
+                    self.helix_client
+                        .req_get(GetUsersRequest::logins(vec![name]), &*self.token)
+                })
+                .await?;
                 match response.data.into_iter().next() {
                     Some(user) => {
                         let user_id = user.id.to_string();
@@ -117,8 +170,16 @@ impl App {
                         Ok(user_id)
                     }
                     None => {
+                        if self.config.resolve_previous_logins {
+                            if let Some(user_id) = read_user_id_by_login(&self.db, name).await? {
+                                debug!("Resolved previous login {name} to user {user_id}");
+                                self.users
+                                    .insert_previous_login(name.to_owned(), user_id.clone());
+                                return Ok(user_id);
+                            }
+                        }
                         self.users.insert_optional(None, Some(name.to_owned()));
-                        Err(Error::NotFound)
+                        Err(Error::UserNotFound)
                     }
                 }
             }
@@ -151,3 +212,61 @@ impl App {
         Ok(())
     }
 }
+
+/// Retries a Twitch API request with exponential backoff, so that transient errors (e.g. rate limits, server or network issues)
+/// don't immediately fail the whole operation. Other errors are returned right away.
+/// All requests go through the limiter to stay below Twitch's rate limits
+async fn retry_helix<T, F, Fut>(
+    limiter: &HelixLimiter,
+    mut request: F,
+) -> std::result::Result<T, ClientRequestError<reqwest::Error>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = std::result::Result<T, ClientRequestError<reqwest::Error>>>,
+{
+    let mut delay = HELIX_RETRY_INITIAL_DELAY;
+    let mut attempt = 1;
+
+    loop {
+        let result = {
+            let _permit = limiter.acquire().await;
+            request().await
+        };
+
+        match result {
+            Ok(response) => return Ok(response),
+            Err(err) if attempt < HELIX_REQUEST_ATTEMPTS && is_transient(&err) => {
+                if is_rate_limited(&err) {
+                    // The response headers are not exposed by the client, so the reset time is not known exactly
+                    warn!("Twitch API rate limit exceeded (attempt {attempt}/{HELIX_REQUEST_ATTEMPTS}), pausing requests for {RATE_LIMITED_BACKOFF:?}");
+                    limiter.pause(RATE_LIMITED_BACKOFF);
+                } else {
+                    warn!("Twitch API request failed (attempt {attempt}/{HELIX_REQUEST_ATTEMPTS}), retrying in {delay:?}: {err}");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Whether the request failed because of the connection, a server error or the rate limit
+fn is_transient(err: &ClientRequestError<reqwest::Error>) -> bool {
+    match err {
+        ClientRequestError::RequestError(_) => true,
+        ClientRequestError::HelixRequestGetError(HelixRequestGetError::Error {
+            status, ..
+        }) => status.is_server_error() || status.as_u16() == 429,
+        _ => false,
+    }
+}
+
+fn is_rate_limited(err: &ClientRequestError<reqwest::Error>) -> bool {
+    matches!(
+        err,
+        ClientRequestError::HelixRequestGetError(HelixRequestGetError::Error { status, .. })
+            if status.as_u16() == 429
+    )
+}
@@ -0,0 +1,233 @@
+use crate::{
+    config::{Config, WebhookConfig},
+    db::schema::{MessageFlags, MessageType, StructuredMessage},
+    logs::schema::message::{FullMessage, ResponseMessage},
+};
+use reqwest::header::CONTENT_TYPE;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    Semaphore,
+};
+use tracing::{debug, error, warn};
+
+/// Messages waiting to be delivered, further ones are dropped
+const QUEUE_SIZE: usize = 1000;
+const MAX_CONCURRENT_DELIVERIES: usize = 8;
+const DELIVERY_ATTEMPTS: u32 = 4;
+const RETRY_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts logged messages to the configured webhooks
+#[derive(Clone)]
+pub struct Webhooks {
+    config: Arc<Config>,
+    tx: Option<mpsc::Sender<(usize, StructuredMessage<'static>)>>,
+}
+
+impl Webhooks {
+    /// Starts the delivery task if any webhooks are configured
+    pub fn start(config: Arc<Config>) -> Self {
+        if config.webhooks.is_empty() {
+            return Self { config, tx: None };
+        }
+
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(deliver_messages(config.clone(), rx));
+        Self {
+            config,
+            tx: Some(tx),
+        }
+    }
+
+    /// Queues the message for every webhook it triggers
+    pub fn notify(&self, msg: &StructuredMessage) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+
+        for (i, webhook) in self.config.webhooks.iter().enumerate() {
+            if !triggers(webhook, msg) {
+                continue;
+            }
+
+            match tx.try_send((i, msg.clone().into_owned())) {
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) => {
+                    warn!(
+                        "Webhook queue is full, dropping message for {}",
+                        webhook.url
+                    );
+                }
+                Err(TrySendError::Closed(_)) => error!("Webhook delivery task is not running"),
+            }
+        }
+    }
+}
+
+/// All configured conditions of the webhook have to match
+fn triggers(webhook: &WebhookConfig, msg: &StructuredMessage) -> bool {
+    if !webhook.channels.is_empty()
+        && !webhook
+            .channels
+            .iter()
+            .any(|channel_id| channel_id == &msg.channel_id)
+    {
+        return false;
+    }
+
+    if !webhook.message_types.is_empty() {
+        let message_type = msg.message_type.to_string();
+        if !webhook
+            .message_types
+            .iter()
+            .any(|allowed_type| allowed_type.eq_ignore_ascii_case(&message_type))
+        {
+            return false;
+        }
+    }
+
+    if webhook.first_messages_only
+        && !(msg.message_type == MessageType::PrivMsg
+            && msg.message_flags.contains(MessageFlags::FIRST_MSG))
+    {
+        return false;
+    }
+
+    if let Some(pattern) = &webhook.text_pattern {
+        if !pattern.0.is_match(&msg.user_friendly_text()) {
+            return false;
+        }
+    }
+
+    true
+}
+
+async fn deliver_messages(
+    config: Arc<Config>,
+    mut rx: mpsc::Receiver<(usize, StructuredMessage<'static>)>,
+) {
+    let client = reqwest::Client::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        .build()
+        .expect("Could not build HTTP client");
+    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_DELIVERIES));
+
+    while let Some((i, msg)) = rx.recv().await {
+        let url = config.webhooks[i].url.clone();
+        let body = match FullMessage::from_structured(&msg)
+            .and_then(|message| Ok(serde_json::to_vec(&message)?))
+        {
+            Ok(body) => body,
+            Err(err) => {
+                error!("Could not serialize message {msg:?} for webhook: {err}");
+                continue;
+            }
+        };
+
+        let permit = semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("The semaphore is never closed");
+        let client = client.clone();
+        tokio::spawn(async move {
+            deliver(&client, &url, body).await;
+            drop(permit);
+        });
+    }
+}
+
+async fn deliver(client: &reqwest::Client, url: &str, body: Vec<u8>) {
+    let mut delay = RETRY_INITIAL_DELAY;
+
+    for attempt in 1..=DELIVERY_ATTEMPTS {
+        let result = client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.clone())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        match result {
+            Ok(_) => {
+                debug!("Delivered message to webhook {url}");
+                return;
+            }
+            Err(err) if attempt < DELIVERY_ATTEMPTS => {
+                warn!("Webhook {url} failed (attempt {attempt}), retrying in {delay:?}: {err}");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(err) => error!("Could not deliver message to webhook {url}: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::triggers;
+    use crate::{
+        config::{FilterPattern, WebhookConfig},
+        db::schema::{StructuredMessage, UnstructuredMessage},
+    };
+    use regex::Regex;
+
+    fn webhook() -> WebhookConfig {
+        WebhookConfig {
+            url: "http://localhost/hook".to_owned(),
+            channels: vec![],
+            message_types: vec![],
+            first_messages_only: false,
+            text_pattern: None,
+        }
+    }
+
+    #[test]
+    fn trigger_conditions() {
+        let first_msg = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "68136884",
+            timestamp: 1709251274940,
+            raw: "@badges=;color=;display-name=Supibot;emotes=;first-msg=1;id=272e342c-5864-4c59-b730-25908cdb7f57;mod=0;room-id=22484632;subscriber=0;tmi-sent-ts=1709251274940;turbo=0;user-id=68136884;user-type= :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :hello chat",
+        };
+        let first_msg = StructuredMessage::from_unstructured(&first_msg).unwrap();
+        let ban = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "68136884",
+            timestamp: 1709251274940,
+            raw: "@room-id=22484632;target-user-id=68136884;tmi-sent-ts=1709251274940 :tmi.twitch.tv CLEARCHAT #forsen :supibot",
+        };
+        let ban = StructuredMessage::from_unstructured(&ban).unwrap();
+
+        assert!(triggers(&webhook(), &first_msg));
+
+        let other_channel = WebhookConfig {
+            channels: vec!["11148817".to_owned()],
+            ..webhook()
+        };
+        assert!(!triggers(&other_channel, &first_msg));
+
+        let bans = WebhookConfig {
+            message_types: vec!["clearchat".to_owned()],
+            ..webhook()
+        };
+        assert!(triggers(&bans, &ban));
+        assert!(!triggers(&bans, &first_msg));
+
+        let first_messages = WebhookConfig {
+            first_messages_only: true,
+            ..webhook()
+        };
+        assert!(triggers(&first_messages, &first_msg));
+        assert!(!triggers(&first_messages, &ban));
+
+        let pattern = WebhookConfig {
+            text_pattern: Some(FilterPattern(Regex::new("^hello").unwrap())),
+            ..webhook()
+        };
+        assert!(triggers(&pattern, &first_msg));
+        assert!(!triggers(&pattern, &ban));
+    }
+}
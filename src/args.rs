@@ -1,3 +1,8 @@
+use crate::{
+    logs::extract::TimestampPolicy,
+    migrator::{InvalidUtf8Handling, DEFAULT_READ_BUFFER_SIZE},
+};
+use chrono::NaiveDate;
 use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
@@ -20,5 +25,54 @@ pub enum Command {
         /// Parallel migration jobs
         #[clap(short, long, default_value_t = 1)]
         jobs: usize,
+        /// Only parse the logs and report how many lines would be migrated, without writing anything
+        #[clap(long)]
+        dry_run: bool,
+        /// Only reimport the given day (YYYY-MM-DD), replacing the logs already stored for it.
+        /// Messages logged live on that day which are missing from the source logs are removed as well
+        #[clap(long, value_parser)]
+        date: Option<NaiveDate>,
+        /// Only migrate PRIVMSG (chat) messages, dropping all other message types
+        #[clap(long)]
+        privmsg_only: bool,
+        /// Buffer size (in bytes) used for reading log files
+        #[clap(long, default_value_t = DEFAULT_READ_BUFFER_SIZE)]
+        read_buffer_size: usize,
+        /// How to handle lines which are not valid UTF-8
+        #[clap(long, value_enum, default_value_t)]
+        invalid_utf8: InvalidUtf8Handling,
+        /// Report chat messages which appear more than once in a day's logs (with the same message id)
+        #[clap(long)]
+        report_duplicate_ids: bool,
+        /// How to handle messages with a timestamp far from the day of their log file
+        #[clap(long, value_enum, default_value_t)]
+        timestamp_policy: TimestampPolicy,
+    },
+    /// Compare migrated justlog logs with the database
+    Verify {
+        /// The justlog logs folder
+        #[clap(short, long, value_parser)]
+        source_dir: String,
+        /// List of channel ids to verify (None specified = verify all)
+        #[clap(short, long, value_parser)]
+        channel_id: Vec<String>,
+        /// Only count PRIVMSG messages, for logs migrated with `--privmsg-only`
+        #[clap(long)]
+        privmsg_only: bool,
+        /// Buffer size (in bytes) used for reading log files
+        #[clap(long, default_value_t = DEFAULT_READ_BUFFER_SIZE)]
+        read_buffer_size: usize,
+        /// How to handle lines which are not valid UTF-8
+        #[clap(long, value_enum, default_value_t)]
+        invalid_utf8: InvalidUtf8Handling,
+        /// How to handle messages with a timestamp far from the day of their log file, as used for the migration
+        #[clap(long, value_enum, default_value_t)]
+        timestamp_policy: TimestampPolicy,
+    },
+    /// Build the secondary indexes of the messages table for already stored logs
+    RebuildIndexes {
+        /// Names of the indexes to rebuild (None specified = rebuild all)
+        #[clap(short, long, value_parser)]
+        index: Vec<String>,
     },
 }
@@ -1,16 +1,20 @@
 use crate::{
     app::App,
     db::schema::{StructuredMessage, UnstructuredMessage},
-    logs::extract::{extract_channel_and_user_from_raw, extract_raw_timestamp},
+    logs::extract::{
+        extract_channel_and_user_from_raw, extract_raw_timestamp, has_invalid_user_id,
+        resolve_timestamp,
+    },
     ShutdownRx,
 };
 use anyhow::{anyhow, Context};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use futures::future::join_all;
 use lazy_static::lazy_static;
-use prometheus::{register_int_counter_vec, IntCounterVec};
-use std::time::Duration;
+use prometheus::{core::Collector, register_int_counter_vec, IntCounterVec};
+use std::{sync::Arc, time::Duration};
 use tokio::{
-    sync::mpsc::{Receiver, Sender},
+    sync::mpsc::{Receiver, Sender, UnboundedReceiver},
     time::sleep,
 };
 use tracing::{debug, error, info, log::warn, trace};
@@ -22,9 +26,50 @@ use twitch_irc::{
 
 const CHANNEL_REJOIN_INTERVAL_SECONDS: u64 = 3600;
 const CHANENLS_REFETCH_RETRY_INTERVAL_SECONDS: u64 = 5;
+const CHANNELS_REFETCH_MAX_RETRY_INTERVAL_SECONDS: u64 = 300;
 
 type TwitchClient<C> = TwitchIRCClient<SecureTCPTransport, C>;
 
+/// IRC clients between which the logged channels are split
+struct Shards<C: LoginCredentials> {
+    clients: Arc<Vec<TwitchClient<C>>>,
+}
+
+impl<C: LoginCredentials> Shards<C> {
+    fn client_for(&self, channel_id: &str) -> &TwitchClient<C> {
+        &self.clients[shard_for(channel_id, self.clients.len())]
+    }
+}
+
+impl<C: LoginCredentials> Clone for Shards<C> {
+    fn clone(&self) -> Self {
+        Self {
+            clients: self.clients.clone(),
+        }
+    }
+}
+
+async fn request_membership_events<C: LoginCredentials>(client: &TwitchClient<C>) {
+    let cap_req = IRCMessage::new_simple(
+        "CAP".to_owned(),
+        vec!["REQ".to_owned(), "twitch.tv/membership".to_owned()],
+    );
+    if let Err(err) = client.send_message(cap_req).await {
+        warn!(error = %err, "Could not request membership events");
+    }
+}
+
+/// Index of the IRC connection shard which logs the channel
+pub fn shard_for(channel_id: &str, shard_count: usize) -> usize {
+    // FNV-1a, so that channels stay on the same shard across restarts
+    let hash = channel_id
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    (hash % shard_count.max(1) as u64) as usize
+}
+
 #[derive(Debug)]
 pub enum BotMessage {
     JoinChannels(Vec<String>),
@@ -38,11 +83,27 @@ lazy_static! {
         &["channel_id"]
     )
     .unwrap();
+    static ref MESSAGE_PARSE_ERRORS_COUNTERS: IntCounterVec = register_int_counter_vec!(
+        "rustlog_message_parse_errors",
+        "How many messages could not be converted to be logged",
+        &["channel_id", "reason"]
+    )
+    .unwrap();
 }
 
 const COMMAND_PREFIX: &str = "!rustlog ";
 
-pub async fn run<C: LoginCredentials>(
+/// Total amount of messages logged in all channels since startup
+pub fn messages_received_total() -> u64 {
+    MESSAGES_RECEIVED_COUNTERS
+        .collect()
+        .iter()
+        .flat_map(|family| family.get_metric())
+        .map(|metric| metric.get_counter().get_value() as u64)
+        .sum()
+}
+
+pub async fn run<C: LoginCredentials + Clone>(
     login_credentials: C,
     app: App,
     writer_tx: Sender<StructuredMessage<'static>>,
@@ -64,18 +125,38 @@ impl Bot {
         Self { app, writer_tx }
     }
 
-    pub async fn run<C: LoginCredentials>(
+    pub async fn run<C: LoginCredentials + Clone>(
         self,
         login_credentials: C,
-        mut shutdown_rx: ShutdownRx,
+        shutdown_rx: ShutdownRx,
         mut command_rx: Receiver<BotMessage>,
     ) {
-        let client_config = ClientConfig::new_simple(login_credentials);
-        let (mut receiver, client) = TwitchIRCClient::<SecureTCPTransport, C>::new(client_config);
+        let shard_count = self.app.config.irc_shards.max(1);
+        let mut clients = Vec::with_capacity(shard_count);
+        let mut receivers = Vec::with_capacity(shard_count);
+        for _ in 0..shard_count {
+            let mut client_config = ClientConfig::new_simple(login_credentials.clone());
+            if self.app.config.log_membership_events {
+                // Membership events are requested on the connection of each client (see `receive_messages`),
+                // which only reaches all of a client's channels if it doesn't open more connections
+                client_config.max_channels_per_connection = usize::MAX;
+            }
+            let (receiver, client) = TwitchIRCClient::<SecureTCPTransport, C>::new(client_config);
+            clients.push(client);
+            receivers.push(receiver);
+        }
+        let shards = Shards {
+            clients: Arc::new(clients),
+        };
+        if shard_count > 1 {
+            info!(shard_count, "Splitting channels between IRC connections");
+        }
 
         let app = self.app.clone();
-        let join_client = client.clone();
+        let join_shards = shards.clone();
         tokio::spawn(async move {
+            let mut retry_interval = CHANENLS_REFETCH_RETRY_INTERVAL_SECONDS;
+
             loop {
                 let channel_ids = app.config.channels.read().unwrap().clone();
 
@@ -84,18 +165,35 @@ impl Bot {
                     .await
                 {
                     Ok(users) => {
-                        info!("Joining {} channels", users.len());
-                        for channel_login in users.into_values() {
+                        info!(count = users.len(), "Joining channels");
+                        let mut logins_changed = false;
+                        for (channel_id, channel_login) in users {
                             debug!("Logging channel {channel_login}");
-                            join_client
+                            let previous_login = app
+                                .config
+                                .channel_logins
+                                .insert(channel_id.clone(), channel_login.clone());
+                            logins_changed |= previous_login.as_ref() != Some(&channel_login);
+
+                            join_shards
+                                .client_for(&channel_id)
                                 .join(channel_login)
                                 .expect("Failed to join channel");
                         }
+                        if logins_changed {
+                            if let Err(err) = app.config.save() {
+                                error!(error = %err, "Could not save channel logins");
+                            }
+                        }
+                        retry_interval = CHANENLS_REFETCH_RETRY_INTERVAL_SECONDS;
                         CHANNEL_REJOIN_INTERVAL_SECONDS
                     }
                     Err(err) => {
-                        error!("Could not fetch users list: {err}");
-                        CHANENLS_REFETCH_RETRY_INTERVAL_SECONDS
+                        error!(retry_interval_seconds = retry_interval, error = %err, "Could not fetch users list, retrying");
+                        let interval = retry_interval;
+                        retry_interval =
+                            (retry_interval * 2).min(CHANNELS_REFETCH_MAX_RETRY_INTERVAL_SECONDS);
+                        interval
                     }
                 };
                 sleep(Duration::from_secs(interval)).await;
@@ -103,47 +201,90 @@ impl Bot {
         });
 
         let bot = self.clone();
-        let msg_client = client.clone();
+        let msg_shards = shards.clone();
         tokio::spawn(async move {
             while let Some(msg) = command_rx.recv().await {
                 match msg {
                     BotMessage::JoinChannels(channels) => {
                         if let Err(err) = bot
                             .update_channels(
-                                &msg_client,
+                                &msg_shards,
                                 &channels.iter().map(String::as_str).collect::<Vec<_>>(),
                                 ChannelAction::Join,
                             )
                             .await
                         {
-                            error!("Could not join channels: {err}");
+                            error!(error = %err, "Could not join channels");
                         }
                     }
                     BotMessage::PartChannels(channels) => {
                         if let Err(err) = bot
                             .update_channels(
-                                &msg_client,
+                                &msg_shards,
                                 &channels.iter().map(String::as_str).collect::<Vec<_>>(),
                                 ChannelAction::Part,
                             )
                             .await
                         {
-                            error!("Could not join channels: {err}");
+                            error!(error = %err, "Could not part channels");
                         }
                     }
                 }
             }
         });
 
+        // Every shard is handled by its own task. A channel is always on the same shard,
+        // so its messages are still written in the order they were received
+        let shard_tasks = receivers.into_iter().enumerate().map(|(shard, receiver)| {
+            let bot = self.clone();
+            let shards = shards.clone();
+            let shutdown_rx = shutdown_rx.clone();
+            tokio::spawn(async move {
+                bot.receive_messages(shard, receiver, &shards, shutdown_rx)
+                    .await;
+            })
+        });
+        join_all(shard_tasks).await;
+    }
+
+    async fn receive_messages<C: LoginCredentials>(
+        &self,
+        shard: usize,
+        mut receiver: UnboundedReceiver<ServerMessage>,
+        shards: &Shards<C>,
+        mut shutdown_rx: ShutdownRx,
+    ) {
+        let gap_warning_threshold =
+            chrono::Duration::seconds(self.app.config.connection_gap_warning_seconds as i64);
+        let mut last_received_at: Option<DateTime<Utc>> = None;
+
         loop {
             tokio::select! {
                 Some(msg) = receiver.recv() => {
-                    if let Err(e) = self.handle_message(msg, &client).await {
-                        error!("Could not handle message: {e}");
+                    let now = Utc::now();
+                    if let Some(last_received_at) = last_received_at {
+                        if now - last_received_at > gap_warning_threshold {
+                            warn!(
+                                shard,
+                                from = %last_received_at,
+                                to = %now,
+                                "No messages were received on IRC shard, logs in this period may be incomplete"
+                            );
+                        }
+                    }
+                    last_received_at = Some(now);
+
+                    // The welcome is received on every new connection, including reconnects, which don't keep the capability
+                    if self.app.config.log_membership_events && msg.source().command == "001" {
+                        request_membership_events(&shards.clients[shard]).await;
+                    }
+
+                    if let Err(e) = self.handle_message(msg, shards).await {
+                        error!(error = %e, "Could not handle message");
                     }
                 }
                 _ = shutdown_rx.changed() => {
-                    debug!("Shutting down bot task");
+                    debug!("Shutting down bot task of IRC shard {shard}");
                     break;
                 }
             }
@@ -153,16 +294,16 @@ impl Bot {
     async fn handle_message<C: LoginCredentials>(
         &self,
         msg: ServerMessage,
-        client: &TwitchClient<C>,
+        shards: &Shards<C>,
     ) -> anyhow::Result<()> {
         if let ServerMessage::Privmsg(privmsg) = &msg {
             trace!("Processing message {}", privmsg.message_text);
             if let Some(cmd) = privmsg.message_text.strip_prefix(COMMAND_PREFIX) {
                 if let Err(err) = self
-                    .handle_command(cmd, client, &privmsg.sender.id, &privmsg.sender.login)
+                    .handle_command(cmd, shards, &privmsg.sender.id, &privmsg.sender.login)
                     .await
                 {
-                    warn!("Could not handle command {cmd}: {err:#}");
+                    warn!(command = cmd, error = %format_args!("{err:#}"), "Could not handle command");
                 }
             }
         }
@@ -186,13 +327,66 @@ impl Bot {
         }
     }
 
+    /// JOIN and PART messages don't have any tags, so the ids and timestamp are added to be stored like other messages.
+    /// Returns `None` if membership events are not logged or the channel is unknown
+    fn membership_message(
+        &self,
+        command: &str,
+        channel_login: &str,
+        user_login: &str,
+    ) -> Option<IRCMessage> {
+        if !self.app.config.log_membership_events {
+            return None;
+        }
+
+        let channel_id = self
+            .app
+            .config
+            .channel_logins
+            .iter()
+            .find(|entry| entry.value() == channel_login)
+            .map(|entry| entry.key().clone())?;
+        let timestamp = Utc::now().timestamp_millis();
+        // Only known if the user was looked up before, Twitch doesn't send it
+        let user_id_tag = match self.app.users.get_id(user_login) {
+            Some(Some(user_id)) => format!(";user-id={user_id}"),
+            _ => String::new(),
+        };
+
+        let raw = format!("@room-id={channel_id};tmi-sent-ts={timestamp}{user_id_tag} :{user_login}!{user_login}@{user_login}.tmi.twitch.tv {command} #{channel_login}");
+        match IRCMessage::parse(&raw) {
+            Ok(irc_message) => Some(irc_message),
+            Err(err) => {
+                error!(command, %raw, error = %err, "Could not build message");
+                None
+            }
+        }
+    }
+
     async fn write_message(&self, msg: ServerMessage) -> anyhow::Result<()> {
         // Ignore
         if matches!(msg, ServerMessage::RoomState(_)) {
             return Ok(());
         }
+        if self.app.config.privmsg_only && !matches!(msg, ServerMessage::Privmsg(_)) {
+            return Ok(());
+        }
 
-        let irc_message = IRCMessage::from(msg);
+        let irc_message = match msg {
+            ServerMessage::Join(join) => {
+                match self.membership_message("JOIN", &join.channel_login, &join.user_login) {
+                    Some(irc_message) => irc_message,
+                    None => return Ok(()),
+                }
+            }
+            ServerMessage::Part(part) => {
+                match self.membership_message("PART", &part.channel_login, &part.user_login) {
+                    Some(irc_message) => irc_message,
+                    None => return Ok(()),
+                }
+            }
+            msg => IRCMessage::from(msg),
+        };
 
         if let Some((channel_id, maybe_user_id)) = extract_channel_and_user_from_raw(&irc_message) {
             if !channel_id.is_empty() {
@@ -201,8 +395,21 @@ impl Bot {
                     .inc();
             }
 
-            let timestamp = extract_raw_timestamp(&irc_message)
-                .unwrap_or_else(|| Utc::now().timestamp_millis().try_into().unwrap());
+            let received_at = Utc::now().timestamp_millis().try_into().unwrap();
+            let Some(timestamp) = resolve_timestamp(
+                extract_raw_timestamp(&irc_message),
+                received_at,
+                self.app.config.timestamp_policy,
+            ) else {
+                return Ok(());
+            };
+            if has_invalid_user_id(&irc_message) {
+                warn!(
+                    %channel_id,
+                    raw = %irc_message.as_raw_irc(),
+                    "Ignoring invalid user id in message"
+                );
+            }
             let user_id = maybe_user_id.unwrap_or_default().to_owned();
 
             if self.app.config.opt_out.contains_key(&user_id) {
@@ -216,12 +423,42 @@ impl Bot {
                 timestamp,
                 raw: &raw_irc,
             };
-            match StructuredMessage::from_unstructured(&unstructured) {
-                Ok(msg) => {
+            let structured = if self.app.config.log_unknown_messages {
+                StructuredMessage::from_unstructured_allow_unknown(&unstructured)
+            } else {
+                StructuredMessage::from_unstructured(&unstructured)
+            };
+            match structured {
+                Ok(mut msg) => {
+                    let allowed = self
+                        .app
+                        .config
+                        .ingest_filters
+                        .read()
+                        .unwrap()
+                        .get(channel_id)
+                        .map_or(true, |filter| filter.allows(&msg));
+                    if !allowed {
+                        trace!("Message filtered out from logging: {msg:?}");
+                        return Ok(());
+                    }
+
+                    if msg.truncate_text(self.app.config.max_message_length) {
+                        warn!(
+                            %channel_id,
+                            %user_id,
+                            "Truncated message exceeding the maximum length"
+                        );
+                    }
+
+                    self.app.webhooks.notify(&msg);
                     self.writer_tx.send(msg.into_owned()).await?;
                 }
                 Err(err) => {
-                    error!("Could not convert message {unstructured:?} to be logged: {err}");
+                    MESSAGE_PARSE_ERRORS_COUNTERS
+                        .with_label_values(&[channel_id, err.category()])
+                        .inc();
+                    error!(?unstructured, error = %err, "Could not convert message to be logged");
                 }
             }
         }
@@ -232,7 +469,7 @@ impl Bot {
     async fn handle_command<C: LoginCredentials>(
         &self,
         cmd: &str,
-        client: &TwitchClient<C>,
+        shards: &Shards<C>,
         sender_id: &str,
         sender_login: &str,
     ) -> anyhow::Result<()> {
@@ -244,12 +481,12 @@ impl Bot {
             match action {
                 "join" => {
                     self.check_admin(sender_login)?;
-                    self.update_channels(client, &args, ChannelAction::Join)
+                    self.update_channels(shards, &args, ChannelAction::Join)
                         .await?
                 }
                 "leave" | "part" => {
                     self.check_admin(sender_login)?;
-                    self.update_channels(client, &args, ChannelAction::Part)
+                    self.update_channels(shards, &args, ChannelAction::Part)
                         .await?
                 }
                 /*"optout" => {
@@ -286,7 +523,7 @@ impl Bot {
 
     async fn update_channels<C: LoginCredentials>(
         &self,
-        client: &TwitchClient<C>,
+        shards: &Shards<C>,
         channels: &[&str],
         action: ChannelAction,
     ) -> anyhow::Result<()> {
@@ -309,14 +546,19 @@ impl Bot {
             for (channel_id, channel_name) in channels {
                 match action {
                     ChannelAction::Join => {
-                        info!("Joining channel {channel_name}");
+                        info!(channel = %channel_name, "Joining channel");
+                        self.app
+                            .config
+                            .channel_logins
+                            .insert(channel_id.clone(), channel_name.clone());
+                        shards.client_for(&channel_id).join(channel_name)?;
                         config_channels.insert(channel_id);
-                        client.join(channel_name)?;
                     }
                     ChannelAction::Part => {
-                        info!("Parting channel {channel_name}");
+                        info!(channel = %channel_name, "Parting channel");
+                        self.app.config.channel_logins.remove(&channel_id);
+                        shards.client_for(&channel_id).part(channel_name);
                         config_channels.remove(&channel_id);
-                        client.part(channel_name);
                     }
                 }
             }
@@ -332,3 +574,20 @@ enum ChannelAction {
     Join,
     Part,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::shard_for;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn shard_assignment() {
+        assert_eq!(0, shard_for("22484632", 1));
+        assert_eq!(0, shard_for("22484632", 0));
+
+        let shards: Vec<_> = (0..100).map(|id| shard_for(&id.to_string(), 4)).collect();
+        assert!(shards.iter().all(|shard| *shard < 4));
+        assert!((0..4).all(|shard| shards.contains(&shard)));
+        assert_eq!(shards[42], shard_for("42", 4));
+    }
+}
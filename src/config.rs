@@ -1,12 +1,26 @@
+use crate::{
+    db::schema::{MessageType, StructuredMessage},
+    logs::extract::TimestampPolicy,
+};
 use anyhow::Context;
 use dashmap::DashMap;
-use serde::{Deserialize, Serialize};
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
 use std::fs;
-use std::{collections::HashSet, sync::RwLock};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
 use tracing::info;
 
 const CONFIG_FILE_NAME: &str = "config.json";
 
+/// Settings which are applied by `Config::reload`, all others only take effect after a restart
+const RELOADABLE_SETTINGS: &[&str] = &["channels", "optOut", "channelAliases", "ingestFilters"];
+/// Maintained by the service itself rather than edited by hand, or compared separately
+const IGNORED_SETTINGS: &[&str] = &["channelLogins", "channels"];
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
@@ -27,6 +41,52 @@ pub struct Config {
     pub opt_out: DashMap<String, bool>,
     #[serde(rename = "adminAPIKey")]
     pub admin_api_key: Option<String>,
+    pub serialization_threads: Option<usize>,
+    #[serde(default = "default_response_compression")]
+    pub response_compression: bool,
+    pub response_compression_quality: Option<i32>,
+    #[serde(default = "default_connection_gap_warning_seconds")]
+    pub connection_gap_warning_seconds: u64,
+    pub max_concurrent_log_requests: Option<usize>,
+    pub log_request_timeout_seconds: Option<u64>,
+    pub max_range_months: Option<u32>,
+    #[serde(default)]
+    pub ingest_filters: RwLock<HashMap<String, IngestFilter>>,
+    #[serde(default)]
+    pub log_unknown_messages: bool,
+    #[serde(default)]
+    pub privmsg_only: bool,
+    pub permalink_secret: Option<String>,
+    #[serde(default)]
+    pub message_id_index: bool,
+    pub compaction_interval_hours: Option<u64>,
+    #[serde(default)]
+    pub channel_logins: DashMap<String, String>,
+    #[serde(default, deserialize_with = "deserialize_lowercase_keys")]
+    pub channel_aliases: DashMap<String, String>,
+    #[serde(default = "default_max_message_length")]
+    pub max_message_length: usize,
+    #[serde(default = "default_helix_concurrency")]
+    pub helix_concurrency: usize,
+    #[serde(default = "default_helix_requests_per_minute")]
+    pub helix_requests_per_minute: u32,
+    #[serde(default = "default_irc_shards")]
+    pub irc_shards: usize,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    #[serde(default)]
+    pub resolve_previous_logins: bool,
+    #[serde(default)]
+    pub default_response_formats: DefaultResponseFormats,
+    #[serde(default = "default_html_error_pages")]
+    pub html_error_pages: bool,
+    #[serde(default)]
+    pub timestamp_policy: TimestampPolicy,
+    #[serde(default)]
+    pub log_membership_events: bool,
+    /// Reloaded values of settings which need a restart, kept so that saving the config doesn't revert them
+    #[serde(skip)]
+    pending_settings: RwLock<Map<String, Value>>,
 }
 
 impl Config {
@@ -38,11 +98,232 @@ impl Config {
 
     pub fn save(&self) -> anyhow::Result<()> {
         info!("Updating config");
-        let json = serde_json::to_string_pretty(self)?;
+        let mut settings = serde_json::to_value(self)?;
+        if let Value::Object(settings) = &mut settings {
+            settings.extend(self.pending_settings.read().unwrap().clone());
+        }
+        let json = serde_json::to_string_pretty(&settings)?;
         fs::write(CONFIG_FILE_NAME, json)?;
 
         Ok(())
     }
+
+    /// Re-reads the config file and applies the changed settings which are safe to change at runtime
+    pub fn reload(&self) -> anyhow::Result<ConfigReload> {
+        let new_config = Self::load()?;
+        self.apply(new_config)
+    }
+
+    fn apply(&self, new_config: Config) -> anyhow::Result<ConfigReload> {
+        let current = serde_json::to_value(self)?;
+        let new = serde_json::to_value(&new_config)?;
+
+        let mut reload = ConfigReload::default();
+        let mut pending_settings = self.pending_settings.write().unwrap();
+        pending_settings.clear();
+
+        for setting in changed_settings(&current, &new) {
+            if RELOADABLE_SETTINGS.contains(&setting.as_str()) {
+                reload.applied.push(setting);
+            } else {
+                if let Some(value) = new.get(&setting) {
+                    pending_settings.insert(setting.clone(), value.clone());
+                }
+                reload.restart_required.push(setting);
+            }
+        }
+
+        // Channels are joined and parted by the bot, which updates the list itself
+        let new_channels = new_config.channels.into_inner().unwrap();
+        {
+            let channels = self.channels.read().unwrap();
+            reload.joined_channels = new_channels.difference(&channels).cloned().collect();
+            reload.parted_channels = channels.difference(&new_channels).cloned().collect();
+        }
+        if !reload.joined_channels.is_empty() || !reload.parted_channels.is_empty() {
+            reload.applied.push("channels".to_owned());
+            reload.applied.sort();
+        }
+
+        self.opt_out
+            .retain(|user_id, _| new_config.opt_out.contains_key(user_id));
+        for (user_id, opted_out) in new_config.opt_out {
+            self.opt_out.insert(user_id, opted_out);
+        }
+
+        self.channel_aliases
+            .retain(|alias, _| new_config.channel_aliases.contains_key(alias));
+        for (alias, channel_id) in new_config.channel_aliases {
+            self.channel_aliases.insert(alias, channel_id);
+        }
+
+        *self.ingest_filters.write().unwrap() = new_config.ingest_filters.into_inner().unwrap();
+
+        Ok(reload)
+    }
+}
+
+/// Outcome of reloading the config file
+#[derive(Default, Debug)]
+pub struct ConfigReload {
+    /// Changed settings which are now in effect
+    pub applied: Vec<String>,
+    /// Changed settings which only take effect after a restart
+    pub restart_required: Vec<String>,
+    /// Ids of channels added to the config, which still have to be joined
+    pub joined_channels: Vec<String>,
+    /// Ids of channels removed from the config, which still have to be parted
+    pub parted_channels: Vec<String>,
+}
+
+/// Names of the top-level settings which differ between the configs
+fn changed_settings(current: &Value, new: &Value) -> Vec<String> {
+    let empty = Map::new();
+    let current = current.as_object().unwrap_or(&empty);
+    let new = new.as_object().unwrap_or(&empty);
+
+    let mut changed: Vec<String> = current
+        .keys()
+        .chain(new.keys().filter(|key| !current.contains_key(*key)))
+        .filter(|key| !IGNORED_SETTINGS.contains(&key.as_str()))
+        .filter(|key| current.get(*key) != new.get(*key))
+        .cloned()
+        .collect();
+    changed.sort();
+    changed
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IngestFilter {
+    /// Logins of users whose messages should not be logged
+    #[serde(default)]
+    pub exclude_users: Vec<String>,
+    /// Messages with text matching this pattern are not logged
+    pub exclude_text_pattern: Option<FilterPattern>,
+    /// If not empty, only messages of these types (e.g. `PRIVMSG`, `CLEARCHAT`) are logged
+    #[serde(default)]
+    pub message_types: Vec<String>,
+    /// Only log 1 in this many chat messages. Other message types are always logged
+    pub sample_rate: Option<u32>,
+}
+
+impl IngestFilter {
+    pub fn allows(&self, msg: &StructuredMessage) -> bool {
+        if let Some(rate) = self.sample_rate {
+            if !is_sampled(msg, rate) {
+                return false;
+            }
+        }
+
+        if !self.message_types.is_empty() {
+            let message_type = msg.message_type.to_string();
+            if !self
+                .message_types
+                .iter()
+                .any(|allowed_type| allowed_type.eq_ignore_ascii_case(&message_type))
+            {
+                return false;
+            }
+        }
+
+        if !msg.user_login.is_empty()
+            && self
+                .exclude_users
+                .iter()
+                .any(|login| login.eq_ignore_ascii_case(&msg.user_login))
+        {
+            return false;
+        }
+
+        if let Some(pattern) = &self.exclude_text_pattern {
+            if pattern.0.is_match(&msg.user_friendly_text()) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Formats of logs responses when the request doesn't pick one with query params or the `Accept` header
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct DefaultResponseFormats {
+    /// Routes returning the logs of a whole channel
+    #[serde(default)]
+    pub channel: ResponseFormat,
+    /// Routes returning the logs of a single user
+    #[serde(default)]
+    pub user: ResponseFormat,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseFormat {
+    #[default]
+    Text,
+    Json,
+    NdJson,
+    Csv,
+    Raw,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookConfig {
+    /// URL the logged messages are posted to
+    pub url: String,
+    /// Channel ids to send messages from, all logged channels if empty
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// If not empty, only messages of these types (e.g. `PRIVMSG`, `CLEARCHAT`) are sent
+    #[serde(default)]
+    pub message_types: Vec<String>,
+    /// Only send the first messages users sent in the channel
+    #[serde(default)]
+    pub first_messages_only: bool,
+    /// Only send messages with text matching this pattern
+    pub text_pattern: Option<FilterPattern>,
+}
+
+/// Decided by the message id, so that the same messages are kept when they are processed again
+fn is_sampled(msg: &StructuredMessage, rate: u32) -> bool {
+    if msg.message_type != MessageType::PrivMsg || rate <= 1 {
+        return true;
+    }
+    match msg.uuid() {
+        Some(id) => id.as_u128() % u128::from(rate) == 0,
+        None => true,
+    }
+}
+
+pub struct FilterPattern(pub Regex);
+
+impl Serialize for FilterPattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for FilterPattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern)
+            .map(FilterPattern)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Aliases are matched case insensitively, so they are kept in lowercase
+fn deserialize_lowercase_keys<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<DashMap<String, String>, D::Error> {
+    let map = HashMap::<String, String>::deserialize(deserializer)?;
+    Ok(map
+        .into_iter()
+        .map(|(key, value)| (key.to_lowercase(), value))
+        .collect())
 }
 
 fn default_listen_address() -> String {
@@ -52,3 +333,121 @@ fn default_listen_address() -> String {
 fn clickhouse_flush_interval() -> u64 {
     10
 }
+
+fn default_response_compression() -> bool {
+    true
+}
+
+fn default_connection_gap_warning_seconds() -> u64 {
+    120
+}
+
+fn default_max_message_length() -> usize {
+    16 * 1024
+}
+
+fn default_helix_concurrency() -> usize {
+    4
+}
+
+fn default_helix_requests_per_minute() -> u32 {
+    800
+}
+
+fn default_irc_shards() -> usize {
+    1
+}
+
+fn default_html_error_pages() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_sampled, Config};
+    use crate::db::schema::{StructuredMessage, UnstructuredMessage};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    fn config(settings: serde_json::Value) -> Config {
+        let mut base = json!({
+            "clickhouseUrl": "http://localhost:8123",
+            "clickhouseDb": "rustlog",
+            "channels": ["22484632", "11148817"],
+            "clientID": "id",
+            "clientSecret": "secret",
+            "admins": [],
+        });
+        base.as_object_mut()
+            .unwrap()
+            .extend(settings.as_object().unwrap().clone());
+        serde_json::from_value(base).unwrap()
+    }
+
+    fn sampled(raw: &str, rate: u32) -> bool {
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "68136884",
+            timestamp: 1709251274940,
+            raw,
+        };
+        let msg = StructuredMessage::from_unstructured(&unstructured).unwrap();
+        is_sampled(&msg, rate)
+    }
+
+    #[test]
+    fn sample_chat_messages() {
+        let kept = (0..100)
+            .filter(|i| {
+                // The last hex digit of the id is the last digit of `i`
+                let raw = format!("@id=272e342c-5864-4c59-b730-{i:012};room-id=22484632;tmi-sent-ts=1709251274940;user-id=68136884 :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :+join");
+                sampled(&raw, 4)
+            })
+            .count();
+        assert_eq!(30, kept);
+        assert!(sampled("@id=272e342c-5864-4c59-b730-000000000008;room-id=22484632;tmi-sent-ts=1709251274940;user-id=68136884 :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :+join", 4));
+    }
+
+    #[test]
+    fn keep_moderation_events() {
+        assert!(sampled(
+            "@room-id=22484632;target-user-id=68136884;tmi-sent-ts=1709251274940 :tmi.twitch.tv CLEARCHAT #forsen :supibot",
+            1000
+        ));
+    }
+
+    #[test]
+    fn lowercase_channel_aliases() {
+        let config = config(json!({ "channelAliases": { "ForsenLol": "22484632" } }));
+        assert!(config.channel_aliases.contains_key("forsenlol"));
+    }
+
+    #[test]
+    fn reload_runtime_settings() {
+        let current = config(json!({ "channelAliases": { "forsenlol": "22484632" } }));
+        let new = config(json!({
+            "channels": ["22484632", "71092938"],
+            "channelAliases": { "xqcow": "71092938" },
+            "listenAddress": "0.0.0.0:9000",
+            "channelLogins": { "71092938": "xqc" },
+        }));
+
+        let reload = current.apply(new).unwrap();
+        assert_eq!(vec!["channelAliases", "channels"], reload.applied);
+        assert_eq!(vec!["listenAddress"], reload.restart_required);
+        assert_eq!(vec!["71092938"], reload.joined_channels);
+        assert_eq!(vec!["11148817"], reload.parted_channels);
+
+        assert!(current.channel_aliases.contains_key("xqcow"));
+        assert!(!current.channel_aliases.contains_key("forsenlol"));
+        assert_eq!("0.0.0.0:8025", current.listen_address);
+        assert_eq!(
+            Some(&json!("0.0.0.0:9000")),
+            current
+                .pending_settings
+                .read()
+                .unwrap()
+                .get("listenAddress")
+        );
+    }
+}
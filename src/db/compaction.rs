@@ -0,0 +1,103 @@
+use super::schema::MESSAGES_STRUCTURED_TABLE;
+use crate::{Result, ShutdownRx};
+use chrono::{DateTime, Utc};
+use clickhouse::Client;
+use std::time::{Duration, Instant};
+use tokio::time::{interval_at, MissedTickBehavior};
+use tracing::{debug, error, info};
+
+/// Periodically merges the parts of past months, so that rarely active channels don't leave many small parts behind
+pub async fn run(db: Client, db_name: String, period: Duration, mut shutdown_rx: ShutdownRx) {
+    let mut interval = interval_at(tokio::time::Instant::now() + period, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if let Err(err) = compact_past_months(&db, &db_name, &mut shutdown_rx).await {
+                    error!("Could not compact logs: {err}");
+                }
+            }
+            _ = shutdown_rx.changed() => {
+                debug!("Stopping compaction");
+                return;
+            }
+        }
+    }
+}
+
+async fn compact_past_months(
+    db: &Client,
+    db_name: &str,
+    shutdown_rx: &mut ShutdownRx,
+) -> Result<()> {
+    let partitions =
+        read_fragmented_partitions(db, db_name, &month_partition_id(Utc::now())).await?;
+    if partitions.is_empty() {
+        debug!("No partitions to compact");
+        return Ok(());
+    }
+
+    for partition_id in partitions {
+        if shutdown_rx.has_changed().unwrap_or(true) {
+            break;
+        }
+
+        let started_at = Instant::now();
+        optimize_partition(db, &partition_id).await?;
+        info!(
+            "Compacted partition {partition_id} in {:?}",
+            started_at.elapsed()
+        );
+    }
+
+    Ok(())
+}
+
+/// Partitions before the given one which consist of more than one part
+async fn read_fragmented_partitions(
+    db: &Client,
+    db_name: &str,
+    before_partition_id: &str,
+) -> Result<Vec<String>> {
+    let partitions = db
+        .query("SELECT partition_id FROM system.parts WHERE database = ? AND table = ? AND active AND partition_id < ? GROUP BY partition_id HAVING count() > 1 ORDER BY partition_id")
+        .bind(db_name)
+        .bind(MESSAGES_STRUCTURED_TABLE)
+        .bind(before_partition_id)
+        .fetch_all()
+        .await?;
+    Ok(partitions)
+}
+
+/// Merges all parts of the partition into one, waiting until it is done
+async fn optimize_partition(db: &Client, partition_id: &str) -> Result<()> {
+    db.query(&format!(
+        "OPTIMIZE TABLE {MESSAGES_STRUCTURED_TABLE} PARTITION ID '{partition_id}' FINAL"
+    ))
+    .execute()
+    .await?;
+    Ok(())
+}
+
+/// Id of the table partition containing the given time, the table is partitioned by `toYYYYMM(timestamp)`
+fn month_partition_id(time: DateTime<Utc>) -> String {
+    time.format("%Y%m").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::month_partition_id;
+    use chrono::DateTime;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn partition_id() {
+        let time = DateTime::from_timestamp(1709251274, 0).unwrap();
+        assert_eq!("202403", month_partition_id(time));
+        assert_eq!(
+            "202401",
+            month_partition_id(DateTime::from_timestamp(1706745599, 0).unwrap())
+        );
+    }
+}
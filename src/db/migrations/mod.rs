@@ -8,7 +8,7 @@ use tracing::{debug, info};
 
 use self::migratable::Migratable;
 
-pub async fn run(db: &Client, db_name: &str) -> Result<()> {
+pub async fn run(db: &Client, db_name: &str, message_id_index: bool) -> Result<()> {
     create_migrations_table(db).await?;
 
     run_migration(
@@ -71,6 +71,37 @@ String CODEC(ZSTD(10))
 
     run_migration(db, "6_structured_message", StructuredMigration { db_name }).await?;
 
+    // Optional migrations are named outside of the numbered sequence, which has to be the same on every install
+    rename_migration(db, "7_add_message_id_index", "opt_message_id_index_add").await?;
+    rename_migration(
+        db,
+        "8_materialize_message_id_index",
+        "opt_message_id_index_materialize",
+    )
+    .await?;
+
+    if message_id_index {
+        run_migration(
+            db,
+            "opt_message_id_index_add",
+            "
+ALTER TABLE message_structured
+ADD INDEX IF NOT EXISTS message_id_index id TYPE bloom_filter GRANULARITY 4
+    ",
+        )
+        .await?;
+
+        run_migration(
+            db,
+            "opt_message_id_index_materialize",
+            "
+ALTER TABLE message_structured
+MATERIALIZE INDEX message_id_index
+    ",
+        )
+        .await?;
+    }
+
     Ok(())
 }
 
@@ -100,6 +131,32 @@ async fn run_migration<'a, T: Migratable<'a>>(
     Ok(())
 }
 
+/// Keeps a migration which was run under its previous name from running again
+async fn rename_migration(db: &Client, old_name: &str, new_name: &str) -> Result<()> {
+    let count = db
+        .query("SELECT count(*) FROM __rustlog_migrations WHERE name = ?")
+        .bind(old_name)
+        .fetch_one::<u64>()
+        .await?;
+
+    if count > 0 {
+        info!("Renaming migration {old_name} to {new_name}");
+        db.query("INSERT INTO __rustlog_migrations SELECT ?, executed_at FROM __rustlog_migrations WHERE name = ?")
+            .bind(new_name)
+            .bind(old_name)
+            .execute()
+            .await?;
+        db.clone()
+            .with_option("mutations_sync", "1")
+            .query("ALTER TABLE __rustlog_migrations DELETE WHERE name = ?")
+            .bind(old_name)
+            .execute()
+            .await?;
+    }
+
+    Ok(())
+}
+
 async fn create_migrations_table(db: &Client) -> Result<()> {
     db.query(
         "
@@ -1,3 +1,4 @@
+pub mod compaction;
 mod migrations;
 pub mod schema;
 pub mod writer;
@@ -11,16 +12,24 @@ use crate::{
         schema::LogRangeParams,
         stream::{FlushBufferResponse, LogsStream},
     },
-    web::schema::{AvailableLogDate, LogsParams},
+    web::schema::{AvailableLogDate, ChannelBan, LogsParams, SearchParams, UserBan},
     Result,
 };
 use chrono::{DateTime, Datelike, Duration, Utc};
-use clickhouse::{query::RowCursor, Client};
+use clickhouse::{query::RowCursor, Client, Row};
 use rand::{seq::IteratorRandom, thread_rng};
-use schema::StructuredMessage;
+use regex::Regex;
+use schema::{MessageType, StructuredMessage};
+use serde::Deserialize;
+use std::collections::BTreeMap;
 use tracing::debug;
+use uuid::Uuid;
 
 const CHANNEL_MULTI_QUERY_SIZE_DAYS: i64 = 14;
+/// Time ranges before a message which are searched for its context, until enough lines are found
+const CONTEXT_WINDOWS_DAYS: [u64; 3] = [1, 30, 365];
+/// How far back previous logins are looked up, Twitch only releases logins for reuse after months
+const PREVIOUS_LOGIN_MAX_AGE_DAYS: i64 = 365;
 
 pub async fn read_channel(
     db: &Client,
@@ -36,7 +45,8 @@ pub async fn read_channel(
         "ASC"
     };
 
-    let mut query = format!("SELECT ?fields FROM message_structured WHERE channel_id = ? AND timestamp >= ? AND timestamp < ? ORDER BY timestamp {suffix}");
+    let params_filter = logs_params_filter(&params.logs_params);
+    let mut query = format!("SELECT ?fields FROM message_structured WHERE channel_id = ? AND timestamp >= ? AND timestamp < ?{params_filter} ORDER BY timestamp {suffix}, id {suffix}");
 
     if params.to - params.from > Duration::days(CHANNEL_MULTI_QUERY_SIZE_DAYS) {
         let count = db
@@ -106,28 +116,32 @@ fn next_cursor(
     Ok(cursor)
 }
 
+/// Reads the logs of one or more users (merged by timestamp)
 pub async fn read_user(
     db: &Client,
     channel_id: &str,
-    user_id: &str,
+    user_ids: &[&str],
     params: LogRangeParams,
     flush_buffer: &FlushBuffer,
 ) -> Result<LogsStream> {
     let buffer_response =
-        FlushBufferResponse::new(flush_buffer, channel_id, Some(user_id), params).await;
+        FlushBufferResponse::new(flush_buffer, channel_id, Some(user_ids), params).await;
 
     let suffix = if params.logs_params.reverse {
         "DESC"
     } else {
         "ASC"
     };
-    let mut query = format!("SELECT * FROM message_structured WHERE channel_id = ? AND user_id = ? AND timestamp >= ? AND timestamp < ? ORDER BY timestamp {suffix}");
+    let user_placeholders = vec!["?"; user_ids.len()].join(", ");
+    let params_filter = logs_params_filter(&params.logs_params);
+    let mut query = format!("SELECT * FROM message_structured WHERE channel_id = ? AND user_id IN ({user_placeholders}) AND timestamp >= ? AND timestamp < ?{params_filter} ORDER BY timestamp {suffix}");
     apply_limit_offset(&mut query, &buffer_response);
 
-    let cursor = db
-        .query(&query)
-        .bind(channel_id)
-        .bind(user_id)
+    let mut query = db.query(&query).bind(channel_id);
+    for user_id in user_ids {
+        query = query.bind(user_id);
+    }
+    let cursor = query
         .bind(params.from.timestamp_millis() as f64 / 1000.0)
         .bind(params.to.timestamp_millis() as f64 / 1000.0)
         .fetch()?;
@@ -188,6 +202,630 @@ pub async fn read_available_user_logs(
     Ok(dates)
 }
 
+/// Id of the user who most recently sent messages under the login within the last year, across all channels
+pub async fn read_user_id_by_login(db: &Client, login: &str) -> Result<Option<String>> {
+    let from = Utc::now() - Duration::days(PREVIOUS_LOGIN_MAX_AGE_DAYS);
+    let user_id = db
+        .query("SELECT user_id FROM message_structured WHERE timestamp >= ? AND user_login = ? AND user_id != '' ORDER BY timestamp DESC LIMIT 1")
+        .bind(from.timestamp_millis() as f64 / 1000.0)
+        .bind(login.to_lowercase())
+        .fetch_optional::<String>()
+        .await?;
+    Ok(user_id)
+}
+
+/// Ids of the users in the channel's logs of the time range whose id or login is `user`
+pub async fn find_channel_user_ids(
+    db: &Client,
+    channel_id: &str,
+    user: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    flush_buffer: &FlushBuffer,
+) -> Result<Vec<String>> {
+    let login = user.to_lowercase();
+
+    let mut user_ids: Vec<String> = db
+        .query("SELECT DISTINCT user_id FROM message_structured WHERE channel_id = ? AND timestamp >= ? AND timestamp < ? AND user_id != '' AND (user_id = ? OR user_login = ?)")
+        .bind(channel_id)
+        .bind(from.timestamp_millis() as f64 / 1000.0)
+        .bind(to.timestamp_millis() as f64 / 1000.0)
+        .bind(user)
+        .bind(&login)
+        .fetch_all()
+        .await?;
+
+    let time_range = (from.timestamp_millis() as u64)..(to.timestamp_millis() as u64);
+    for msg in flush_buffer
+        .messages_by_channel(time_range, channel_id)
+        .await
+    {
+        if msg.is_sent_by(user) && !user_ids.iter().any(|user_id| *user_id == msg.user_id) {
+            user_ids.push(msg.user_id.into_owned());
+        }
+    }
+
+    Ok(user_ids)
+}
+
+/// Checks if any logs of the channel are stored
+pub async fn has_channel_logs(db: &Client, channel_id: &str) -> Result<bool> {
+    let count = db
+        .query(
+            "SELECT count() FROM (SELECT 1 FROM message_structured WHERE channel_id = ? LIMIT 1)",
+        )
+        .bind(channel_id)
+        .fetch_one::<u64>()
+        .await?;
+    Ok(count > 0)
+}
+
+/// Checks if any logs of the users in the channel are stored
+pub async fn has_user_logs(db: &Client, channel_id: &str, user_ids: &[&str]) -> Result<bool> {
+    let user_placeholders = vec!["?"; user_ids.len()].join(", ");
+    let mut query = db
+        .query(&format!("SELECT count() FROM (SELECT 1 FROM message_structured WHERE channel_id = ? AND user_id IN ({user_placeholders}) LIMIT 1)"))
+        .bind(channel_id);
+    for user_id in user_ids {
+        query = query.bind(user_id);
+    }
+    let count = query.fetch_one::<u64>().await?;
+    Ok(count > 0)
+}
+
+pub async fn read_user_count_by_month(
+    db: &Client,
+    channel_id: &str,
+    user_id: &str,
+) -> Result<BTreeMap<String, u64>> {
+    #[derive(Row, Deserialize)]
+    struct MonthCount {
+        month: u32,
+        count: u64,
+    }
+
+    let counts: Vec<MonthCount> = db
+        .query("SELECT toYYYYMM(timestamp) AS month, count() AS count FROM message_structured WHERE channel_id = ? AND user_id = ? GROUP BY month ORDER BY month")
+        .bind(channel_id)
+        .bind(user_id)
+        .fetch_all()
+        .await?;
+
+    let counts = counts
+        .into_iter()
+        .map(|MonthCount { month, count }| (format!("{}-{:02}", month / 100, month % 100), count))
+        .collect();
+
+    Ok(counts)
+}
+
+/// Reads the bans and timeouts (`CLEARCHAT` messages) targeting the user in the given channels, oldest first
+pub async fn read_user_bans(
+    db: &Client,
+    channel_ids: &[String],
+    user_id: &str,
+    limit: u64,
+    offset: u64,
+) -> Result<Vec<UserBan>> {
+    #[derive(Row, Deserialize)]
+    struct Ban {
+        channel_id: String,
+        channel_login: String,
+        timestamp: i64,
+        duration: String,
+    }
+
+    if channel_ids.is_empty() {
+        return Ok(vec![]);
+    }
+
+    // Filtering by the channels allows using the primary key instead of scanning all logs
+    let channel_placeholders = vec!["?"; channel_ids.len()].join(", ");
+    let mut query = db.query(&format!("SELECT channel_id, channel_login, toUnixTimestamp64Milli(timestamp) AS timestamp, extra_tags['ban-duration'] AS duration FROM message_structured WHERE channel_id IN ({channel_placeholders}) AND user_id = ? AND message_type = ? ORDER BY timestamp ASC, channel_id ASC LIMIT ? OFFSET ?"));
+    for channel_id in channel_ids {
+        query = query.bind(channel_id);
+    }
+    let bans: Vec<Ban> = query
+        .bind(user_id)
+        .bind(MessageType::ClearChat as u8)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all()
+        .await?;
+
+    Ok(bans
+        .into_iter()
+        .map(|ban| UserBan {
+            channel_id: ban.channel_id,
+            channel: ban.channel_login,
+            timestamp: DateTime::from_timestamp_millis(ban.timestamp).unwrap_or_default(),
+            duration: ban.duration.parse().ok(),
+        })
+        .collect())
+}
+
+/// Reads the bans and timeouts of users (`CLEARCHAT` messages with a target) in the channel, oldest first
+pub async fn read_channel_bans(
+    db: &Client,
+    channel_id: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<ChannelBan>> {
+    #[derive(Row, Deserialize)]
+    struct Ban {
+        user_id: String,
+        user_login: String,
+        timestamp: i64,
+        duration: String,
+    }
+
+    let bans: Vec<Ban> = db
+        .query("SELECT user_id, user_login, toUnixTimestamp64Milli(timestamp) AS timestamp, extra_tags['ban-duration'] AS duration FROM message_structured WHERE channel_id = ? AND message_type = ? AND user_id != '' AND timestamp >= ? AND timestamp < ? ORDER BY timestamp ASC")
+        .bind(channel_id)
+        .bind(MessageType::ClearChat as u8)
+        .bind(from.timestamp_millis() as f64 / 1000.0)
+        .bind(to.timestamp_millis() as f64 / 1000.0)
+        .fetch_all()
+        .await?;
+
+    Ok(bans
+        .into_iter()
+        .map(|ban| ChannelBan {
+            user_id: ban.user_id,
+            user: ban.user_login,
+            timestamp: DateTime::from_timestamp_millis(ban.timestamp).unwrap_or_default(),
+            duration: ban.duration.parse().ok(),
+        })
+        .collect())
+}
+
+/// Returns the amount of rows and the size on disk (in bytes) of the messages table
+pub async fn read_storage_size(db: &Client, db_name: &str) -> Result<(u64, u64)> {
+    #[derive(Row, Deserialize)]
+    struct StorageSize {
+        rows: u64,
+        bytes_on_disk: u64,
+    }
+
+    let size: StorageSize = db
+        .query("SELECT sum(rows) AS rows, sum(bytes_on_disk) AS bytes_on_disk FROM system.parts WHERE database = ? AND table = ? AND active")
+        .bind(db_name)
+        .bind(schema::MESSAGES_STRUCTURED_TABLE)
+        .fetch_one()
+        .await?;
+    Ok((size.rows, size.bytes_on_disk))
+}
+
+/// Size of a monthly partition (`YYYYMM`) of the messages table, which contains the logs of all channels
+pub async fn read_partition_size(
+    db: &Client,
+    db_name: &str,
+    partition: &str,
+) -> Result<PartitionSize> {
+    let size = db
+        .query("SELECT sum(rows) AS rows, sum(data_compressed_bytes) AS compressed_bytes, sum(data_uncompressed_bytes) AS uncompressed_bytes FROM system.parts WHERE database = ? AND table = ? AND partition = ? AND active")
+        .bind(db_name)
+        .bind(schema::MESSAGES_STRUCTURED_TABLE)
+        .bind(partition)
+        .fetch_one()
+        .await?;
+    Ok(size)
+}
+
+#[derive(Row, Deserialize)]
+pub struct PartitionSize {
+    pub rows: u64,
+    pub compressed_bytes: u64,
+    pub uncompressed_bytes: u64,
+}
+
+/// Name of the highest numbered database migration that was run, which identifies the schema version.
+/// Optional migrations (named without a number) are not part of it
+pub async fn read_latest_migration(db: &Client) -> Result<Option<String>> {
+    let name = db
+        .query("SELECT name FROM __rustlog_migrations WHERE match(name, '^[0-9]+_') ORDER BY toUInt64(splitByChar('_', name)[1]) DESC LIMIT 1")
+        .fetch_optional()
+        .await?;
+    Ok(name)
+}
+
+/// Lists the names of the secondary (data skipping) indexes on the messages table
+pub async fn read_secondary_indexes(db: &Client, db_name: &str) -> Result<Vec<String>> {
+    let names = db
+        .query("SELECT name FROM system.data_skipping_indices WHERE database = ? AND table = ? ORDER BY name")
+        .bind(db_name)
+        .bind(schema::MESSAGES_STRUCTURED_TABLE)
+        .fetch_all()
+        .await?;
+    Ok(names)
+}
+
+/// Builds the given secondary index for all existing rows, waiting until it is done
+pub async fn materialize_index(db: &Client, index_name: &str) -> Result<()> {
+    db.clone()
+        .with_option("mutations_sync", "1")
+        .query(&format!(
+            "ALTER TABLE {} MATERIALIZE INDEX `{index_name}`",
+            schema::MESSAGES_STRUCTURED_TABLE
+        ))
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Deletes the channel's stored messages in the time range, waiting until they are removed
+pub async fn delete_channel_range(
+    db: &Client,
+    channel_id: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<()> {
+    db.clone()
+        .with_option("mutations_sync", "1")
+        .query(&format!(
+            "ALTER TABLE {} DELETE WHERE channel_id = ? AND timestamp >= ? AND timestamp < ?",
+            schema::MESSAGES_STRUCTURED_TABLE
+        ))
+        .bind(channel_id)
+        .bind(from.timestamp_millis() as f64 / 1000.0)
+        .bind(to.timestamp_millis() as f64 / 1000.0)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Creates an empty table with the structure of the messages table, for preparing messages before they replace stored ones
+pub async fn create_staging_table(db: &Client, name: &str) -> Result<()> {
+    db.query(&format!(
+        "CREATE TABLE {name} AS {}",
+        schema::MESSAGES_STRUCTURED_TABLE
+    ))
+    .execute()
+    .await?;
+    Ok(())
+}
+
+/// Copies all messages of the staging table into the messages table
+pub async fn copy_staged_messages(db: &Client, name: &str) -> Result<()> {
+    db.query(&format!(
+        "INSERT INTO {} SELECT * FROM {name}",
+        schema::MESSAGES_STRUCTURED_TABLE
+    ))
+    .execute()
+    .await?;
+    Ok(())
+}
+
+pub async fn drop_staging_table(db: &Client, name: &str) -> Result<()> {
+    db.query(&format!("DROP TABLE IF EXISTS {name}"))
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Reads the message counts of the users in the time range, starting with the most active ones
+pub async fn read_top_users(
+    db: &Client,
+    channel_id: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    limit: u64,
+    offset: u64,
+) -> Result<Vec<(String, u64)>> {
+    #[derive(Row, Deserialize)]
+    struct UserCount {
+        user_id: String,
+        count: u64,
+    }
+
+    // Users with the same count are ordered by their id, so that pages don't overlap
+    let counts: Vec<UserCount> = db
+        .query("SELECT user_id, count() AS count FROM message_structured WHERE channel_id = ? AND user_id != '' AND timestamp >= ? AND timestamp < ? GROUP BY user_id ORDER BY count DESC, user_id ASC LIMIT ? OFFSET ?")
+        .bind(channel_id)
+        .bind(from.timestamp_millis() as f64 / 1000.0)
+        .bind(to.timestamp_millis() as f64 / 1000.0)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all()
+        .await?;
+
+    Ok(counts
+        .into_iter()
+        .map(|UserCount { user_id, count }| (user_id, count))
+        .collect())
+}
+
+/// Reads up to `count` channel messages sent within a year right before the given timestamp (in milliseconds), oldest first
+pub async fn read_channel_lines_before(
+    db: &Client,
+    channel_id: &str,
+    timestamp: u64,
+    count: u64,
+    flush_buffer: &FlushBuffer,
+) -> Result<Vec<StructuredMessage<'static>>> {
+    let mut messages = Vec::new();
+    // Most messages have their context within the last day, older logs are only read if needed
+    for window_days in CONTEXT_WINDOWS_DAYS {
+        let from = timestamp.saturating_sub(window_days * 24 * 3600 * 1000);
+        messages = db
+            .query("SELECT ?fields FROM message_structured WHERE channel_id = ? AND timestamp >= ? AND timestamp < ? ORDER BY timestamp DESC LIMIT ?")
+            .bind(channel_id)
+            .bind(from as f64 / 1000.0)
+            .bind(timestamp as f64 / 1000.0)
+            .bind(count)
+            .fetch_all::<StructuredMessage>()
+            .await?;
+        if messages.len() as u64 >= count {
+            break;
+        }
+    }
+    messages.extend(
+        flush_buffer
+            .messages_by_channel(0..timestamp, channel_id)
+            .await,
+    );
+
+    messages.sort_by_key(|msg| msg.timestamp);
+    let skip = messages.len().saturating_sub(count as usize);
+    Ok(messages.split_off(skip))
+}
+
+/// Reads the message at the given position (starting at 0) in the channel's logs of the time range, including messages which have not been flushed yet.
+/// Messages with the same timestamp are ordered by their id, so a position always refers to the same message
+pub async fn read_channel_line(
+    db: &Client,
+    channel_id: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    offset: u64,
+    flush_buffer: &FlushBuffer,
+) -> Result<StructuredMessage<'static>> {
+    let msg = db
+        .query("SELECT ?fields FROM message_structured WHERE channel_id = ? AND timestamp >= ? AND timestamp < ? ORDER BY timestamp ASC, id ASC LIMIT 1 OFFSET ?")
+        .bind(channel_id)
+        .bind(from.timestamp_millis() as f64 / 1000.0)
+        .bind(to.timestamp_millis() as f64 / 1000.0)
+        .bind(offset)
+        .fetch_optional::<StructuredMessage>()
+        .await?;
+    if let Some(msg) = msg {
+        return Ok(msg);
+    }
+
+    // Buffered messages come after the stored ones, same as when reading the logs
+    let stored_count = db
+        .query("SELECT count() FROM message_structured WHERE channel_id = ? AND timestamp >= ? AND timestamp < ?")
+        .bind(channel_id)
+        .bind(from.timestamp_millis() as f64 / 1000.0)
+        .bind(to.timestamp_millis() as f64 / 1000.0)
+        .fetch_one::<u64>()
+        .await?;
+    let buffered = flush_buffer
+        .messages_by_channel(
+            from.timestamp_millis() as u64..to.timestamp_millis() as u64,
+            channel_id,
+        )
+        .await;
+
+    offset
+        .checked_sub(stored_count)
+        .and_then(|index| buffered.into_iter().nth(index as usize))
+        .ok_or(Error::NotFound)
+}
+
+/// Counts the channel's messages in the time range, including messages which have not been flushed yet
+pub async fn count_channel_lines(
+    db: &Client,
+    channel_id: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    flush_buffer: &FlushBuffer,
+) -> Result<u64> {
+    let stored_count = db
+        .query("SELECT count() FROM message_structured WHERE channel_id = ? AND timestamp >= ? AND timestamp < ?")
+        .bind(channel_id)
+        .bind(from.timestamp_millis() as f64 / 1000.0)
+        .bind(to.timestamp_millis() as f64 / 1000.0)
+        .fetch_one::<u64>()
+        .await?;
+    let buffered = flush_buffer
+        .messages_by_channel(
+            from.timestamp_millis() as u64..to.timestamp_millis() as u64,
+            channel_id,
+        )
+        .await;
+    Ok(stored_count + buffered.len() as u64)
+}
+
+/// Counts the user's messages in the channel, including messages which have not been flushed yet
+pub async fn count_user_lines(
+    db: &Client,
+    channel_id: &str,
+    user_id: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    flush_buffer: &FlushBuffer,
+) -> Result<u64> {
+    let stored_count = db
+        .query("SELECT count() FROM message_structured WHERE channel_id = ? AND user_id = ? AND timestamp >= ? AND timestamp < ?")
+        .bind(channel_id)
+        .bind(user_id)
+        .bind(from.timestamp_millis() as f64 / 1000.0)
+        .bind(to.timestamp_millis() as f64 / 1000.0)
+        .fetch_one::<u64>()
+        .await?;
+    let buffered = flush_buffer
+        .messages_by_channel_and_users(
+            from.timestamp_millis() as u64..to.timestamp_millis() as u64,
+            channel_id,
+            &[user_id],
+        )
+        .await;
+    Ok(stored_count + buffered.len() as u64)
+}
+
+/// Reads at most `limit` messages of a channel which come after the message with the given timestamp (in milliseconds) and id,
+/// including messages which have not been flushed yet. Messages are ordered by their timestamp and then by their id
+pub async fn read_channel_since(
+    db: &Client,
+    channel_id: &str,
+    timestamp: u64,
+    id: Uuid,
+    limit: u64,
+    flush_buffer: &FlushBuffer,
+) -> Result<Vec<StructuredMessage<'static>>> {
+    // The buffer is read first, so that messages flushed in the meantime are returned by the query instead of being missed
+    let buffered = flush_buffer
+        .messages_by_channel(timestamp..u64::MAX, channel_id)
+        .await;
+
+    // Ids are compared as strings to match the order of `Uuid`
+    let stored = db
+        .query("SELECT ?fields FROM message_structured WHERE channel_id = ? AND timestamp >= ? AND (timestamp > ? OR toString(id) > ?) ORDER BY timestamp ASC, toString(id) ASC LIMIT ?")
+        .bind(channel_id)
+        .bind(timestamp as f64 / 1000.0)
+        .bind(timestamp as f64 / 1000.0)
+        .bind(id.hyphenated().to_string())
+        .bind(limit)
+        .fetch_all::<StructuredMessage>()
+        .await?;
+
+    let after = (timestamp, id);
+    let buffered = buffered
+        .into_iter()
+        .filter(|msg| message_position(msg) > after);
+    let mut messages = merge_buffered(stored, buffered);
+    messages.truncate(limit as usize);
+    Ok(messages)
+}
+
+/// Reads the latest `count` messages of a channel since `from`, including messages which have not been flushed yet.
+/// Messages are ordered by their timestamp and then by their id
+pub async fn read_channel_latest(
+    db: &Client,
+    channel_id: &str,
+    from: DateTime<Utc>,
+    count: u64,
+    flush_buffer: &FlushBuffer,
+) -> Result<Vec<StructuredMessage<'static>>> {
+    let buffered = flush_buffer
+        .messages_by_channel(from.timestamp_millis() as u64..u64::MAX, channel_id)
+        .await;
+
+    let mut stored = db
+        .query("SELECT ?fields FROM message_structured WHERE channel_id = ? AND timestamp >= ? ORDER BY timestamp DESC, toString(id) DESC LIMIT ?")
+        .bind(channel_id)
+        .bind(from.timestamp_millis() as f64 / 1000.0)
+        .bind(count)
+        .fetch_all::<StructuredMessage>()
+        .await?;
+    stored.reverse();
+
+    let mut messages = merge_buffered(stored, buffered);
+    let skip = messages.len().saturating_sub(count as usize);
+    Ok(messages.split_off(skip))
+}
+
+/// Adds the buffered messages which are not stored yet, keeping the messages ordered by their position
+fn merge_buffered(
+    mut stored: Vec<StructuredMessage<'static>>,
+    buffered: impl IntoIterator<Item = StructuredMessage<'static>>,
+) -> Vec<StructuredMessage<'static>> {
+    let new = buffered
+        .into_iter()
+        .filter(|msg| !stored.contains(msg))
+        .collect::<Vec<_>>();
+    stored.extend(new);
+    stored.sort_by_key(message_position);
+    stored
+}
+
+fn message_position(msg: &StructuredMessage) -> (u64, Uuid) {
+    (msg.timestamp, msg.uuid().unwrap_or_default())
+}
+
+/// Reads the user's message at the given position (starting at 1) in all of their logs in the channel.
+/// Negative positions count back from the latest message
+pub async fn read_user_nth_line(
+    db: &Client,
+    channel_id: &str,
+    user_id: &str,
+    position: i64,
+    flush_buffer: &FlushBuffer,
+) -> Result<StructuredMessage<'static>> {
+    if position == 0 {
+        return Err(Error::InvalidParam("Positions start at 1 or -1".to_owned()));
+    }
+    let reverse = position < 0;
+    let index = position.unsigned_abs() - 1;
+
+    // Buffered messages are newer than all stored ones
+    let mut buffered = flush_buffer
+        .messages_by_channel_and_users(0..u64::MAX, channel_id, &[user_id])
+        .await;
+    if reverse {
+        if let Some(msg) = buffered.iter().rev().nth(index as usize) {
+            return Ok(msg.clone());
+        }
+    }
+
+    let stored_index = if reverse {
+        index - buffered.len() as u64
+    } else {
+        index
+    };
+    let order = if reverse { "DESC" } else { "ASC" };
+    let msg = db
+        .query(&format!("SELECT ?fields FROM message_structured WHERE channel_id = ? AND user_id = ? ORDER BY timestamp {order}, id {order} LIMIT 1 OFFSET ?"))
+        .bind(channel_id)
+        .bind(user_id)
+        .bind(stored_index)
+        .fetch_optional::<StructuredMessage>()
+        .await?;
+
+    match msg {
+        Some(msg) => Ok(msg),
+        None if !reverse => {
+            let stored_count = db
+                .query(
+                    "SELECT count() FROM message_structured WHERE channel_id = ? AND user_id = ?",
+                )
+                .bind(channel_id)
+                .bind(user_id)
+                .fetch_one::<u64>()
+                .await?;
+            let buffered_index = index.saturating_sub(stored_count) as usize;
+            if index >= stored_count && buffered_index < buffered.len() {
+                Ok(buffered.swap_remove(buffered_index))
+            } else {
+                Err(Error::NotFound)
+            }
+        }
+        None => Err(Error::NotFound),
+    }
+}
+
+pub async fn read_message_by_id(
+    db: &Client,
+    channel_id: &str,
+    message_id: &str,
+    flush_buffer: &FlushBuffer,
+) -> Result<StructuredMessage<'static>> {
+    if let Some(msg) = flush_buffer.message_by_id(channel_id, message_id).await {
+        return Ok(msg);
+    }
+
+    let msg = db
+        .query("SELECT ?fields FROM message_structured WHERE channel_id = ? AND id = ? LIMIT 1")
+        .bind(channel_id)
+        .bind(message_id)
+        .fetch_optional::<StructuredMessage>()
+        .await?
+        .ok_or(Error::NotFound)?;
+
+    Ok(msg)
+}
+
 pub async fn read_random_user_line(
     db: &Client,
     channel_id: &str,
@@ -277,30 +915,121 @@ pub async fn search_user_logs(
     db: &Client,
     channel_id: &str,
     user_id: &str,
-    search: &str,
-    params: LogsParams,
+    params: &SearchParams,
 ) -> Result<LogsStream> {
     let buffer_response = FlushBufferResponse::empty(LogRangeParams {
         from: DateTime::UNIX_EPOCH,
         to: DateTime::UNIX_EPOCH,
-        logs_params: params,
+        logs_params: params.logs_params,
     });
 
-    let suffix = if params.reverse { "DESC" } else { "ASC" };
+    if let Some(regex) = &params.regex {
+        Regex::new(regex).map_err(|err| Error::InvalidFormat(format!("Invalid regex: {err}")))?;
+    }
+    let message_type = params.logs_params.message_type;
+    let min_sub_months = params.logs_params.min_sub_months;
+    let sub_months_condition = format!("{SUBSCRIBER_MONTHS_EXPR} >= ?");
 
-    let mut query = format!("SELECT * FROM message_structured WHERE channel_id = ? AND user_id = ? AND positionCaseInsensitive(text, ?) != 0 ORDER BY timestamp {suffix}");
+    // The time range is checked first so that ClickHouse can skip granules using the primary key
+    let mut conditions = vec!["channel_id = ?", "user_id = ?"];
+    if params.logs_params.exclude_shared {
+        conditions.push(NATIVE_MESSAGE_CONDITION);
+    }
+    if params.logs_params.first_msg_only {
+        conditions.push(FIRST_MESSAGE_CONDITION);
+    }
+    if params.from.is_some() {
+        conditions.push("timestamp >= ?");
+    }
+    if params.to.is_some() {
+        conditions.push("timestamp < ?");
+    }
+    if message_type.is_some() {
+        conditions.push("message_type = ?");
+    }
+    if params.min_bits.is_some() {
+        conditions.push("toUInt64OrZero(extra_tags['bits']) >= ?");
+    }
+    if min_sub_months.is_some() {
+        conditions.push(&sub_months_condition);
+    }
+    if params.q.is_some() {
+        conditions.push("positionCaseInsensitive(text, ?) != 0");
+    }
+    if params.regex.is_some() {
+        conditions.push("match(text, ?)");
+    }
+
+    let suffix = if params.logs_params.reverse {
+        "DESC"
+    } else {
+        "ASC"
+    };
+    let conditions = conditions.join(" AND ");
+    let mut query =
+        format!("SELECT * FROM message_structured WHERE {conditions} ORDER BY timestamp {suffix}");
     apply_limit_offset(&mut query, &buffer_response);
 
-    let cursor = db
-        .query(&query)
-        .bind(channel_id)
-        .bind(user_id)
-        .bind(search)
-        .fetch()?;
+    let mut query = db.query(&query).bind(channel_id).bind(user_id);
+    if let Some(from) = params.from {
+        query = query.bind(from.timestamp_millis() as f64 / 1000.0);
+    }
+    if let Some(to) = params.to {
+        query = query.bind(to.timestamp_millis() as f64 / 1000.0);
+    }
+    if let Some(message_type) = message_type {
+        query = query.bind(message_type as u8);
+    }
+    if let Some(min_bits) = params.min_bits {
+        query = query.bind(min_bits);
+    }
+    if let Some(min_sub_months) = min_sub_months {
+        query = query.bind(min_sub_months);
+    }
+    if let Some(search) = &params.q {
+        query = query.bind(search);
+    }
+    if let Some(regex) = &params.regex {
+        query = query.bind(regex);
+    }
+
+    let cursor = query.fetch()?;
 
     LogsStream::new_cursor(cursor, buffer_response).await
 }
 
+/// Shared chat messages have the `source-room-id` tag set to the channel they were sent in
+const NATIVE_MESSAGE_CONDITION: &str =
+    "(extra_tags['source-room-id'] = '' OR extra_tags['source-room-id'] = channel_id)";
+
+/// Chat messages with the `first-msg` tag set, `MessageFlags::FIRST_MSG`
+const FIRST_MESSAGE_CONDITION: &str = "bitAnd(message_flags, 16) != 0";
+
+/// Subscribed months from the first `subscriber` or `founder` entry of the `badge-info` tag, 0 if not subscribed.
+/// Same rule as `StructuredMessage::subscriber_months`, which filters buffered messages
+const SUBSCRIBER_MONTHS_EXPR: &str =
+    "toUInt32OrZero(extract(badge_info, '(?:^|,)(?:subscriber|founder)/([0-9]+)'))";
+
+/// Additional conditions for the filters of the params
+fn logs_params_filter(params: &LogsParams) -> String {
+    let mut filter = String::new();
+    if params.exclude_shared {
+        filter.push_str(&format!(" AND {NATIVE_MESSAGE_CONDITION}"));
+    }
+    if params.first_msg_only {
+        filter.push_str(&format!(" AND {FIRST_MESSAGE_CONDITION}"));
+    }
+    if let Some(min_sub_months) = params.min_sub_months {
+        filter.push_str(&format!(
+            " AND {SUBSCRIBER_MONTHS_EXPR} >= {min_sub_months}"
+        ));
+    }
+    if let Some(message_type) = params.message_type {
+        filter.push_str(&format!(" AND message_type = {}", message_type as u8));
+    }
+    filter
+}
+
 fn apply_limit_offset(query: &mut String, buffer_response: &FlushBufferResponse) {
     if let Some(limit) = buffer_response.normalized_limit() {
         *query = format!("{query} LIMIT {limit}");
@@ -309,3 +1038,20 @@ fn apply_limit_offset(query: &mut String, buffer_response: &FlushBufferResponse)
         *query = format!("{query} OFFSET {offset}");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{schema::MessageFlags, FIRST_MESSAGE_CONDITION};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn first_message_condition_matches_flag() {
+        assert_eq!(
+            format!(
+                "bitAnd(message_flags, {}) != 0",
+                MessageFlags::FIRST_MSG.bits()
+            ),
+            FIRST_MESSAGE_CONDITION
+        );
+    }
+}
@@ -1,4 +1,3 @@
-use anyhow::Context;
 use bitflags::bitflags;
 use clickhouse::Row;
 use serde::{Deserialize, Serialize};
@@ -6,6 +5,7 @@ use serde_repr::{Deserialize_repr, Serialize_repr};
 use std::fmt::Write;
 use std::{borrow::Cow, fmt::Debug};
 use strum::{Display, EnumString};
+use thiserror::Error;
 use tmi::{IrcMessageRef, Tag};
 use uuid::Uuid;
 
@@ -71,6 +71,9 @@ impl MessageFlags {
     }
 }
 
+/// Appended to message texts which were cut off because of their length
+const TRUNCATED_TEXT_MARKER: &str = "[truncated]";
+
 #[derive(Row, Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct StructuredMessage<'a> {
     pub channel_id: Cow<'a, str>,
@@ -94,6 +97,25 @@ pub struct StructuredMessage<'a> {
     pub extra_tags: Vec<(Cow<'a, str>, Cow<'a, str>)>,
 }
 
+/// Why a raw IRC line could not be converted into a `StructuredMessage`
+#[derive(Error, Debug, PartialEq)]
+pub enum ParseError {
+    #[error("Malformed IRC message")]
+    MalformedIrc,
+    #[error("Unsupported command {0}")]
+    UnsupportedCommand(String),
+}
+
+impl ParseError {
+    /// Short name of the error kind, used as a metrics label
+    pub fn category(&self) -> &'static str {
+        match self {
+            Self::MalformedIrc => "malformed_irc",
+            Self::UnsupportedCommand(_) => "unsupported_command",
+        }
+    }
+}
+
 #[derive(Row, Serialize, Deserialize, Debug)]
 pub struct UnstructuredMessage<'a> {
     pub channel_id: &'a str,
@@ -103,9 +125,24 @@ pub struct UnstructuredMessage<'a> {
 }
 
 impl<'a> StructuredMessage<'a> {
-    pub fn from_unstructured(message: &'a UnstructuredMessage<'a>) -> anyhow::Result<Self> {
+    pub fn from_unstructured(message: &'a UnstructuredMessage<'a>) -> Result<Self, ParseError> {
+        Self::parse_unstructured(message, false)
+    }
+
+    /// Like `from_unstructured`, but messages with unsupported commands are converted
+    /// to `MessageType::Unknown` instead of failing
+    pub fn from_unstructured_allow_unknown(
+        message: &'a UnstructuredMessage<'a>,
+    ) -> Result<Self, ParseError> {
+        Self::parse_unstructured(message, true)
+    }
+
+    fn parse_unstructured(
+        message: &'a UnstructuredMessage<'a>,
+        allow_unknown: bool,
+    ) -> Result<Self, ParseError> {
         let irc_message = IrcMessageRef::parse(message.raw.trim().trim_matches('\0'))
-            .context("Could not parse message")?;
+            .ok_or(ParseError::MalformedIrc)?;
 
         let channel_login = irc_message
             .channel()
@@ -117,8 +154,15 @@ impl<'a> StructuredMessage<'a> {
             .map(Cow::Borrowed)
             .unwrap_or_default();
 
-        let message_type = MessageType::from_tmi_command(irc_message.command())
-            .with_context(|| format!("Unknown message type {}", irc_message.command()))?;
+        let message_type = match MessageType::from_tmi_command(irc_message.command()) {
+            Some(message_type) => message_type,
+            None if allow_unknown => MessageType::Unknown,
+            None => {
+                return Err(ParseError::UnsupportedCommand(
+                    irc_message.command().to_string(),
+                ))
+            }
+        };
 
         let mut text = irc_message
             .params()
@@ -137,7 +181,14 @@ impl<'a> StructuredMessage<'a> {
             }
             _ => (),
         }
-        let text = Cow::Borrowed(text);
+        let text = match message_type {
+            // The command is kept in the text, so that the message can be converted back to IRC
+            MessageType::Unknown if text.is_empty() => {
+                Cow::Owned(irc_message.command().to_string())
+            }
+            MessageType::Unknown => Cow::Owned(format!("{} {text}", irc_message.command())),
+            _ => Cow::Borrowed(text),
+        };
 
         let mut message_flags = MessageFlags::empty();
         let mut extra_tags = Vec::new();
@@ -266,10 +317,57 @@ impl<'a> StructuredMessage<'a> {
                     Cow::default()
                 }
             }
+            MessageType::Join => Cow::Owned(format!("{} joined the channel", self.user_login)),
+            MessageType::Part => Cow::Owned(format!("{} left the channel", self.user_login)),
             _ => Cow::default(),
         }
     }
 
+    /// Id of the channel a shared chat message was originally sent in
+    pub fn source_channel_id(&self) -> Option<&str> {
+        self.extra_tags
+            .iter()
+            .find(|(tag, _)| tag == "source-room-id")
+            .map(|(_, value)| value.as_ref())
+            .filter(|value| !value.is_empty())
+    }
+
+    /// Whether the message was sent by the user with the given id or login
+    pub fn is_sent_by(&self, user: &str) -> bool {
+        !self.user_id.is_empty()
+            && (self.user_id == user || self.user_login.eq_ignore_ascii_case(user))
+    }
+
+    /// Whether the message was sent in another channel and shown here through shared chat
+    pub fn is_shared(&self) -> bool {
+        self.source_channel_id()
+            .is_some_and(|source_channel_id| source_channel_id != self.channel_id)
+    }
+
+    /// Months the user has been subscribed to the channel, from the first `subscriber` or `founder` entry of the `badge-info` tag.
+    /// Unlike the version of the subscriber badge, this is the exact amount of months
+    pub fn subscriber_months(&self) -> Option<u32> {
+        self.badge_info.split(',').find_map(|badge| {
+            let (set, months) = badge.split_once('/')?;
+            match set {
+                "subscriber" | "founder" => months.parse().ok(),
+                _ => None,
+            }
+        })
+    }
+
+    /// Amount of bits cheered with the message
+    pub fn bits(&self) -> Option<u64> {
+        self.extra_tags
+            .iter()
+            .find(|(tag, _)| tag == Tag::Bits.as_str())
+            .and_then(|(_, value)| value.parse().ok())
+    }
+
+    pub fn uuid(&self) -> Option<Uuid> {
+        (!self.id.is_nil()).then_some(self.id)
+    }
+
     pub fn id(&self) -> Option<String> {
         if self.id.is_nil() {
             None
@@ -286,6 +384,22 @@ impl<'a> StructuredMessage<'a> {
         }
     }
 
+    /// Replaces the id, login and display name of the message's user, including the login in the text of bans and timeouts
+    pub fn replace_user(&mut self, replacement: &str) {
+        if !self.user_id.is_empty() {
+            self.user_id = Cow::Owned(replacement.to_owned());
+        }
+        if !self.user_login.is_empty() {
+            self.user_login = Cow::Owned(replacement.to_owned());
+        }
+        if !self.display_name.is_empty() {
+            self.display_name = Cow::Owned(replacement.to_owned());
+        }
+        if self.message_type == MessageType::ClearChat && !self.text.is_empty() {
+            self.text = Cow::Owned(replacement.to_owned());
+        }
+    }
+
     pub fn all_tags(&self, escape: bool) -> Vec<(Tag, Cow<'_, str>)> {
         let mut tags = Vec::with_capacity(16);
 
@@ -399,7 +513,7 @@ impl<'a> StructuredMessage<'a> {
         }
 
         match self.message_type {
-            MessageType::PrivMsg => {
+            MessageType::PrivMsg | MessageType::Join | MessageType::Part => {
                 let _ = write!(
                     out,
                     " :{name}!{name}@{name}.tmi.twitch.tv",
@@ -411,6 +525,18 @@ impl<'a> StructuredMessage<'a> {
             }
         }
 
+        if self.message_type == MessageType::Unknown {
+            let (command, params) = self.text.split_once(' ').unwrap_or((&self.text, ""));
+            let _ = write!(out, " {command}");
+            if !self.channel_login.is_empty() {
+                let _ = write!(out, " #{}", self.channel_login);
+            }
+            if !params.is_empty() {
+                let _ = write!(out, " {params}");
+            }
+            return out;
+        }
+
         let _ = write!(
             out,
             " {message_type} #{channel}",
@@ -432,6 +558,26 @@ impl<'a> StructuredMessage<'a> {
         out
     }
 
+    /// Truncates the text to at most `max_len` bytes (including a marker at the end, if it fits).
+    /// Returns whether the text was truncated
+    pub fn truncate_text(&mut self, max_len: usize) -> bool {
+        if self.text.len() <= max_len {
+            return false;
+        }
+
+        let marker = if max_len >= TRUNCATED_TEXT_MARKER.len() {
+            TRUNCATED_TEXT_MARKER
+        } else {
+            ""
+        };
+        let mut end = max_len - marker.len();
+        while !self.text.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.text = Cow::Owned(format!("{}{marker}", &self.text[..end]));
+        true
+    }
+
     pub fn into_owned(self) -> StructuredMessage<'static> {
         StructuredMessage {
             channel_id: Cow::Owned(self.channel_id.into_owned()),
@@ -506,6 +652,8 @@ pub enum MessageType {
     Pong = 12,
     ClearMsg = 13,
     GlobalUserState = 14,
+    /// Any other command, only stored when `logUnknownMessages` is enabled
+    Unknown = 255,
 }
 
 impl MessageType {
@@ -532,20 +680,19 @@ impl MessageType {
     }
 }
 
-fn extract_message_text(mut message_text: &str) -> &str {
-    let is_action =
-        message_text.starts_with("\u{0001}ACTION ") && message_text.ends_with('\u{0001}');
-    if is_action {
-        // remove the prefix and suffix
-        message_text = &message_text[8..message_text.len() - 1]
+fn extract_message_text(message_text: &str) -> &str {
+    match message_text.strip_prefix("\u{0001}ACTION ") {
+        // the suffix can be missing if the line was truncated
+        Some(action_text) => action_text.strip_suffix('\u{0001}').unwrap_or(action_text),
+        None => message_text,
     }
-
-    message_text
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{MessageType, StructuredMessage, UnstructuredMessage};
+    use super::{
+        extract_message_text, MessageType, ParseError, StructuredMessage, UnstructuredMessage,
+    };
     use crate::db::schema::MessageFlags;
     use pretty_assertions::assert_eq;
     use tmi::{IrcMessageRef, Tag};
@@ -587,6 +734,81 @@ mod tests {
         assert_eq!(expected_message, message);
     }
 
+    #[test]
+    fn extract_action_text() {
+        assert_eq!(
+            "waves",
+            extract_message_text("\u{0001}ACTION waves\u{0001}")
+        );
+        assert_eq!("hello", extract_message_text("hello"));
+    }
+
+    #[test]
+    fn extract_truncated_action_text() {
+        assert_eq!("waves", extract_message_text("\u{0001}ACTION waves"));
+        assert_eq!("", extract_message_text("\u{0001}ACTION \u{0001}"));
+    }
+
+    #[test]
+    fn subscriber_months_from_first_badge() {
+        let subscriber_months = |badge_info: &str| {
+            let raw = format!("@badge-info={badge_info};user-id=68136884;room-id=22484632;tmi-sent-ts=1709251274940 :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :+join");
+            let unstructured = UnstructuredMessage {
+                channel_id: "22484632",
+                user_id: "68136884",
+                timestamp: 1709251274940,
+                raw: &raw,
+            };
+            StructuredMessage::from_unstructured(&unstructured)
+                .unwrap()
+                .subscriber_months()
+        };
+
+        assert_eq!(Some(65), subscriber_months("subscriber/65"));
+        assert_eq!(Some(12), subscriber_months("founder/12,subscriber/30"));
+        assert_eq!(
+            Some(30),
+            subscriber_months("predictions/blue,subscriber/30")
+        );
+        assert_eq!(None, subscriber_months(""));
+    }
+
+    #[test]
+    fn match_sender_by_id_or_login() {
+        let raw = "@badges=;color=;display-name=Supibot;emotes=;room-id=22484632;tmi-sent-ts=1709251274940;user-id=68136884 :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :+join";
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "68136884",
+            timestamp: 1709251274940,
+            raw,
+        };
+        let message = StructuredMessage::from_unstructured(&unstructured).unwrap();
+
+        assert!(message.is_sent_by("68136884"));
+        assert!(message.is_sent_by("supibot"));
+        assert!(message.is_sent_by("Supibot"));
+        assert!(!message.is_sent_by("forsen"));
+        assert!(!message.is_sent_by("22484632"));
+    }
+
+    #[test]
+    fn truncate_long_text() {
+        let raw = "@user-id=68136884;room-id=22484632;tmi-sent-ts=1709251274940 :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :aaaaaaaaaaäaaaaaaaaaaaaaaaaa";
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "68136884",
+            timestamp: 1709251274940,
+            raw,
+        };
+        let mut message = StructuredMessage::from_unstructured(&unstructured).unwrap();
+
+        assert!(!message.truncate_text(100));
+        assert!(message.truncate_text(22));
+        assert_eq!("aaaaaaaaaa[truncated]", message.text);
+        assert!(message.truncate_text(5));
+        assert_eq!("aaaaa", message.text);
+    }
+
     #[test]
     fn roundtrip_tags() {
         let raw = "@returning-chatter=0;user-id=68136884;user-type=;badges=vip/1,subscriber/60;mod=0;display-name=Supibot;room-id=22484632;flags=;emotes=;first-msg=0;vip=1;tmi-sent-ts=1709251274940;id=272e342c-5864-4c59-b730-25908cdb7f57;subscriber=1;turbo=0;color=#1E90FF;badge-info=subscriber/65 :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :+join 󠀀";
@@ -617,6 +839,69 @@ mod tests {
         }
     }
 
+    #[test]
+    fn roundtrip_unknown_command() {
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "",
+            timestamp: 1686947117960,
+            raw: "@room-id=22484632;tmi-sent-ts=1686947117960 :tmi.twitch.tv HOSTTARGET #forsen :xqcow 100",
+        };
+        assert!(StructuredMessage::from_unstructured(&unstructured).is_err());
+
+        let message = StructuredMessage::from_unstructured_allow_unknown(&unstructured).unwrap();
+        assert_eq!(MessageType::Unknown, message.message_type);
+
+        let converted = message.to_raw_irc();
+        let original = IrcMessageRef::parse(unstructured.raw).unwrap();
+        let converted = IrcMessageRef::parse(&converted).unwrap();
+
+        assert_eq!(original.command(), converted.command());
+        assert_eq!(original.channel(), converted.channel());
+        assert_eq!(original.params(), converted.params());
+    }
+
+    #[test]
+    fn parse_error_malformed_irc() {
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "",
+            timestamp: 1686947117960,
+            raw: "\0\r\n",
+        };
+        let err = StructuredMessage::from_unstructured(&unstructured).unwrap_err();
+        assert_eq!(ParseError::MalformedIrc, err);
+        assert_eq!("malformed_irc", err.category());
+    }
+
+    #[test]
+    fn parse_error_unsupported_command() {
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "",
+            timestamp: 1686947117960,
+            raw: "@room-id=22484632;tmi-sent-ts=1686947117960 :tmi.twitch.tv HOSTTARGET #forsen :xqcow 100",
+        };
+        let err = StructuredMessage::from_unstructured(&unstructured).unwrap_err();
+        assert_eq!(ParseError::UnsupportedCommand("HOSTTARGET".to_owned()), err);
+        assert_eq!("unsupported_command", err.category());
+    }
+
+    #[test]
+    fn membership_events() {
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "68136884",
+            timestamp: 1686947117960,
+            raw: "@room-id=22484632;tmi-sent-ts=1686947117960;user-id=68136884 :supibot!supibot@supibot.tmi.twitch.tv JOIN #forsen",
+        };
+        let message = StructuredMessage::from_unstructured(&unstructured).unwrap();
+        assert_eq!(MessageType::Join, message.message_type);
+        assert_eq!("supibot", message.user_login);
+        assert_eq!("supibot joined the channel", message.user_friendly_text());
+        assert_roundtrip(unstructured);
+    }
+
     fn assert_roundtrip(unstructured: UnstructuredMessage) {
         let message = StructuredMessage::from_unstructured(&unstructured).unwrap();
         let converted = message.to_raw_irc();
@@ -1,12 +1,14 @@
 use super::schema::StructuredMessage;
 use crate::{db::schema::MESSAGES_STRUCTURED_TABLE, ShutdownRx};
 use anyhow::{anyhow, Context};
-use clickhouse::Client;
+use clickhouse::{insert::Insert, Client};
+use futures::Future;
 use lazy_static::lazy_static;
 use prometheus::{register_int_gauge, IntGauge};
 use std::{ops::Range, sync::Arc, time::Duration};
 use tokio::{
     sync::{
+        broadcast,
         mpsc::{channel, Sender},
         RwLock,
     },
@@ -16,6 +18,8 @@ use tokio::{
 use tracing::{debug, error, info, trace};
 
 const RETRY_COUNT: usize = 20;
+/// How many new messages can be queued for live subscribers which are falling behind
+const LIVE_MESSAGES_CAPACITY: usize = 1024;
 const RETRY_INTERVAL_SECONDS: u64 = 5;
 
 lazy_static! {
@@ -26,12 +30,62 @@ lazy_static! {
     .unwrap();
 }
 
-#[derive(Default, Clone)]
+/// Destination the buffered messages are written to, so that the flushing can be tested with an in-memory sink.
+/// Only the write path goes through it, the reads in `db` query ClickHouse directly
+pub trait MessageSink: Send + Sync + 'static {
+    type Insert: PendingInsert;
+
+    /// Writes the messages, which are only stored once the returned insert is ended
+    fn write(
+        &self,
+        messages: &[StructuredMessage<'static>],
+    ) -> impl Future<Output = anyhow::Result<Self::Insert>> + Send;
+}
+
+pub trait PendingInsert: Send {
+    fn end(self) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+impl MessageSink for Client {
+    type Insert = Insert<StructuredMessage<'static>>;
+
+    async fn write(&self, messages: &[StructuredMessage<'static>]) -> anyhow::Result<Self::Insert> {
+        let mut insert = self.insert(MESSAGES_STRUCTURED_TABLE)?;
+        for message in messages {
+            insert.write(message).await.context("Could not write row")?;
+        }
+        Ok(insert)
+    }
+}
+
+impl PendingInsert for Insert<StructuredMessage<'static>> {
+    async fn end(self) -> anyhow::Result<()> {
+        Insert::end(self).await.context("Could not end insert")
+    }
+}
+
+#[derive(Clone)]
 pub struct FlushBuffer {
     messages: Arc<RwLock<Vec<StructuredMessage<'static>>>>,
+    live_tx: broadcast::Sender<StructuredMessage<'static>>,
+}
+
+impl Default for FlushBuffer {
+    fn default() -> Self {
+        let (live_tx, _) = broadcast::channel(LIVE_MESSAGES_CAPACITY);
+        Self {
+            messages: Arc::default(),
+            live_tx,
+        }
+    }
 }
 
 impl FlushBuffer {
+    /// Receives all messages as they are logged, before they are written to the database
+    pub fn subscribe(&self) -> broadcast::Receiver<StructuredMessage<'static>> {
+        self.live_tx.subscribe()
+    }
+
     pub async fn messages_by_channel(
         &self,
         time_range: Range<u64>,
@@ -50,11 +104,11 @@ impl FlushBuffer {
         msgs
     }
 
-    pub async fn messages_by_channel_and_user(
+    pub async fn messages_by_channel_and_users(
         &self,
         time_range: Range<u64>,
         channel_id: &str,
-        user_id: &str,
+        user_ids: &[&str],
     ) -> Vec<StructuredMessage<'static>> {
         let msgs = self
             .messages
@@ -62,16 +116,33 @@ impl FlushBuffer {
             .await
             .iter()
             .filter(|msg| time_range.contains(&msg.timestamp))
-            .filter(|msg| msg.channel_id == channel_id && msg.user_id == user_id)
+            .filter(|msg| msg.channel_id == channel_id && user_ids.contains(&msg.user_id.as_ref()))
             .cloned()
             .collect::<Vec<_>>();
         trace!("Read {} messages from flush buffer", msgs.len());
         msgs
     }
+
+    pub async fn message_count(&self) -> usize {
+        self.messages.read().await.len()
+    }
+
+    pub async fn message_by_id(
+        &self,
+        channel_id: &str,
+        message_id: &str,
+    ) -> Option<StructuredMessage<'static>> {
+        self.messages
+            .read()
+            .await
+            .iter()
+            .find(|msg| msg.channel_id == channel_id && msg.id().as_deref() == Some(message_id))
+            .cloned()
+    }
 }
 
-pub async fn create_writer(
-    db: Client,
+pub async fn create_writer<S: MessageSink>(
+    sink: S,
     mut shutdown_rx: ShutdownRx,
     flush_interval: u64,
 ) -> anyhow::Result<(
@@ -92,17 +163,19 @@ pub async fn create_writer(
             tokio::select! {
                 _ = &mut timeout => {
                     timeout.as_mut().reset(Instant::now() + Duration::from_secs(flush_interval));
-                    if let Err(err) = write_chunk_with_retry(&db, &flush_buffer).await {
+                    if let Err(err) = write_chunk_with_retry(&sink, &flush_buffer).await {
                         error!("Could not write messages: {err}");
                     }
                 }
                 Some(msg) = rx.recv() => {
+                    // Sending only fails when there are no subscribers
+                    let _ = flush_buffer.live_tx.send(msg.clone());
                     flush_buffer.messages.write().await.push(msg);
                 }
                 Ok(()) = shutdown_rx.changed() => {
                     info!("Flushing database write buffer");
 
-                    if let Err(err) = write_chunk_with_retry(&db, &flush_buffer).await {
+                    if let Err(err) = write_chunk_with_retry(&sink, &flush_buffer).await {
                         error!("Could not flush messages: {err}");
                     }
 
@@ -115,9 +188,12 @@ pub async fn create_writer(
     Ok((tx, flush_buffer_clone, handle))
 }
 
-async fn write_chunk_with_retry(db: &Client, buffer: &FlushBuffer) -> anyhow::Result<()> {
+async fn write_chunk_with_retry<S: MessageSink>(
+    sink: &S,
+    buffer: &FlushBuffer,
+) -> anyhow::Result<()> {
     for attempt in 1..=RETRY_COUNT {
-        match write_chunk(db, buffer).await {
+        match write_chunk(sink, buffer).await {
             Ok(()) => {
                 if attempt > 1 {
                     debug!("Insert succeeded on attempt {attempt}");
@@ -135,19 +211,18 @@ async fn write_chunk_with_retry(db: &Client, buffer: &FlushBuffer) -> anyhow::Re
     ))
 }
 
-async fn write_chunk(db: &Client, buffer: &FlushBuffer) -> anyhow::Result<()> {
+async fn write_chunk<S: MessageSink>(sink: &S, buffer: &FlushBuffer) -> anyhow::Result<()> {
     let messages_read_guard = buffer.messages.read().await;
 
     let started_at = Instant::now();
 
-    let mut insert = db.insert(MESSAGES_STRUCTURED_TABLE)?;
-    for message in messages_read_guard.iter() {
-        insert.write(message).await.context("Could not write row")?;
-    }
+    let insert = sink.write(&messages_read_guard).await?;
     drop(messages_read_guard);
 
+    // The messages are removed from the buffer in the same step as they become readable from the database,
+    // so that readers see every message exactly once
     let mut messages_write_guard = buffer.messages.write().await;
-    insert.end().await.context("Could not end insert")?;
+    insert.end().await?;
 
     debug!(
         "{} messages have been inserted (took {}ms)",
@@ -159,3 +234,89 @@ async fn write_chunk(db: &Client, buffer: &FlushBuffer) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{create_writer, MessageSink, PendingInsert};
+    use crate::db::schema::{StructuredMessage, UnstructuredMessage};
+    use pretty_assertions::assert_eq;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::watch;
+
+    /// Keeps the written messages in memory
+    #[derive(Clone, Default)]
+    struct MemorySink {
+        messages: Arc<Mutex<Vec<StructuredMessage<'static>>>>,
+    }
+
+    impl MessageSink for MemorySink {
+        type Insert = MemoryInsert;
+
+        async fn write(
+            &self,
+            messages: &[StructuredMessage<'static>],
+        ) -> anyhow::Result<MemoryInsert> {
+            Ok(MemoryInsert {
+                sink: self.clone(),
+                messages: messages.to_vec(),
+            })
+        }
+    }
+
+    struct MemoryInsert {
+        sink: MemorySink,
+        messages: Vec<StructuredMessage<'static>>,
+    }
+
+    impl PendingInsert for MemoryInsert {
+        async fn end(self) -> anyhow::Result<()> {
+            self.sink.messages.lock().unwrap().extend(self.messages);
+            Ok(())
+        }
+    }
+
+    fn message(timestamp: u64) -> StructuredMessage<'static> {
+        let raw = format!("@badges=;color=;display-name=Supibot;emotes=;room-id=22484632;tmi-sent-ts={timestamp};user-id=68136884 :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :+join");
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "68136884",
+            timestamp,
+            raw: &raw,
+        };
+        StructuredMessage::from_unstructured(&unstructured)
+            .unwrap()
+            .into_owned()
+    }
+
+    #[tokio::test]
+    async fn flush_buffer_on_shutdown() {
+        let sink = MemorySink::default();
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        let (tx, flush_buffer, handle) = create_writer(sink.clone(), shutdown_rx, 3600)
+            .await
+            .unwrap();
+
+        let messages = vec![message(1686947117960), message(1686947121100)];
+        for msg in &messages {
+            tx.send(msg.clone()).await.unwrap();
+        }
+
+        // Buffered messages are readable before being written
+        while flush_buffer.message_count().await < messages.len() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            messages,
+            flush_buffer
+                .messages_by_channel(0..u64::MAX, "22484632")
+                .await
+        );
+        assert!(sink.messages.lock().unwrap().is_empty());
+
+        shutdown_tx.send(()).unwrap();
+        handle.await.unwrap();
+
+        assert_eq!(messages, *sink.messages.lock().unwrap());
+        assert_eq!(0, flush_buffer.message_count().await);
+    }
+}
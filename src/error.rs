@@ -1,6 +1,11 @@
 use aide::{openapi::MediaType, OperationOutput};
-use axum::response::{IntoResponse, Response};
+use axum::{
+    response::{IntoResponse, Response},
+    Json,
+};
 use reqwest::StatusCode;
+use schemars::JsonSchema;
+use serde::Serialize;
 use std::num::ParseIntError;
 use thiserror::Error;
 use tracing::error;
@@ -16,38 +21,105 @@ pub enum Error {
     ParseInt(#[from] ParseIntError),
     #[error("Invalid param: {0}")]
     InvalidParam(String),
+    #[error("Invalid date: {0}")]
+    InvalidDate(String),
+    #[error("Invalid format: {0}")]
+    InvalidFormat(String),
+    #[error("Request too large: {0}")]
+    TooLarge(String),
     #[error("Internal error")]
     Internal,
     #[error("Database error")]
     Clickhouse(#[from] clickhouse::error::Error),
+    #[error("No, I don't think so")]
+    InvalidApiKey,
     #[error("The requested channel has opted out of being logged")]
     ChannelOptedOut,
     #[error("The requested user has opted out of being logged")]
     UserOptedOut,
     #[error("Not found")]
     NotFound,
+    #[error("Channel not found")]
+    ChannelNotFound,
+    #[error("User not found")]
+    UserNotFound,
+    #[error("Too many logs requests are being processed, try again later")]
+    Overloaded,
+    #[error("The request took too long to process")]
+    Timeout,
 }
 
-impl IntoResponse for Error {
-    fn into_response(self) -> Response {
-        let status_code = match &self {
-            Error::Helix(_) | Error::Io(_) | Error::Internal => StatusCode::INTERNAL_SERVER_ERROR,
-            Error::Clickhouse(error) => {
-                error!("DB error: {error}");
+/// Body of every error response
+#[derive(Serialize, JsonSchema, Clone)]
+pub struct ErrorResponse {
+    /// Human readable description of the error
+    pub error: String,
+    /// Machine readable error code, e.g. `not_found` or `invalid_date`
+    pub code: &'static str,
+}
+
+impl Error {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::Helix(_) | Error::Io(_) | Error::Internal | Error::Clickhouse(_) => {
                 StatusCode::INTERNAL_SERVER_ERROR
             }
-            Error::ParseInt(_) | Error::InvalidParam(_) => StatusCode::BAD_REQUEST,
-            Error::ChannelOptedOut | Error::UserOptedOut => StatusCode::FORBIDDEN,
-            Error::NotFound => StatusCode::NOT_FOUND,
-        };
+            Error::ParseInt(_)
+            | Error::InvalidParam(_)
+            | Error::InvalidDate(_)
+            | Error::InvalidFormat(_) => StatusCode::BAD_REQUEST,
+            Error::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::InvalidApiKey | Error::ChannelOptedOut | Error::UserOptedOut => {
+                StatusCode::FORBIDDEN
+            }
+            Error::NotFound | Error::ChannelNotFound | Error::UserNotFound => StatusCode::NOT_FOUND,
+            Error::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
 
-        (status_code, self.to_string()).into_response()
+    /// Stable identifier of the error kind for clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Helix(_) => "twitch_api_error",
+            Error::Io(_) | Error::Internal => "internal_error",
+            Error::Clickhouse(_) => "database_error",
+            Error::ParseInt(_) | Error::InvalidParam(_) => "invalid_param",
+            Error::InvalidDate(_) => "invalid_date",
+            Error::InvalidFormat(_) => "invalid_format",
+            Error::TooLarge(_) => "too_large",
+            Error::InvalidApiKey => "invalid_api_key",
+            Error::ChannelOptedOut => "channel_opted_out",
+            Error::UserOptedOut => "user_opted_out",
+            Error::NotFound => "not_found",
+            Error::ChannelNotFound => "channel_not_found",
+            Error::UserNotFound => "user_not_found",
+            Error::Overloaded => "overloaded",
+            Error::Timeout => "timeout",
+        }
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        if let Error::Clickhouse(error) = &self {
+            error!(%error, "DB error");
+        }
+
+        let body = ErrorResponse {
+            error: self.to_string(),
+            code: self.code(),
+        };
+        let mut response = (self.status_code(), Json(body.clone())).into_response();
+        // Used for rendering the error in other formats
+        response.extensions_mut().insert(body);
+        response
     }
 }
 
 impl From<anyhow::Error> for Error {
     fn from(err: anyhow::Error) -> Self {
-        error!("Error: {err}");
+        error!(error = %err, "Internal error");
         Self::Internal
     }
 }
@@ -56,14 +128,24 @@ impl OperationOutput for Error {
     type Inner = Self;
 
     fn operation_response(
-        _: &mut aide::gen::GenContext,
+        ctx: &mut aide::gen::GenContext,
         _: &mut aide::openapi::Operation,
     ) -> Option<aide::openapi::Response> {
         Some(aide::openapi::Response {
             description: "Error response".into(),
-            content: [("text/plain".into(), MediaType::default())]
-                .into_iter()
-                .collect(),
+            content: [(
+                "application/json".into(),
+                MediaType {
+                    schema: Some(aide::openapi::SchemaObject {
+                        json_schema: ctx.schema.subschema_for::<ErrorResponse>(),
+                        example: None,
+                        external_docs: None,
+                    }),
+                    ..Default::default()
+                },
+            )]
+            .into_iter()
+            .collect(),
             ..Default::default()
         })
     }
@@ -95,10 +177,32 @@ impl OperationOutput for Error {
                         ..res.clone()
                     },
                 ),
+                (
+                    Some(413),
+                    aide::openapi::Response {
+                        description: "The request asks for too much data".to_owned(),
+                        ..res.clone()
+                    },
+                ),
                 (
                     Some(500),
                     aide::openapi::Response {
                         description: "An internal server error occured".to_owned(),
+                        ..res.clone()
+                    },
+                ),
+                (
+                    Some(503),
+                    aide::openapi::Response {
+                        description: "The server is processing too many requests".to_owned(),
+                        ..res.clone()
+                    },
+                ),
+                (
+                    Some(504),
+                    aide::openapi::Response {
+                        description: "The request took longer than the configured timeout"
+                            .to_owned(),
                         ..res
                     },
                 ),
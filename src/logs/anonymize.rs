@@ -0,0 +1,218 @@
+use crate::db::schema::StructuredMessage;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+/// Tags containing the name of the user of the message
+const OWN_USER_TAGS: &[&str] = &["msg-param-login", "msg-param-displayName"];
+
+/// Tags referring to other users than the one of the message: the id tag (if there is one) and the name tags of each user
+const OTHER_USER_TAGS: &[(Option<&str>, &[&str])] = &[
+    (
+        Some("reply-parent-user-id"),
+        &["reply-parent-user-login", "reply-parent-display-name"],
+    ),
+    (
+        Some("reply-thread-parent-user-id"),
+        &[
+            "reply-thread-parent-user-login",
+            "reply-thread-parent-display-name",
+        ],
+    ),
+    (
+        Some("msg-param-recipient-id"),
+        &[
+            "msg-param-recipient-user-name",
+            "msg-param-recipient-display-name",
+        ],
+    ),
+    (
+        Some("msg-param-gifter-id"),
+        &["msg-param-gifter-login", "msg-param-gifter-name"],
+    ),
+    (
+        Some("msg-param-prior-gifter-id"),
+        &[
+            "msg-param-prior-gifter-user-name",
+            "msg-param-prior-gifter-display-name",
+        ],
+    ),
+    (None, &["msg-param-sender-login", "msg-param-sender-name"]),
+];
+
+/// Tag with the text Twitch shows for user notices, which contains the names of the users involved
+const SYSTEM_MSG_TAG: &str = "system-msg";
+
+/// Replaces the ids and names of users with pseudonyms.
+/// A random salt is used for every response, so that the same user gets the same pseudonym within it but not across responses
+pub struct Anonymizer {
+    salt: [u8; 16],
+}
+
+impl Anonymizer {
+    pub fn new() -> Self {
+        Self {
+            salt: rand::random(),
+        }
+    }
+
+    pub fn anonymize(&self, msg: &mut StructuredMessage) {
+        // Replaced names with their pseudonyms, which are also replaced in the system message
+        let mut replaced_names = Vec::new();
+
+        let user = if msg.user_id.is_empty() {
+            &msg.user_login
+        } else {
+            &msg.user_id
+        };
+        if !user.is_empty() {
+            let pseudonym = self.pseudonym(user);
+            replaced_names.push((msg.user_login.to_string(), pseudonym.clone()));
+            replaced_names.push((msg.display_name().to_owned(), pseudonym.clone()));
+
+            msg.replace_user(&pseudonym);
+            for (tag, value) in &mut msg.extra_tags {
+                if OWN_USER_TAGS.contains(&tag.as_ref()) {
+                    *value = Cow::Owned(pseudonym.clone());
+                }
+            }
+        }
+
+        for (id_tag, name_tags) in OTHER_USER_TAGS {
+            let tag_value = |name: &str| {
+                msg.extra_tags
+                    .iter()
+                    .find(|(tag, value)| tag == name && !value.is_empty())
+                    .map(|(_, value)| value)
+            };
+            // The id is preferred, so that the user gets the same pseudonym no matter which tags are set
+            let other_user = id_tag
+                .and_then(tag_value)
+                .or_else(|| name_tags.iter().find_map(|tag| tag_value(tag)))
+                .map(|value| self.pseudonym(value));
+            let Some(pseudonym) = other_user else {
+                continue;
+            };
+
+            for (tag, value) in &mut msg.extra_tags {
+                let is_name_tag = name_tags.contains(&tag.as_ref());
+                if is_name_tag {
+                    replaced_names.push((value.to_string(), pseudonym.clone()));
+                }
+                if is_name_tag || Some(tag.as_ref()) == *id_tag {
+                    *value = Cow::Owned(pseudonym.clone());
+                }
+            }
+        }
+
+        replaced_names.retain(|(name, _)| !name.is_empty());
+        // Longer names first, so that names containing other ones are replaced as a whole
+        replaced_names.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+        for (tag, value) in &mut msg.extra_tags {
+            if tag == SYSTEM_MSG_TAG {
+                let mut system_msg = value.to_string();
+                for (name, pseudonym) in &replaced_names {
+                    system_msg = system_msg.replace(name.as_str(), pseudonym);
+                }
+                *value = Cow::Owned(system_msg);
+            }
+        }
+    }
+
+    fn pseudonym(&self, user: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt);
+        hasher.update(user.to_lowercase().as_bytes());
+        let hash = hasher.finalize();
+        format!(
+            "user{:x}",
+            u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]])
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Anonymizer;
+    use crate::db::schema::{StructuredMessage, UnstructuredMessage};
+    use pretty_assertions::assert_eq;
+
+    fn message(raw: &str) -> StructuredMessage<'static> {
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "68136884",
+            timestamp: 1709251274940,
+            raw,
+        };
+        StructuredMessage::from_unstructured(&unstructured)
+            .unwrap()
+            .into_owned()
+    }
+
+    #[test]
+    fn consistent_pseudonyms() {
+        let anonymizer = Anonymizer::new();
+        let mut privmsg = message("@badges=;color=;display-name=Supibot;emotes=;id=272e342c-5864-4c59-b730-25908cdb7f57;reply-parent-display-name=Forsen;reply-parent-user-id=22484632;reply-parent-user-login=forsen;room-id=22484632;tmi-sent-ts=1709251274940;user-id=68136884 :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :@forsen hello");
+        let mut ban = message("@room-id=22484632;target-user-id=68136884;tmi-sent-ts=1709251274940 :tmi.twitch.tv CLEARCHAT #forsen :supibot");
+
+        anonymizer.anonymize(&mut privmsg);
+        anonymizer.anonymize(&mut ban);
+
+        assert_ne!("supibot", privmsg.user_login);
+        assert_eq!(privmsg.user_login, privmsg.display_name());
+        assert_eq!(privmsg.user_login, privmsg.user_id);
+        assert_eq!(privmsg.user_login, ban.user_login);
+        assert_eq!(
+            format!("{} has been banned", privmsg.user_login),
+            ban.user_friendly_text()
+        );
+
+        let reply_tags: Vec<&str> = privmsg
+            .extra_tags
+            .iter()
+            .filter(|(tag, _)| tag.starts_with("reply-parent-"))
+            .map(|(_, value)| value.as_ref())
+            .collect();
+        assert_eq!(3, reply_tags.len());
+        assert!(reply_tags.iter().all(|value| value.starts_with("user")));
+        assert_ne!(privmsg.user_login, reply_tags[0]);
+        // Mentions in the text are kept
+        assert_eq!("@forsen hello", privmsg.user_friendly_text());
+    }
+
+    #[test]
+    fn anonymize_user_notice_tags() {
+        let anonymizer = Anonymizer::new();
+        let mut subgift = message(
+            r"@badges=;color=;display-name=Supibot;emotes=;id=272e342c-5864-4c59-b730-25908cdb7f57;login=supibot;msg-id=subgift;msg-param-months=1;msg-param-recipient-display-name=Forsen;msg-param-recipient-id=22484632;msg-param-recipient-user-name=forsen;msg-param-sender-count=0;msg-param-sub-plan=1000;room-id=22484632;system-msg=Supibot\sgifted\sa\sTier\s1\ssub\sto\sForsen!;tmi-sent-ts=1709251274940;user-id=68136884 :tmi.twitch.tv USERNOTICE #forsen",
+        );
+        let mut upgrade = message(
+            r"@badges=;color=;display-name=Supibot;emotes=;id=272e342c-5864-4c59-b730-25908cdb7f58;login=supibot;msg-id=giftpaidupgrade;msg-param-sender-login=forsen;msg-param-sender-name=Forsen;room-id=22484632;system-msg=Supibot\sis\scontinuing\sthe\sGift\sSub\sthey\sgot\sfrom\sForsen!;tmi-sent-ts=1709251274940;user-id=68136884 :tmi.twitch.tv USERNOTICE #forsen",
+        );
+
+        anonymizer.anonymize(&mut subgift);
+        anonymizer.anonymize(&mut upgrade);
+
+        let tag = |msg: &StructuredMessage, name: &str| {
+            msg.extra_tags
+                .iter()
+                .find(|(tag, _)| tag == name)
+                .map(|(_, value)| value.to_string())
+                .unwrap()
+        };
+        let user = subgift.user_login.to_string();
+        let recipient = tag(&subgift, "msg-param-recipient-user-name");
+        assert_eq!(user, subgift.display_name());
+        assert_eq!(recipient, tag(&subgift, "msg-param-recipient-id"));
+        assert_eq!(
+            format!("{user} gifted a Tier 1 sub to {recipient}!"),
+            tag(&subgift, "system-msg")
+        );
+
+        let sender = tag(&upgrade, "msg-param-sender-login");
+        assert_eq!(sender, tag(&upgrade, "msg-param-sender-name"));
+        assert_eq!(
+            format!("{user} is continuing the Gift Sub they got from {sender}!"),
+            tag(&upgrade, "system-msg")
+        );
+    }
+}
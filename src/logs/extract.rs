@@ -1,6 +1,29 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 use tmi::Tag;
+use tracing::warn;
 use twitch_irc::message::IRCMessage;
 
+/// 2011-01-01, before any messages with timestamps were sent on Twitch
+const MIN_TIMESTAMP: u64 = 1_293_840_000_000;
+/// How far a timestamp may be after the time the message was received (or the day of its log file)
+const MAX_TIMESTAMP_SKEW: u64 = 2 * 24 * 60 * 60 * 1000;
+
+/// What to do with messages whose `tmi-sent-ts` is far in the future or past
+#[derive(Serialize, Deserialize, ValueEnum, Clone, Copy, Default, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampPolicy {
+    /// Store the timestamp as it is
+    #[default]
+    Keep,
+    /// Move the timestamp into the valid range
+    Clamp,
+    /// Use the time the message was received, or the day of its log file
+    Fallback,
+    /// Don't store the message
+    Drop,
+}
+
 pub trait MessageWithTags {
     fn get_tag(&self, key: Tag) -> Option<&str>;
 }
@@ -20,9 +43,22 @@ impl MessageWithTags for tmi::IrcMessageRef<'_> {
     }
 }
 
+/// Twitch user ids are numeric. Other values, such as an empty `user-id` tag from malformed upstream data, are ignored
 pub fn extract_user_id<T: MessageWithTags>(msg: &T) -> Option<&str> {
-    msg.get_tag(Tag::UserId)
-        .or_else(|| msg.get_tag(Tag::TargetUserId))
+    [Tag::UserId, Tag::TargetUserId]
+        .into_iter()
+        .filter_map(|tag| msg.get_tag(tag))
+        .find(|user_id| is_valid_user_id(user_id))
+}
+
+/// Whether the message has a user id tag, but none with a valid value
+pub fn has_invalid_user_id<T: MessageWithTags>(msg: &T) -> bool {
+    extract_user_id(msg).is_none()
+        && (msg.get_tag(Tag::UserId).is_some() || msg.get_tag(Tag::TargetUserId).is_some())
+}
+
+fn is_valid_user_id(user_id: &str) -> bool {
+    !user_id.is_empty() && user_id.bytes().all(|c| c.is_ascii_digit())
 }
 
 pub fn extract_channel_and_user_from_raw<T: MessageWithTags>(
@@ -38,3 +74,120 @@ pub fn extract_raw_timestamp<T: MessageWithTags>(msg: &T) -> Option<u64> {
     msg.get_tag(Tag::TmiSentTs)
         .and_then(|raw_timestamp| raw_timestamp.parse().ok())
 }
+
+/// Timestamp of the message according to the policy, `None` if it should be dropped.
+/// Messages without a timestamp always get the fallback
+pub fn resolve_timestamp(
+    timestamp: Option<u64>,
+    fallback: u64,
+    policy: TimestampPolicy,
+) -> Option<u64> {
+    let Some(timestamp) = timestamp else {
+        return Some(fallback);
+    };
+
+    // The fallback can itself be before the minimum, e.g. when reimporting very old logs
+    let max_timestamp = fallback
+        .saturating_add(MAX_TIMESTAMP_SKEW)
+        .max(MIN_TIMESTAMP);
+    if policy == TimestampPolicy::Keep || (MIN_TIMESTAMP..=max_timestamp).contains(&timestamp) {
+        return Some(timestamp);
+    }
+
+    warn!("Out of range message timestamp {timestamp}, applying the {policy:?} policy");
+    match policy {
+        TimestampPolicy::Keep => Some(timestamp),
+        TimestampPolicy::Clamp => Some(timestamp.clamp(MIN_TIMESTAMP, max_timestamp)),
+        TimestampPolicy::Fallback => Some(fallback),
+        TimestampPolicy::Drop => None,
+    }
+}
+
+/// Cheaply checks if a raw IRC line is a PRIVMSG without fully parsing it
+pub fn is_raw_privmsg(raw: &str) -> bool {
+    let mut rest = raw.trim_start();
+    if rest.starts_with('@') {
+        rest = rest.split_once(' ').map_or("", |(_, rest)| rest);
+    }
+    if rest.starts_with(':') {
+        rest = rest.split_once(' ').map_or("", |(_, rest)| rest);
+    }
+    rest.starts_with("PRIVMSG ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract_user_id, has_invalid_user_id, resolve_timestamp, TimestampPolicy};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn extract_valid_user_id() {
+        let msg = tmi::IrcMessageRef::parse("@room-id=22484632;user-id=68136884 :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :+join").unwrap();
+        assert_eq!(Some("68136884"), extract_user_id(&msg));
+        assert!(!has_invalid_user_id(&msg));
+    }
+
+    #[test]
+    fn ignore_empty_user_id() {
+        let msg = tmi::IrcMessageRef::parse("@room-id=22484632;user-id= :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :+join").unwrap();
+        assert_eq!(None, extract_user_id(&msg));
+        assert!(has_invalid_user_id(&msg));
+    }
+
+    #[test]
+    fn ignore_non_numeric_user_id() {
+        let msg = tmi::IrcMessageRef::parse("@room-id=22484632;user-id=../68136884 :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :+join").unwrap();
+        assert_eq!(None, extract_user_id(&msg));
+        assert!(has_invalid_user_id(&msg));
+    }
+
+    #[test]
+    fn resolve_out_of_range_timestamps() {
+        let received = 1709251274940;
+        let future = 32503680000000;
+        let past = 1000;
+
+        assert_eq!(
+            Some(received - 500),
+            resolve_timestamp(Some(received - 500), received, TimestampPolicy::Drop)
+        );
+        assert_eq!(
+            Some(received),
+            resolve_timestamp(None, received, TimestampPolicy::Drop)
+        );
+
+        assert_eq!(
+            Some(future),
+            resolve_timestamp(Some(future), received, TimestampPolicy::Keep)
+        );
+        assert_eq!(
+            Some(received + 2 * 24 * 60 * 60 * 1000),
+            resolve_timestamp(Some(future), received, TimestampPolicy::Clamp)
+        );
+        assert_eq!(
+            Some(1_293_840_000_000),
+            resolve_timestamp(Some(past), received, TimestampPolicy::Clamp)
+        );
+        assert_eq!(
+            Some(received),
+            resolve_timestamp(Some(future), received, TimestampPolicy::Fallback)
+        );
+        assert_eq!(
+            None,
+            resolve_timestamp(Some(past), received, TimestampPolicy::Drop)
+        );
+    }
+
+    #[test]
+    fn clamp_with_fallback_before_min_timestamp() {
+        let received = 1000;
+        assert_eq!(
+            Some(1_293_840_000_000),
+            resolve_timestamp(Some(500), received, TimestampPolicy::Clamp)
+        );
+        assert_eq!(
+            Some(1_293_840_000_000),
+            resolve_timestamp(Some(32503680000000), received, TimestampPolicy::Clamp)
+        );
+    }
+}
@@ -1,3 +1,4 @@
+pub mod anonymize;
 pub mod extract;
 pub mod schema;
 pub mod stream;
@@ -16,7 +16,12 @@ pub struct BasicMessage<'a> {
     #[schemars(with = "String")]
     pub timestamp: DateTime<Utc>,
     pub id: Cow<'a, str>,
-    pub tags: HashMap<&'a str, Cow<'a, str>>,
+    /// Left out with `tags=none`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<HashMap<&'a str, Cow<'a, str>>>,
+    /// Position of the message in the response, added with `lineNumbers`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<u64>,
 }
 
 impl<'a> ResponseMessage<'a> for BasicMessage<'a> {
@@ -27,13 +32,19 @@ impl<'a> ResponseMessage<'a> for BasicMessage<'a> {
             timestamp: chrono::DateTime::from_timestamp_millis(msg.timestamp.try_into()?)
                 .context("Invalid timestamp")?,
             id: Cow::Owned(msg.id().unwrap_or_default()),
-            tags: msg
-                .all_tags(false)
-                .into_iter()
-                .map(|(tag, value)| (tag.as_str(), value))
-                .collect(),
+            tags: Some(
+                msg.all_tags(false)
+                    .into_iter()
+                    .map(|(tag, value)| (tag.as_str(), value))
+                    .collect(),
+            ),
+            line: None,
         })
     }
+
+    fn basic_mut(&mut self) -> &mut BasicMessage<'a> {
+        self
+    }
 }
 
 #[cfg(test)]
@@ -57,7 +68,7 @@ mod tests {
         let basic = BasicMessage::from_structured(&structured).unwrap();
         assert_eq!(
             "daney___ subscribed at Tier 1. They've subscribed for 19 months!",
-            basic.tags.get("system-msg").unwrap()
+            basic.tags.unwrap().get("system-msg").unwrap()
         );
     }
 }
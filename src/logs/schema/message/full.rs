@@ -10,9 +10,18 @@ pub struct FullMessage<'a> {
     pub basic: BasicMessage<'a>,
     pub username: &'a str,
     pub channel: &'a str,
-    pub raw: String,
+    pub channel_id: &'a str,
+    /// Left out with the `omitRaw` param
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<String>,
     #[schemars(with = "i8")]
     pub r#type: MessageType,
+    /// Amount of bits cheered with the message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bits: Option<u64>,
+    /// Id of the channel the message was originally sent in, for shared chat messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_channel_id: Option<&'a str>,
 }
 
 impl<'a> ResponseMessage<'a> for FullMessage<'a> {
@@ -22,10 +31,21 @@ impl<'a> ResponseMessage<'a> for FullMessage<'a> {
             basic,
             username: &msg.user_login,
             channel: &msg.channel_login,
-            raw: msg.to_raw_irc(),
+            channel_id: &msg.channel_id,
+            raw: Some(msg.to_raw_irc()),
             r#type: msg.message_type,
+            bits: msg.bits(),
+            source_channel_id: msg.source_channel_id(),
         })
     }
+
+    fn basic_mut(&mut self) -> &mut BasicMessage<'a> {
+        &mut self.basic
+    }
+
+    fn omit_raw(&mut self) {
+        self.raw = None;
+    }
 }
 
 #[cfg(test)]
@@ -60,34 +80,61 @@ mod tests {
                 display_name: "Snusbot",
                 timestamp: Utc.timestamp_millis_opt(1489263601000).unwrap(),
                 id: "".into(),
-                tags: [
-                    ("display-name", "Snusbot"),
-                    ("badges", ""),
-                    ("badge-info", ""),
-                    ("emotes", ""),
-                    ("flags", ""),
-                    ("user-id", "62541963"),
-                    ("tmi-sent-ts", "1489263601000"),
-                    ("room-id", "22484632"),
-                    ("user-type", ""),
-                ]
-                .into_iter()
-                .map(|(k, v)| (k, Cow::Borrowed(v)))
-                .collect(),
+                tags: Some(
+                    [
+                        ("display-name", "Snusbot"),
+                        ("badges", ""),
+                        ("badge-info", ""),
+                        ("emotes", ""),
+                        ("flags", ""),
+                        ("user-id", "62541963"),
+                        ("tmi-sent-ts", "1489263601000"),
+                        ("room-id", "22484632"),
+                        ("user-type", ""),
+                    ]
+                    .into_iter()
+                    .map(|(k, v)| (k, Cow::Borrowed(v)))
+                    .collect(),
+                ),
+                line: None,
             },
-            raw: "@tmi-sent-ts=1489263601000;room-id=22484632;user-id=62541963;display-name=Snusbot;badges=;badge-info=;flags=;user-type=;emotes= :snusbot!snusbot@snusbot.tmi.twitch.tv PRIVMSG #forsen :prasoc won 10 points in roulette and now has 2838 points! forsenPls".to_owned(),
+            raw: Some("@tmi-sent-ts=1489263601000;room-id=22484632;user-id=62541963;display-name=Snusbot;badges=;badge-info=;flags=;user-type=;emotes= :snusbot!snusbot@snusbot.tmi.twitch.tv PRIVMSG #forsen :prasoc won 10 points in roulette and now has 2838 points! forsenPls".to_owned()),
             r#type: MessageType::PrivMsg,
+            bits: None,
+            source_channel_id: None,
             username: "snusbot",
             channel: "forsen",
+            channel_id: "22484632",
         };
 
-        let mut expected_tags = expected_message.basic.tags.iter().collect::<Vec<_>>();
+        let mut expected_tags = expected_message
+            .basic
+            .tags
+            .iter()
+            .flatten()
+            .collect::<Vec<_>>();
         expected_tags.sort_unstable();
 
-        let mut actual_tags = message.basic.tags.iter().collect::<Vec<_>>();
+        let mut actual_tags = message.basic.tags.iter().flatten().collect::<Vec<_>>();
         actual_tags.sort_unstable();
 
         assert_eq!(expected_tags, actual_tags);
         assert_eq!(expected_message, message);
     }
+
+    #[test]
+    fn parse_cheer_bits() {
+        let data = "@badges=;bits=100;color=;display-name=Cheerer;emotes=;id=1c0b5ae8-9c3a-4d36-a4d0-6b2a6dbb7f6a;mod=0;room-id=22484632;subscriber=0;tmi-sent-ts=1686947117960;turbo=0;user-id=12345;user-type= :cheerer!cheerer@cheerer.tmi.twitch.tv PRIVMSG #forsen :Cheer100 forsenE";
+
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "12345",
+            timestamp: 1686947117960,
+            raw: data,
+        };
+        let structured = StructuredMessage::from_unstructured(&unstructured).unwrap();
+
+        let message = FullMessage::from_structured(&structured).unwrap();
+        assert_eq!(Some(100), message.bits);
+    }
 }
@@ -1,8 +1,10 @@
 mod basic;
 mod full;
+mod parsed_tags;
 
 pub use basic::BasicMessage;
 pub use full::FullMessage;
+pub use parsed_tags::ParsedTagsMessage;
 
 use serde::Serialize;
 
@@ -10,4 +12,9 @@ use crate::db::schema::StructuredMessage;
 
 pub trait ResponseMessage<'a>: Sized + Send + Serialize + Unpin {
     fn from_structured(msg: &'a StructuredMessage<'a>) -> anyhow::Result<Self>;
+
+    fn basic_mut(&mut self) -> &mut BasicMessage<'a>;
+
+    /// Leaves out the raw IRC line, for message types which include it
+    fn omit_raw(&mut self) {}
 }
@@ -0,0 +1,156 @@
+use super::{BasicMessage, FullMessage, ResponseMessage};
+use crate::db::schema::{MessageFlags, MessageType, StructuredMessage};
+use schemars::JsonSchema;
+use serde::Serialize;
+
+/// Full message with the `color`, `badges`, `badge-info`, `first-msg` and `emote-only` tags of chat messages parsed into structured fields
+#[derive(Serialize, JsonSchema, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ParsedTagsMessage<'a> {
+    #[serde(flatten)]
+    pub full: FullMessage<'a>,
+    /// Name color of the user in the `#RRGGBB` format
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub badges: Option<Vec<Badge<'a>>>,
+    /// Exact amount of months the user has been subscribed, which can differ from the subscriber badge version
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscriber_months: Option<u32>,
+    /// Whether this is the first message the user sent in the channel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_msg: Option<bool>,
+    /// Whether the message consists only of emotes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub emote_only: Option<bool>,
+}
+
+#[derive(Serialize, JsonSchema, Debug, PartialEq)]
+pub struct Badge<'a> {
+    pub set: &'a str,
+    pub version: &'a str,
+}
+
+impl<'a> ResponseMessage<'a> for ParsedTagsMessage<'a> {
+    fn from_structured(msg: &'a StructuredMessage<'a>) -> anyhow::Result<Self> {
+        let full = FullMessage::from_structured(msg)?;
+
+        if msg.message_type != MessageType::PrivMsg {
+            return Ok(Self {
+                full,
+                color: None,
+                badges: None,
+                subscriber_months: None,
+                first_msg: None,
+                emote_only: None,
+            });
+        }
+
+        let badges = msg
+            .badges
+            .iter()
+            .filter_map(|badge| {
+                let (set, version) = badge.split_once('/')?;
+                Some(Badge { set, version })
+            })
+            .collect();
+
+        Ok(Self {
+            full,
+            color: msg.color.map(|color| format!("#{color:06X}")),
+            badges: Some(badges),
+            subscriber_months: msg.subscriber_months(),
+            first_msg: Some(msg.message_flags.contains(MessageFlags::FIRST_MSG)),
+            emote_only: Some(msg.message_flags.contains(MessageFlags::EMOTE_ONLY)),
+        })
+    }
+
+    fn basic_mut(&mut self) -> &mut BasicMessage<'a> {
+        &mut self.full.basic
+    }
+
+    fn omit_raw(&mut self) {
+        self.full.omit_raw();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Badge, ParsedTagsMessage};
+    use crate::{
+        db::schema::{StructuredMessage, UnstructuredMessage},
+        logs::schema::message::ResponseMessage,
+    };
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parse_color_and_badges() {
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "68136884",
+            timestamp: 1709251274940,
+            raw: "@returning-chatter=0;user-id=68136884;user-type=;badges=vip/1,subscriber/60;mod=0;display-name=Supibot;room-id=22484632;flags=;emotes=;first-msg=0;vip=1;tmi-sent-ts=1709251274940;id=272e342c-5864-4c59-b730-25908cdb7f57;subscriber=1;turbo=0;color=#1E90FF;badge-info=subscriber/65 :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :+join",
+        };
+        let structured = StructuredMessage::from_unstructured(&unstructured).unwrap();
+
+        let message = ParsedTagsMessage::from_structured(&structured).unwrap();
+        assert_eq!(Some("#1E90FF".to_owned()), message.color);
+        assert_eq!(
+            Some(vec![
+                Badge {
+                    set: "vip",
+                    version: "1"
+                },
+                Badge {
+                    set: "subscriber",
+                    version: "60"
+                },
+            ]),
+            message.badges
+        );
+        assert_eq!(Some(65), message.subscriber_months);
+        assert_eq!(Some(false), message.first_msg);
+        assert_eq!(Some(false), message.emote_only);
+        assert_eq!(
+            Some("vip/1,subscriber/60"),
+            message
+                .full
+                .basic
+                .tags
+                .as_ref()
+                .and_then(|tags| tags.get("badges"))
+                .map(|value| value.as_ref())
+        );
+    }
+
+    #[test]
+    fn parse_first_msg_and_emote_only() {
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "68136884",
+            timestamp: 1709251274940,
+            raw: "@badges=;color=;display-name=Supibot;emote-only=1;emotes=25:0-4;first-msg=1;id=272e342c-5864-4c59-b730-25908cdb7f57;mod=0;room-id=22484632;subscriber=0;tmi-sent-ts=1709251274940;turbo=0;user-id=68136884;user-type= :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :Kappa",
+        };
+        let structured = StructuredMessage::from_unstructured(&unstructured).unwrap();
+
+        let message = ParsedTagsMessage::from_structured(&structured).unwrap();
+        assert_eq!(Some(true), message.first_msg);
+        assert_eq!(Some(true), message.emote_only);
+        assert_eq!(None, message.color);
+        assert_eq!(None, message.subscriber_months);
+    }
+
+    #[test]
+    fn parse_founder_months() {
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "68136884",
+            timestamp: 1709251274940,
+            raw: "@badge-info=founder/47;badges=founder/0;color=;display-name=Supibot;emotes=;first-msg=0;id=272e342c-5864-4c59-b730-25908cdb7f57;mod=0;room-id=22484632;subscriber=1;tmi-sent-ts=1709251274940;turbo=0;user-id=68136884;user-type= :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :hello",
+        };
+        let structured = StructuredMessage::from_unstructured(&unstructured).unwrap();
+
+        let message = ParsedTagsMessage::from_structured(&structured).unwrap();
+        assert_eq!(Some(47), message.subscriber_months);
+    }
+}
@@ -6,6 +6,7 @@ pub use buffer_response::FlushBufferResponse;
 use cursor::CursorStream;
 use multi_query::MultiQueryStream;
 
+use super::anonymize::Anonymizer;
 use crate::{db::schema::StructuredMessage, error::Error, Result};
 use clickhouse::query::RowCursor;
 use futures::{Stream, StreamExt};
@@ -13,14 +14,19 @@ use std::{
     ops::DerefMut,
     pin::Pin,
     task::{Context, Poll},
+    vec,
 };
 
 pub enum LogsStream {
     Cursor(CursorStream),
     MultiQuery(MultiQueryStream),
-    Provided(Option<Vec<StructuredMessage<'static>>>),
+    Provided(vec::IntoIter<StructuredMessage<'static>>),
+    Anonymized(Box<LogsStream>, Anonymizer),
 }
 
+/// Messages given at once are yielded in chunks of this size, so that responses can stop between them
+const PROVIDED_CHUNK_SIZE: usize = 3000;
+
 impl LogsStream {
     pub async fn new_cursor(
         cursor: RowCursor<StructuredMessage<'static>>,
@@ -31,11 +37,16 @@ impl LogsStream {
         ))
     }
 
+    /// A stream without any messages, for ranges which exist but contain no logs
+    pub fn empty() -> Self {
+        Self::Provided(Vec::new().into_iter())
+    }
+
     pub fn new_provided(messages: Vec<StructuredMessage<'static>>) -> Result<Self> {
         if messages.is_empty() {
             Err(Error::NotFound)
         } else {
-            Ok(Self::Provided(Some(messages)))
+            Ok(Self::Provided(messages.into_iter()))
         }
     }
 
@@ -52,6 +63,11 @@ impl LogsStream {
             buffer_response,
         )))
     }
+
+    /// Replaces the users of the messages with pseudonyms
+    pub fn anonymized(self) -> Self {
+        Self::Anonymized(Box::new(self), Anonymizer::new())
+    }
 }
 
 impl Stream for LogsStream {
@@ -61,7 +77,18 @@ impl Stream for LogsStream {
         match self.deref_mut() {
             LogsStream::Cursor(stream) => stream.poll_next_unpin(cx),
             LogsStream::MultiQuery(stream) => stream.poll_next_unpin(cx),
-            LogsStream::Provided(values) => Poll::Ready(values.take().map(Ok)),
+            LogsStream::Provided(values) => {
+                let chunk: Vec<_> = values.by_ref().take(PROVIDED_CHUNK_SIZE).collect();
+                Poll::Ready((!chunk.is_empty()).then_some(Ok(chunk)))
+            }
+            LogsStream::Anonymized(stream, anonymizer) => {
+                stream.poll_next_unpin(cx).map_ok(|mut chunk| {
+                    for msg in &mut chunk {
+                        anonymizer.anonymize(msg);
+                    }
+                    chunk
+                })
+            }
         }
     }
 }
@@ -1,5 +1,8 @@
 use crate::{
-    db::{schema::StructuredMessage, writer::FlushBuffer},
+    db::{
+        schema::{MessageFlags, StructuredMessage},
+        writer::FlushBuffer,
+    },
     logs::schema::LogRangeParams,
 };
 
@@ -20,15 +23,15 @@ impl FlushBufferResponse {
     pub async fn new(
         buffer: &FlushBuffer,
         channel_id: &str,
-        user_id: Option<&str>,
+        user_ids: Option<&[&str]>,
         params: LogRangeParams,
     ) -> Self {
         let timestamp_range =
             (params.from.timestamp_millis() as u64)..(params.to.timestamp_millis() as u64);
 
-        let mut messages = if let Some(user_id) = user_id {
+        let mut messages = if let Some(user_ids) = user_ids {
             buffer
-                .messages_by_channel_and_user(timestamp_range, channel_id, user_id)
+                .messages_by_channel_and_users(timestamp_range, channel_id, user_ids)
                 .await
         } else {
             buffer
@@ -36,6 +39,22 @@ impl FlushBufferResponse {
                 .await
         };
 
+        if params.logs_params.exclude_shared {
+            messages.retain(|msg| !msg.is_shared());
+        }
+        if params.logs_params.first_msg_only {
+            messages.retain(|msg| msg.message_flags.contains(MessageFlags::FIRST_MSG));
+        }
+        if let Some(min_sub_months) = params.logs_params.min_sub_months {
+            messages.retain(|msg| {
+                msg.subscriber_months()
+                    .is_some_and(|months| months >= min_sub_months)
+            });
+        }
+        if let Some(message_type) = params.logs_params.message_type {
+            messages.retain(|msg| msg.message_type == message_type);
+        }
+
         if params.logs_params.reverse {
             messages.reverse();
         }
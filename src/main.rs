@@ -12,13 +12,14 @@ pub type Result<T> = std::result::Result<T, error::Error>;
 pub type ShutdownRx = watch::Receiver<()>;
 
 use anyhow::{anyhow, Context};
-use app::App;
+use app::{webhooks::Webhooks, App};
 use args::{Args, Command};
+use chrono::NaiveDate;
 use clap::Parser;
 use config::Config;
-use db::{setup_db, writer::create_writer};
+use db::{materialize_index, read_secondary_indexes, setup_db, writer::create_writer};
 use futures::{future::try_join_all, stream::FuturesUnordered, StreamExt};
-use migrator::Migrator;
+use migrator::{Migrator, MigratorOptions};
 use mimalloc::MiMalloc;
 use std::{
     env,
@@ -38,7 +39,7 @@ use twitch_api::{
 };
 use twitch_irc::login::StaticLoginCredentials;
 
-use crate::app::cache::UsersCache;
+use crate::app::{cache::UsersCache, helix_limiter::HelixLimiter};
 
 const SHUTDOWN_TIMEOUT_SECONDS: u64 = 8;
 
@@ -47,16 +48,7 @@ static GLOBAL: MiMalloc = MiMalloc;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let use_ansi = env::var("RUST_LOG_ANSI")
-        .ok()
-        .and_then(|ansi| ansi.parse().ok())
-        .unwrap_or(true);
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
-        )
-        .with_ansi(use_ansi)
-        .init();
+    init_tracing();
 
     let config = Config::load()?;
     let mut db = clickhouse::Client::default()
@@ -74,7 +66,7 @@ async fn main() -> anyhow::Result<()> {
 
     let args = Args::parse();
 
-    setup_db(&db, &config.clickhouse_db)
+    setup_db(&db, &config.clickhouse_db, config.message_id_index)
         .await
         .context("Could not run DB migrations")?;
 
@@ -84,7 +76,83 @@ async fn main() -> anyhow::Result<()> {
             source_dir,
             channel_id,
             jobs,
-        }) => migrate(db, source_dir, channel_id, jobs).await,
+            dry_run,
+            date,
+            privmsg_only,
+            read_buffer_size,
+            invalid_utf8,
+            report_duplicate_ids,
+            timestamp_policy,
+        }) => {
+            let options = MigratorOptions {
+                privmsg_only,
+                read_buffer_size,
+                invalid_utf8,
+                report_duplicate_ids,
+                timestamp_policy,
+            };
+            match date {
+                Some(date) => {
+                    reimport_day(db, source_dir, channel_id, options, date, dry_run).await
+                }
+                None => migrate(db, source_dir, channel_id, options, jobs, dry_run).await,
+            }
+        }
+        Some(Command::Verify {
+            source_dir,
+            channel_id,
+            privmsg_only,
+            read_buffer_size,
+            invalid_utf8,
+            timestamp_policy,
+        }) => {
+            let options = MigratorOptions {
+                privmsg_only,
+                read_buffer_size,
+                invalid_utf8,
+                report_duplicate_ids: false,
+                timestamp_policy,
+            };
+            verify(db, source_dir, channel_id, options).await
+        }
+        Some(Command::RebuildIndexes { index }) => {
+            rebuild_indexes(db, &config.clickhouse_db, index).await
+        }
+    }
+}
+
+fn init_tracing() {
+    let use_ansi = env::var("RUST_LOG_ANSI")
+        .ok()
+        .and_then(|ansi| ansi.parse().ok())
+        .unwrap_or(true);
+    let json_logs = env::var("RUST_LOG_FORMAT").is_ok_and(|format| format == "json");
+    let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+    #[cfg(not(feature = "tokio-console"))]
+    {
+        let subscriber = tracing_subscriber::fmt().with_env_filter(env_filter);
+        if json_logs {
+            subscriber.json().init();
+        } else {
+            subscriber.with_ansi(use_ansi).init();
+        }
+    }
+
+    // The console layer needs the runtime's own trace events, so the env filter only applies to the log output
+    #[cfg(feature = "tokio-console")]
+    {
+        use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, Layer};
+
+        let fmt_layer = if json_logs {
+            fmt::layer().json().boxed()
+        } else {
+            fmt::layer().with_ansi(use_ansi).boxed()
+        };
+        tracing_subscriber::registry()
+            .with(console_subscriber::spawn())
+            .with(fmt_layer.with_filter(env_filter))
+            .init();
     }
 }
 
@@ -101,14 +169,31 @@ async fn run(config: Config, db: clickhouse::Client) -> anyhow::Result<()> {
     )
     .await?;
 
+    if let Some(hours) = config.compaction_interval_hours {
+        tokio::spawn(db::compaction::run(
+            db.clone(),
+            config.clickhouse_db.clone(),
+            Duration::from_secs(hours * 3600),
+            shutdown_rx.clone(),
+        ));
+    }
+
+    let config = Arc::new(config);
     let app = App {
         helix_client,
+        helix_limiter: HelixLimiter::new(
+            config.helix_concurrency,
+            config.helix_requests_per_minute,
+        ),
         token: Arc::new(token),
         users: UsersCache::default(),
-        config: Arc::new(config),
+        webhooks: Webhooks::start(config.clone()),
+        config,
         db: Arc::new(db),
         optout_codes: Arc::default(),
         flush_buffer,
+        started_at: Instant::now(),
+        shutdown_rx: shutdown_rx.clone(),
     };
 
     let (bot_tx, bot_rx) = mpsc::channel(1);
@@ -158,10 +243,77 @@ async fn migrate(
     db: clickhouse::Client,
     source_logs_path: String,
     channel_ids: Vec<String>,
+    options: MigratorOptions,
     jobs: usize,
+    dry_run: bool,
 ) -> anyhow::Result<()> {
-    let migrator = Migrator::new(db, source_logs_path, channel_ids).await?;
-    migrator.run(jobs).await
+    let migrator = Migrator::new(db, source_logs_path, channel_ids, options).await?;
+    migrator.run(jobs, dry_run).await
+}
+
+async fn reimport_day(
+    db: clickhouse::Client,
+    source_logs_path: String,
+    channel_ids: Vec<String>,
+    options: MigratorOptions,
+    date: NaiveDate,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let migrator = Migrator::new(db, source_logs_path, channel_ids, options).await?;
+    match migrator.reimport_day(date, dry_run).await? {
+        Some(lines) => {
+            info!("Reimported {lines} lines from {date}");
+            Ok(())
+        }
+        None => Err(anyhow!("No logs found for {date}")),
+    }
+}
+
+async fn verify(
+    db: clickhouse::Client,
+    source_logs_path: String,
+    channel_ids: Vec<String>,
+    options: MigratorOptions,
+) -> anyhow::Result<()> {
+    let migrator = Migrator::new(db, source_logs_path, channel_ids, options).await?;
+    migrator.verify().await
+}
+
+async fn rebuild_indexes(
+    db: clickhouse::Client,
+    db_name: &str,
+    index_names: Vec<String>,
+) -> anyhow::Result<()> {
+    let existing_indexes = read_secondary_indexes(&db, db_name).await?;
+
+    let index_names = if index_names.is_empty() {
+        existing_indexes
+    } else {
+        if let Some(unknown) = index_names
+            .iter()
+            .find(|name| !existing_indexes.contains(name))
+        {
+            return Err(anyhow!(
+                "Unknown index {unknown}, available indexes: {existing_indexes:?}"
+            ));
+        }
+        index_names
+    };
+
+    if index_names.is_empty() {
+        info!("There are no secondary indexes to rebuild");
+    }
+
+    for index_name in index_names {
+        info!("Rebuilding index {index_name}");
+        let started_at = Instant::now();
+        materialize_index(&db, &index_name)
+            .await
+            .with_context(|| format!("Could not rebuild index {index_name}"))?;
+        info!("Rebuilt index {index_name} in {:?}", started_at.elapsed());
+    }
+
+    Ok(())
 }
 
 async fn generate_token(config: &Config) -> anyhow::Result<AppAccessToken> {
@@ -1,20 +1,29 @@
 mod reader;
 
-use self::reader::{LogsReader, COMPRESSED_CHANNEL_FILE, UNCOMPRESSED_CHANNEL_FILE};
+use self::reader::{
+    LogsReader, COMPRESSED_CHANNEL_FILE, UNCOMPRESSED_CHANNEL_FILE, ZSTD_COMPRESSED_CHANNEL_FILE,
+};
 use crate::{
-    db::schema::{StructuredMessage, UnstructuredMessage, MESSAGES_STRUCTURED_TABLE},
-    logs::extract::{extract_raw_timestamp, extract_user_id},
+    db::{
+        copy_staged_messages, create_staging_table, delete_channel_range, drop_staging_table,
+        schema::{StructuredMessage, UnstructuredMessage, MESSAGES_STRUCTURED_TABLE},
+    },
+    logs::extract::{
+        extract_raw_timestamp, extract_user_id, is_raw_privmsg, resolve_timestamp, TimestampPolicy,
+    },
     migrator::reader::ChannelLogDateMap,
 };
 use anyhow::{anyhow, Context};
-use chrono::{DateTime, Datelike, TimeZone, Utc};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, TimeZone, Utc};
+use clap::ValueEnum;
 use clickhouse::inserter::Inserter;
 use flate2::bufread::GzDecoder;
 use indexmap::IndexMap;
 use std::{
+    collections::HashSet,
     convert::TryInto,
     fs::File,
-    io::{BufRead, BufReader},
+    io::{self, BufRead, BufReader},
     path::{Path, PathBuf},
     sync::{
         atomic::{AtomicU64, Ordering},
@@ -22,17 +31,47 @@ use std::{
     },
     time::{Duration, Instant},
 };
-use tmi::Command;
+use tmi::{Command, Tag};
 use tokio::sync::Semaphore;
 use tracing::{debug, error, info, warn};
+use uuid::Uuid;
 
 const INSERT_BATCH_SIZE: u64 = 10_000_000;
+/// How many of the duplicate message ids of a day are listed in the report
+const MAX_DUPLICATE_ID_SAMPLES: usize = 5;
+/// Same as the default `BufReader` capacity
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 8 * 1024;
 
 #[derive(Clone)]
 pub struct Migrator {
     db: clickhouse::Client,
     source_logs_path: String,
     channel_ids: Arc<Vec<String>>,
+    options: MigratorOptions,
+}
+
+#[derive(Clone, Copy)]
+pub struct MigratorOptions {
+    /// Skip all non-PRIVMSG lines
+    pub privmsg_only: bool,
+    pub read_buffer_size: usize,
+    pub invalid_utf8: InvalidUtf8Handling,
+    /// Report chat messages whose id appears more than once in a day's logs
+    pub report_duplicate_ids: bool,
+    /// What to do with messages whose timestamp is far from the day of their log file
+    pub timestamp_policy: TimestampPolicy,
+}
+
+/// What to do with lines which are not valid UTF-8
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum InvalidUtf8Handling {
+    /// Replace invalid bytes with the replacement character
+    #[default]
+    Replace,
+    /// Skip the line
+    Skip,
+    /// Abort migrating the day
+    Fail,
 }
 
 impl Migrator {
@@ -40,15 +79,18 @@ impl Migrator {
         db: clickhouse::Client,
         source_logs_path: String,
         channel_ids: Vec<String>,
+        options: MigratorOptions,
     ) -> anyhow::Result<Migrator> {
         Ok(Self {
             db,
             source_logs_path,
             channel_ids: Arc::new(channel_ids),
+            options,
         })
     }
 
-    pub async fn run(self, parallel_count: usize) -> anyhow::Result<()> {
+    /// Migrates the logs. With `dry_run`, lines are only parsed and counted without writing anything.
+    pub async fn run(self, parallel_count: usize, dry_run: bool) -> anyhow::Result<()> {
         let source_logs = LogsReader::new(&self.source_logs_path)?;
 
         let started_at = Instant::now();
@@ -80,8 +122,11 @@ impl Migrator {
         let channel_count = channel_logs.len();
         let total_mb = total_bytes / 1024 / 1024;
 
+        if dry_run {
+            info!("Dry run, nothing will be written to the database");
+        }
         info!("Migrating {channel_count} channels with {total_mb} MiB of logs");
-        info!("NOTE: the estimation numbers will be wrong if you use gzip compressed logs");
+        info!("NOTE: the estimation numbers will be wrong if you use compressed logs");
 
         let mut i = 1;
 
@@ -102,29 +147,48 @@ impl Migrator {
                     let migrated_percentage = migrated_percentage.clone();
 
                     let handle = tokio::spawn(async move {
-                        let mut inserter = migrator
-                            .db
-                            .inserter(MESSAGES_STRUCTURED_TABLE)?
-                            .with_timeouts(
-                                Some(Duration::from_secs(30)),
-                                Some(Duration::from_secs(180)),
-                            )
-                            .with_max_entries(INSERT_BATCH_SIZE)
-                            .with_period(Some(Duration::from_secs(15)));
+                        let mut inserter = if dry_run {
+                            None
+                        } else {
+                            let inserter = migrator
+                                .db
+                                .inserter(MESSAGES_STRUCTURED_TABLE)?
+                                .with_timeouts(
+                                    Some(Duration::from_secs(30)),
+                                    Some(Duration::from_secs(180)),
+                                )
+                                .with_max_entries(INSERT_BATCH_SIZE)
+                                .with_period(Some(Duration::from_secs(15)));
+                            Some(inserter)
+                        };
 
                         info!("Migrating channel {channel_id} date {year}-{month}");
 
+                        let mut month_stats = LineStats::default();
+
                         for day in days {
                             let date = Utc
                                 .with_ymd_and_hms(year.try_into().unwrap(), month, day, 0, 0, 0)
                                 .unwrap();
-                            let day_bytes = migrator
-                                .migrate_day(&root_path, &channel_id, date, &mut inserter)
+                            let (day_bytes, day_stats) = migrator
+                                .migrate_day(&root_path, &channel_id, date, inserter.as_mut())
                                 .await
                                 .with_context(|| {
                                     format!("Could not migrate channel {channel_id} date {date}")
                                 })?;
 
+                            if dry_run {
+                                info!(
+                                    %channel_id,
+                                    date = %date.date_naive(),
+                                    converted = day_stats.converted,
+                                    skipped = day_stats.skipped,
+                                    failed = day_stats.failed,
+                                    "Dry run of day finished",
+                                );
+                            }
+                            month_stats.merge(&day_stats);
+
                             total_read_bytes.fetch_add(day_bytes as u64, Ordering::SeqCst);
                             let processed_bytes = total_read_bytes.load(Ordering::SeqCst);
 
@@ -141,17 +205,19 @@ impl Migrator {
                             }
                         }
 
-                        debug!("Flushing messages");
-                        let stats = inserter.end().await.context("Could not flush messages")?;
-                        if stats.entries > 0 {
-                            info!(
-                                "DB: {} entries ({} transactions) have been inserted",
-                                stats.entries, stats.transactions,
-                            );
+                        if let Some(inserter) = inserter {
+                            debug!("Flushing messages");
+                            let stats = inserter.end().await.context("Could not flush messages")?;
+                            if stats.entries > 0 {
+                                info!(
+                                    "DB: {} entries ({} transactions) have been inserted",
+                                    stats.entries, stats.transactions,
+                                );
+                            }
                         }
 
                         drop(permit);
-                        Result::<_, anyhow::Error>::Ok(())
+                        Result::<_, anyhow::Error>::Ok(month_stats)
                     });
                     handles.push(handle);
                 }
@@ -159,12 +225,34 @@ impl Migrator {
             i += 1;
         }
 
+        let mut total_stats = LineStats::default();
         for handle in handles {
-            handle.await.unwrap()?;
+            total_stats.merge(&handle.await.unwrap()?);
         }
 
         let elapsed = started_at.elapsed();
         info!("Migration finished in {elapsed:?}");
+        info!(
+            "Read {} lines: {} converted ({} without a user), {} skipped as unparseable, {} failed to convert, {} filtered out",
+            total_stats.read,
+            total_stats.converted,
+            total_stats.without_user,
+            total_stats.skipped,
+            total_stats.failed,
+            total_stats.read - total_stats.converted - total_stats.skipped - total_stats.failed,
+        );
+        if self.options.report_duplicate_ids {
+            info!(
+                "{} chat messages have a duplicate id",
+                total_stats.duplicate_ids
+            );
+        }
+        if total_stats.unreadable_files > 0 {
+            warn!(
+                "{} log files could not be opened and were skipped",
+                total_stats.unreadable_files
+            );
+        }
 
         if let Some(throughput) =
             (total_read_bytes.load(Ordering::SeqCst) / 1024 / 1024).checked_div(elapsed.as_secs())
@@ -175,75 +263,543 @@ impl Migrator {
         Ok(())
     }
 
-    // Returns the number of read bytes
-    async fn migrate_day<'a>(
+    /// Replaces the stored logs of a single past day with the source logs, leaving the rest of the month untouched.
+    /// The source logs are written to a staging table first, so the stored logs are only deleted once all of them were read.
+    /// Returns the amount of converted lines, or `None` if no channel has a log file for the day.
+    /// With `dry_run`, nothing is deleted or written
+    pub async fn reimport_day(self, date: NaiveDate, dry_run: bool) -> anyhow::Result<Option<u64>> {
+        // The bot is still logging the current day, replacing it would lose those messages
+        if date >= Utc::now().date_naive() {
+            return Err(anyhow!("Only past days can be reimported, not {date}"));
+        }
+
+        let source_logs = LogsReader::new(&self.source_logs_path)?;
+        let channel_ids = if self.channel_ids.is_empty() {
+            source_logs.get_stored_channels().await?
+        } else {
+            self.channel_ids.to_vec()
+        };
+
+        let from = date.and_time(NaiveTime::default()).and_utc();
+        let to = from + chrono::Duration::days(1);
+
+        let mut found_any = false;
+        let mut total_stats = LineStats::default();
+
+        for channel_id in &channel_ids {
+            let Some(reader) = open_day_reader(
+                &source_logs.root_path,
+                channel_id,
+                from,
+                self.options.read_buffer_size,
+            )?
+            else {
+                debug!("No log file found for channel {channel_id} date {date}, skipping");
+                continue;
+            };
+            found_any = true;
+
+            let day_stats = if dry_run {
+                let (_, day_stats) = self
+                    .migrate_reader(reader, from, channel_id, None)
+                    .await
+                    .with_context(|| {
+                        format!("Could not reimport channel {channel_id} date {date}")
+                    })?;
+                day_stats
+            } else {
+                let staging_table = format!(
+                    "{MESSAGES_STRUCTURED_TABLE}_reimport_{}",
+                    Uuid::new_v4().simple()
+                );
+                create_staging_table(&self.db, &staging_table)
+                    .await
+                    .context("Could not create staging table")?;
+
+                let staged = self
+                    .stage_day(reader, from, channel_id, &staging_table)
+                    .await;
+                let day_stats = match staged {
+                    Ok(day_stats) => day_stats,
+                    Err(err) => {
+                        if let Err(drop_err) = drop_staging_table(&self.db, &staging_table).await {
+                            error!("Could not drop staging table {staging_table}: {drop_err}");
+                        }
+                        return Err(err.context(format!(
+                            "Could not reimport channel {channel_id} date {date}, the stored logs were not changed"
+                        )));
+                    }
+                };
+
+                delete_channel_range(&self.db, channel_id, from, to)
+                    .await
+                    .with_context(|| {
+                        format!("Could not delete stored logs of channel {channel_id} date {date}")
+                    })?;
+                // The reimported messages stay in the staging table if copying them fails, so that they can be restored manually
+                copy_staged_messages(&self.db, &staging_table)
+                    .await
+                    .with_context(|| {
+                        format!("Could not copy the reimported logs of channel {channel_id} date {date} from {staging_table}")
+                    })?;
+                drop_staging_table(&self.db, &staging_table)
+                    .await
+                    .with_context(|| format!("Could not drop staging table {staging_table}"))?;
+
+                day_stats
+            };
+
+            info!(
+                %channel_id,
+                %date,
+                converted = day_stats.converted,
+                skipped = day_stats.skipped,
+                failed = day_stats.failed,
+                "Reimported day",
+            );
+            total_stats.merge(&day_stats);
+        }
+
+        Ok(found_any.then_some(total_stats.converted))
+    }
+
+    /// Writes all lines of the reader to the staging table
+    async fn stage_day<R: BufRead>(
         &self,
-        root_path: &Path,
-        channel_id: &'a str,
+        reader: R,
         date: DateTime<Utc>,
-        inserter: &mut Inserter<StructuredMessage<'a>>,
-    ) -> anyhow::Result<usize> {
-        let day_path = get_day_path(root_path, channel_id, date);
+        channel_id: &str,
+        staging_table: &str,
+    ) -> anyhow::Result<LineStats> {
+        let mut inserter = self.db.inserter(staging_table)?;
+        let (_, day_stats) = self
+            .migrate_reader(reader, date, channel_id, Some(&mut inserter))
+            .await?;
+        inserter.end().await.context("Could not flush messages")?;
+        Ok(day_stats)
+    }
 
-        let compressed_file_path = day_path.join(COMPRESSED_CHANNEL_FILE);
-        let uncompressed_file_path = day_path.join(UNCOMPRESSED_CHANNEL_FILE);
+    /// Compares the amount of messages with a user in the source logs and in the database for every month
+    pub async fn verify(self) -> anyhow::Result<()> {
+        let source_logs = LogsReader::new(&self.source_logs_path)?;
+        let channels = source_logs.get_stored_channels().await?;
 
-        if compressed_file_path.exists() {
-            debug!("Reading compressed log {compressed_file_path:?}");
-            let file_reader = BufReader::new(File::open(&compressed_file_path)?);
-            let gz = BufReader::new(GzDecoder::new(file_reader));
+        let mut checked_months = 0;
+        let mut mismatched_months = 0;
 
-            self.migrate_reader(gz, date, channel_id, inserter).await
-        } else if uncompressed_file_path.exists() {
-            debug!("Reading uncompressed log {uncompressed_file_path:?}");
-            let file_reader = BufReader::new(File::open(&uncompressed_file_path)?);
+        for channel_id in channels
+            .into_iter()
+            .filter(|channel| self.channel_ids.is_empty() || self.channel_ids.contains(channel))
+        {
+            let (available_logs, _) = source_logs.get_available_channel_logs(&channel_id)?;
 
-            self.migrate_reader(file_reader, date, channel_id, inserter)
-                .await
-        } else {
-            Err(anyhow!("File does not exist"))
+            for (year, months) in available_logs {
+                for (month, days) in months {
+                    let mut source_count = 0;
+
+                    for day in days {
+                        let date = Utc
+                            .with_ymd_and_hms(year.try_into().unwrap(), month, day, 0, 0, 0)
+                            .unwrap();
+                        let Some(reader) = open_day_reader(
+                            &source_logs.root_path,
+                            &channel_id,
+                            date,
+                            self.options.read_buffer_size,
+                        )?
+                        else {
+                            warn!(%channel_id, %date, "No log file found, skipping");
+                            continue;
+                        };
+                        source_count += count_user_lines(reader, &channel_id, date, self.options)
+                            .with_context(|| {
+                            format!("Could not verify channel {channel_id} date {date}")
+                        })?;
+                    }
+
+                    let db_count = self
+                        .db
+                        .query("SELECT count() FROM message_structured WHERE channel_id = ? AND user_id != '' AND toYYYYMM(timestamp) = ?")
+                        .bind(&channel_id)
+                        .bind(year * 100 + month)
+                        .fetch_one::<u64>()
+                        .await?;
+
+                    checked_months += 1;
+                    if source_count == db_count {
+                        info!(%channel_id, year, month, db_count, "Month verified");
+                    } else {
+                        mismatched_months += 1;
+                        warn!(
+                            %channel_id,
+                            year,
+                            month,
+                            source_count,
+                            db_count,
+                            "Messages in source logs and database don't match"
+                        );
+                    }
+                }
+            }
         }
+
+        info!("Verified {checked_months} months, {mismatched_months} mismatched");
+
+        Ok(())
+    }
+
+    // Returns the number of read bytes
+    async fn migrate_day<'a>(
+        &self,
+        root_path: &Path,
+        channel_id: &'a str,
+        date: DateTime<Utc>,
+        inserter: Option<&mut Inserter<StructuredMessage<'a>>>,
+    ) -> anyhow::Result<(usize, LineStats)> {
+        let reader =
+            match open_day_reader(root_path, channel_id, date, self.options.read_buffer_size) {
+                Ok(Some(reader)) => reader,
+                Ok(None) => {
+                    warn!(%channel_id, %date, "No log file found, skipping");
+                    return Ok((0, LineStats::default()));
+                }
+                // A single unreadable file should not abort migrating the rest of the channel
+                Err(err) => {
+                    error!(%channel_id, %date, "Could not open log file, skipping: {err:#}");
+                    let stats = LineStats {
+                        unreadable_files: 1,
+                        ..Default::default()
+                    };
+                    return Ok((0, stats));
+                }
+            };
+        self.migrate_reader(reader, date, channel_id, inserter)
+            .await
     }
 
     async fn migrate_reader<'a, R: BufRead>(
         &self,
-        reader: R,
+        mut reader: R,
         datetime: DateTime<Utc>,
         channel_id: &'a str,
-        inserter: &mut Inserter<StructuredMessage<'a>>,
-    ) -> anyhow::Result<usize> {
+        mut inserter: Option<&mut Inserter<StructuredMessage<'a>>>,
+    ) -> anyhow::Result<(usize, LineStats)> {
         let mut read_bytes = 0;
+        let mut day_stats = LineStats::default();
+        let mut raw_line = Vec::new();
+        let mut line = String::new();
+        let mut i = 0;
+        let mut duplicate_ids = self
+            .options
+            .report_duplicate_ids
+            .then(DuplicateIds::default);
+
+        while let Some(read_line) = read_next_line(
+            &mut reader,
+            &mut raw_line,
+            &mut line,
+            self.options.invalid_utf8,
+        )
+        .with_context(|| format!("Could not read line {i} from input"))?
+        {
+            read_bytes += read_line.bytes;
+            day_stats.read += 1;
 
-        for (i, line) in reader.lines().enumerate() {
-            let line = line.with_context(|| format!("Could not read line {i} from input"))?;
-            read_bytes += line.len() + 1; // Add 1 byte for newline symbol
-            write_line(channel_id, line, inserter, datetime)
+            if read_line.skipped {
+                day_stats.add(LineStatus::Skipped);
+                i += 1;
+                continue;
+            }
+
+            if let Some(duplicate_ids) = &mut duplicate_ids {
+                duplicate_ids.check_line(&line);
+            }
+
+            if !self.options.privmsg_only || is_raw_privmsg(&line) {
+                let status = write_line(
+                    channel_id,
+                    &line,
+                    inserter.as_deref_mut(),
+                    datetime,
+                    self.options.timestamp_policy,
+                )
                 .await
                 .with_context(|| format!("Could not write line {i} to inserter"))?;
+                day_stats.add(status);
+            }
+            i += 1;
         }
 
-        let stats = inserter.commit().await?;
-        if stats.entries > 0 {
-            info!(
-                "DB: {} entries ({} transactions) have been inserted",
-                stats.entries, stats.transactions,
-            );
+        if let Some(duplicate_ids) = duplicate_ids {
+            if duplicate_ids.count > 0 {
+                warn!(
+                    %channel_id,
+                    date = %datetime.date_naive(),
+                    duplicates = duplicate_ids.count,
+                    sample_ids = ?duplicate_ids.samples,
+                    "Found duplicate message ids",
+                );
+            }
+            day_stats.duplicate_ids = duplicate_ids.count;
+        }
+
+        if let Some(inserter) = inserter {
+            let stats = inserter.commit().await?;
+            if stats.entries > 0 {
+                info!(
+                    "DB: {} entries ({} transactions) have been inserted",
+                    stats.entries, stats.transactions,
+                );
+            }
+        }
+
+        Ok((read_bytes, day_stats))
+    }
+}
+
+enum LineStatus {
+    Converted,
+    /// Converted, but the message has no user (e.g. `ROOMSTATE`)
+    ConvertedWithoutUser,
+    /// The line is not a valid IRC message, or was dropped because of its timestamp
+    Skipped,
+    /// The line could not be converted into a message
+    Failed,
+}
+
+#[derive(Default)]
+struct LineStats {
+    read: u64,
+    converted: u64,
+    without_user: u64,
+    skipped: u64,
+    failed: u64,
+    /// Daily log files which could not be opened
+    unreadable_files: u64,
+    /// Chat messages with an id which was already seen on the same day
+    duplicate_ids: u64,
+}
+
+impl LineStats {
+    fn add(&mut self, status: LineStatus) {
+        match status {
+            LineStatus::Converted => self.converted += 1,
+            LineStatus::ConvertedWithoutUser => {
+                self.converted += 1;
+                self.without_user += 1;
+            }
+            LineStatus::Skipped => self.skipped += 1,
+            LineStatus::Failed => self.failed += 1,
+        }
+    }
+
+    fn merge(&mut self, other: &LineStats) {
+        self.read += other.read;
+        self.converted += other.converted;
+        self.without_user += other.without_user;
+        self.skipped += other.skipped;
+        self.failed += other.failed;
+        self.unreadable_files += other.unreadable_files;
+        self.duplicate_ids += other.duplicate_ids;
+    }
+}
+
+/// Message ids of the chat messages seen in a day's logs, to find messages which were logged more than once
+#[derive(Default)]
+struct DuplicateIds {
+    seen: HashSet<String>,
+    count: u64,
+    samples: Vec<String>,
+}
+
+impl DuplicateIds {
+    fn check_line(&mut self, line: &str) {
+        if !is_raw_privmsg(line) {
+            return;
+        }
+        let Some(id) = tmi::IrcMessageRef::parse(line)
+            .and_then(|irc_message| irc_message.tag(Tag::Id).map(str::to_owned))
+        else {
+            return;
+        };
+
+        if self.seen.contains(&id) {
+            self.count += 1;
+            if self.samples.len() < MAX_DUPLICATE_ID_SAMPLES && !self.samples.contains(&id) {
+                self.samples.push(id);
+            }
+        } else {
+            self.seen.insert(id);
+        }
+    }
+}
+
+/// Returns `None` if there is no log file for the day.
+/// The uncompressed file is preferred, as it may still be written to if it exists next to a compressed one
+fn open_day_reader(
+    root_path: &Path,
+    channel_id: &str,
+    date: DateTime<Utc>,
+    buffer_size: usize,
+) -> anyhow::Result<Option<Box<dyn BufRead + Send>>> {
+    let day_path = get_day_path(root_path, channel_id, date);
+
+    let compressed_file_path = day_path.join(COMPRESSED_CHANNEL_FILE);
+    let zstd_compressed_file_path = day_path.join(ZSTD_COMPRESSED_CHANNEL_FILE);
+    let uncompressed_file_path = day_path.join(UNCOMPRESSED_CHANNEL_FILE);
+
+    if uncompressed_file_path.exists() {
+        debug!("Reading uncompressed log {uncompressed_file_path:?}");
+        Ok(Some(Box::new(BufReader::with_capacity(
+            buffer_size,
+            File::open(&uncompressed_file_path)?,
+        ))))
+    } else if compressed_file_path.exists() {
+        debug!("Reading compressed log {compressed_file_path:?}");
+        let file_reader = BufReader::with_capacity(buffer_size, File::open(&compressed_file_path)?);
+        Ok(Some(Box::new(BufReader::with_capacity(
+            buffer_size,
+            GzDecoder::new(file_reader),
+        ))))
+    } else if zstd_compressed_file_path.exists() {
+        debug!("Reading zstd compressed log {zstd_compressed_file_path:?}");
+        let file_reader =
+            BufReader::with_capacity(buffer_size, File::open(&zstd_compressed_file_path)?);
+        Ok(Some(Box::new(BufReader::with_capacity(
+            buffer_size,
+            zstd::Decoder::with_buffer(file_reader)?,
+        ))))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Counts the lines which would be migrated as messages with a user
+fn count_user_lines<R: BufRead>(
+    mut reader: R,
+    channel_id: &str,
+    datetime: DateTime<Utc>,
+    options: MigratorOptions,
+) -> anyhow::Result<u64> {
+    let mut raw_line = Vec::new();
+    let mut line = String::new();
+    let mut count = 0;
+    let mut i = 0;
+
+    while let Some(read_line) =
+        read_next_line(&mut reader, &mut raw_line, &mut line, options.invalid_utf8)
+            .with_context(|| format!("Could not read line {i} from input"))?
+    {
+        i += 1;
+
+        if read_line.skipped || (options.privmsg_only && !is_raw_privmsg(&line)) {
+            continue;
+        }
+
+        let Some(irc_message) = tmi::IrcMessageRef::parse(&line) else {
+            continue;
+        };
+        let user_id = extract_user_id(&irc_message).unwrap_or_default();
+        if user_id.is_empty() {
+            continue;
+        }
+
+        let Some(timestamp) = resolve_timestamp(
+            extract_raw_timestamp(&irc_message),
+            datetime.timestamp_millis() as u64,
+            options.timestamp_policy,
+        ) else {
+            continue;
+        };
+
+        let unstructured = UnstructuredMessage {
+            channel_id,
+            user_id,
+            timestamp,
+            raw: irc_message.raw(),
+        };
+        if StructuredMessage::from_unstructured(&unstructured).is_ok() {
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+/// A line consumed from the reader
+struct ReadLine {
+    /// Amount of bytes consumed, including the terminator if there was one
+    bytes: usize,
+    /// The line was dropped because of invalid UTF-8 and must not be processed
+    skipped: bool,
+}
+
+/// Reads the next line into `buf` without its line terminator, using `raw_buf` as scratch space.
+/// Lines which are not valid UTF-8 are handled according to `invalid_utf8`.
+/// Returns `None` if the end of input was reached.
+fn read_next_line<R: BufRead>(
+    reader: &mut R,
+    raw_buf: &mut Vec<u8>,
+    buf: &mut String,
+    invalid_utf8: InvalidUtf8Handling,
+) -> io::Result<Option<ReadLine>> {
+    raw_buf.clear();
+    buf.clear();
+
+    let read_bytes = reader.read_until(b'\n', raw_buf)?;
+    if read_bytes == 0 {
+        return Ok(None);
+    }
+
+    let mut line = raw_buf.as_slice();
+    if let Some(stripped) = line.strip_suffix(b"\n") {
+        line = stripped;
+        while let Some(stripped) = line.strip_suffix(b"\r") {
+            line = stripped;
         }
+    }
 
-        Ok(read_bytes)
+    let mut skipped = false;
+    match std::str::from_utf8(line) {
+        Ok(line) => buf.push_str(line),
+        Err(err) => match invalid_utf8 {
+            InvalidUtf8Handling::Replace => {
+                warn!("Replacing invalid UTF-8 in line: {err}");
+                buf.push_str(&String::from_utf8_lossy(line));
+            }
+            InvalidUtf8Handling::Skip => {
+                warn!("Skipping line with invalid UTF-8: {err}");
+                skipped = true;
+            }
+            InvalidUtf8Handling::Fail => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+        },
     }
+
+    Ok(Some(ReadLine {
+        bytes: read_bytes,
+        skipped,
+    }))
 }
 
+/// Converts the line and writes it to the inserter, if there is one
 async fn write_line<'a>(
     channel_id: &'a str,
-    raw: String,
-    inserter: &mut Inserter<StructuredMessage<'_>>,
+    raw: &str,
+    inserter: Option<&mut Inserter<StructuredMessage<'_>>>,
     datetime: DateTime<Utc>,
-) -> anyhow::Result<()> {
-    match tmi::IrcMessageRef::parse(&raw) {
+    timestamp_policy: TimestampPolicy,
+) -> anyhow::Result<LineStatus> {
+    match tmi::IrcMessageRef::parse(raw) {
         Some(irc_message) => {
-            let timestamp = extract_raw_timestamp(&irc_message)
-                .unwrap_or_else(|| datetime.timestamp_millis() as u64);
+            let Some(timestamp) = resolve_timestamp(
+                extract_raw_timestamp(&irc_message),
+                datetime.timestamp_millis() as u64,
+                timestamp_policy,
+            ) else {
+                return Ok(LineStatus::Skipped);
+            };
             let user_id = extract_user_id(&irc_message).unwrap_or_else(|| {
                 if irc_message.command() == Command::Privmsg {
                     warn!(
@@ -262,22 +818,29 @@ async fn write_line<'a>(
             };
             match StructuredMessage::from_unstructured(&unstructured) {
                 Ok(msg) => {
-                    // This is safe because despite the function signature,
-                    // `inserter.write` only uses the value for serialization at the time of the method call, and not later
-                    let msg: StructuredMessage<'static> = unsafe { std::mem::transmute(msg) };
-                    inserter.write(&msg).await?;
+                    if let Some(inserter) = inserter {
+                        // This is safe because despite the function signature,
+                        // `inserter.write` only uses the value for serialization at the time of the method call, and not later
+                        let msg: StructuredMessage<'static> = unsafe { std::mem::transmute(msg) };
+                        inserter.write(&msg).await?;
+                    }
+                    if user_id.is_empty() {
+                        Ok(LineStatus::ConvertedWithoutUser)
+                    } else {
+                        Ok(LineStatus::Converted)
+                    }
                 }
                 Err(err) => {
                     error!("Could not convert message {unstructured:?}: {err}");
+                    Ok(LineStatus::Failed)
                 }
             }
         }
         None => {
             warn!("Could not parse message `{raw}`");
+            Ok(LineStatus::Skipped)
         }
     }
-
-    Ok(())
 }
 
 fn get_day_path(root_path: &Path, channel_id: &str, date: DateTime<Utc>) -> PathBuf {
@@ -287,3 +850,132 @@ fn get_day_path(root_path: &Path, channel_id: &str, date: DateTime<Utc>) -> Path
         .join(date.month().to_string())
         .join(date.day().to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        count_user_lines, read_next_line, DuplicateIds, InvalidUtf8Handling, MigratorOptions,
+        DEFAULT_READ_BUFFER_SIZE,
+    };
+    use chrono::{TimeZone, Utc};
+    use pretty_assertions::assert_eq;
+
+    fn read_lines(input: &[u8], invalid_utf8: InvalidUtf8Handling) -> Vec<String> {
+        let mut reader = input;
+        let mut raw_line = Vec::new();
+        let mut line = String::new();
+
+        let mut lines = Vec::new();
+        while let Some(read_line) =
+            read_next_line(&mut reader, &mut raw_line, &mut line, invalid_utf8).unwrap()
+        {
+            if !read_line.skipped {
+                lines.push(line.clone());
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn read_last_line_without_newline() {
+        let input = "@room-id=1 :tmi.twitch.tv CLEARCHAT #a\r\n@room-id=1 :tmi.twitch.tv CLEARCHAT #b\n@room-id=1 :tmi.twitch.tv CLEARCHAT #c";
+        let mut reader = input.as_bytes();
+        let mut raw_line = Vec::new();
+        let mut line = String::new();
+
+        let mut lines = Vec::new();
+        let mut total_bytes = 0;
+        while let Some(read_line) = read_next_line(
+            &mut reader,
+            &mut raw_line,
+            &mut line,
+            InvalidUtf8Handling::default(),
+        )
+        .unwrap()
+        {
+            total_bytes += read_line.bytes;
+            lines.push(line.clone());
+        }
+
+        assert_eq!(
+            vec![
+                "@room-id=1 :tmi.twitch.tv CLEARCHAT #a",
+                "@room-id=1 :tmi.twitch.tv CLEARCHAT #b",
+                "@room-id=1 :tmi.twitch.tv CLEARCHAT #c",
+            ],
+            lines
+        );
+        assert_eq!(input.len(), total_bytes);
+    }
+
+    #[test]
+    fn read_lines_with_invalid_utf8() {
+        let input = b"PING a\r\r\nPING \xffb\nPING c\n";
+
+        assert_eq!(
+            vec!["PING a", "PING \u{FFFD}b", "PING c"],
+            read_lines(input, InvalidUtf8Handling::Replace)
+        );
+        assert_eq!(
+            vec!["PING a", "PING c"],
+            read_lines(input, InvalidUtf8Handling::Skip)
+        );
+
+        let mut reader = &input[..];
+        let mut raw_line = Vec::new();
+        let mut line = String::new();
+        read_next_line(
+            &mut reader,
+            &mut raw_line,
+            &mut line,
+            InvalidUtf8Handling::Fail,
+        )
+        .unwrap();
+        assert!(read_next_line(
+            &mut reader,
+            &mut raw_line,
+            &mut line,
+            InvalidUtf8Handling::Fail
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn malformed_line_does_not_affect_next_line() {
+        let input = "@malformed\n@badges=;color=;display-name=Snusbot;emotes=;mod=0;room-id=22484632;subscriber=0;tmi-sent-ts=1489263601000;turbo=0;user-id=62541963;user-type= :snusbot!snusbot@snusbot.tmi.twitch.tv PRIVMSG #forsen :test\n";
+        let options = MigratorOptions {
+            privmsg_only: false,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            invalid_utf8: InvalidUtf8Handling::default(),
+            report_duplicate_ids: false,
+            timestamp_policy: TimestampPolicy::default(),
+        };
+        let date = Utc.with_ymd_and_hms(2017, 3, 11, 0, 0, 0).unwrap();
+
+        let count = count_user_lines(input.as_bytes(), "22484632", date, options).unwrap();
+        assert_eq!(1, count);
+
+        let lines = read_lines(input.as_bytes(), InvalidUtf8Handling::default());
+        assert_eq!("@malformed", lines[0]);
+        assert!(lines[1].starts_with("@badges="));
+    }
+
+    #[test]
+    fn find_duplicate_ids() {
+        let first = "@id=272e342c-5864-4c59-b730-25908cdb7f57;room-id=22484632;tmi-sent-ts=1489263601000;user-id=62541963 :snusbot!snusbot@snusbot.tmi.twitch.tv PRIVMSG #forsen :first";
+        let second = "@id=9cd5f1d6-9d70-4cc0-9c60-0aa3fa36ee6f;room-id=22484632;tmi-sent-ts=1489263602000;user-id=62541963 :snusbot!snusbot@snusbot.tmi.twitch.tv PRIVMSG #forsen :second";
+        let notice =
+            "@msg-id=slow_off :tmi.twitch.tv NOTICE #forsen :This room is no longer in slow mode.";
+
+        let mut duplicate_ids = DuplicateIds::default();
+        for line in [first, second, notice, first, notice, first] {
+            duplicate_ids.check_line(line);
+        }
+
+        assert_eq!(2, duplicate_ids.count);
+        assert_eq!(
+            vec!["272e342c-5864-4c59-b730-25908cdb7f57".to_owned()],
+            duplicate_ids.samples
+        );
+    }
+}
@@ -8,6 +8,7 @@ use std::{
 use tracing::info;
 
 pub const COMPRESSED_CHANNEL_FILE: &str = "channel.txt.gz";
+pub const ZSTD_COMPRESSED_CHANNEL_FILE: &str = "channel.txt.zst";
 pub const UNCOMPRESSED_CHANNEL_FILE: &str = "channel.txt";
 
 pub type ChannelLogDateMap = BTreeMap<u32, BTreeMap<u32, Vec<u32>>>;
@@ -76,11 +77,14 @@ impl LogsReader {
                             .join(day.to_string());
 
                         let compressed_channel_file_path = day_path.join(COMPRESSED_CHANNEL_FILE);
+                        let zstd_compressed_channel_file_path =
+                            day_path.join(ZSTD_COMPRESSED_CHANNEL_FILE);
                         let uncompressed_channel_file_path =
                             day_path.join(UNCOMPRESSED_CHANNEL_FILE);
 
                         if let Ok(metadata) = fs::metadata(uncompressed_channel_file_path)
                             .or_else(|_| fs::metadata(compressed_channel_file_path))
+                            .or_else(|_| fs::metadata(zstd_compressed_channel_file_path))
                         {
                             if metadata.is_file() {
                                 total_size += metadata.len();
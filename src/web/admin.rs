@@ -1,4 +1,11 @@
-use crate::{app::App, bot::BotMessage, error::Error};
+use super::permalink::Permalink;
+use crate::{
+    app::App,
+    bot::{messages_received_total, shard_for, BotMessage},
+    db::read_storage_size,
+    error::Error,
+    migrator::{Migrator, MigratorOptions, DEFAULT_READ_BUFFER_SIZE},
+};
 use aide::{
     openapi::{
         HeaderStyle, Parameter, ParameterData, ParameterSchemaOrContent, ReferenceOr, SchemaObject,
@@ -8,19 +15,16 @@ use aide::{
 use axum::{
     extract::{Request, State},
     middleware::Next,
-    response::{IntoResponse, Response},
+    response::Response,
     Extension, Json,
 };
-use reqwest::StatusCode;
+use chrono::{DateTime, NaiveDate, Utc};
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use tokio::sync::mpsc::Sender;
 
-pub async fn admin_auth(
-    app: State<App>,
-    request: Request,
-    next: Next,
-) -> Result<Response, impl IntoResponse> {
+pub async fn admin_auth(app: State<App>, request: Request, next: Next) -> Result<Response, Error> {
     if let Some(admin_key) = &app.config.admin_api_key {
         if request
             .headers()
@@ -33,7 +37,7 @@ pub async fn admin_auth(
         }
     }
 
-    Err((StatusCode::FORBIDDEN, "No, I don't think so"))
+    Err(Error::InvalidApiKey)
 }
 
 pub fn admin_auth_doc(op: &mut TransformOperation) {
@@ -92,3 +96,259 @@ pub async fn remove_channels(
 
     Ok(())
 }
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AddChannelAliasRequest {
+    /// Additional name the channel's logs are served under, e.g. its previous login
+    pub alias: String,
+    pub channel_id: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct RemoveChannelAliasRequest {
+    pub alias: String,
+}
+
+pub async fn add_channel_alias(
+    app: State<App>,
+    Json(AddChannelAliasRequest { alias, channel_id }): Json<AddChannelAliasRequest>,
+) -> Result<(), Error> {
+    app.config
+        .channel_aliases
+        .insert(alias.to_lowercase(), channel_id);
+    app.config.save()?;
+    Ok(())
+}
+
+pub async fn remove_channel_alias(
+    app: State<App>,
+    Json(RemoveChannelAliasRequest { alias }): Json<RemoveChannelAliasRequest>,
+) -> Result<(), Error> {
+    app.config
+        .channel_aliases
+        .remove(&alias.to_lowercase())
+        .ok_or(Error::NotFound)?;
+    app.config.save()?;
+    Ok(())
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReloadConfigResponse {
+    /// Changed settings which are now in effect
+    pub applied: Vec<String>,
+    /// Changed settings which only take effect after a restart
+    pub restart_required: Vec<String>,
+}
+
+pub async fn reload_config(
+    Extension(bot_tx): Extension<Sender<BotMessage>>,
+    app: State<App>,
+) -> Result<Json<ReloadConfigResponse>, Error> {
+    let reload = app.config.reload()?;
+
+    if !reload.joined_channels.is_empty() {
+        let users = app.get_users(reload.joined_channels, vec![], false).await?;
+        let names = users.into_values().collect();
+        bot_tx.send(BotMessage::JoinChannels(names)).await.unwrap();
+    }
+    if !reload.parted_channels.is_empty() {
+        let users = app.get_users(reload.parted_channels, vec![], false).await?;
+        let names = users.into_values().collect();
+        bot_tx.send(BotMessage::PartChannels(names)).await.unwrap();
+    }
+
+    Ok(Json(ReloadConfigResponse {
+        applied: reload.applied,
+        restart_required: reload.restart_required,
+    }))
+}
+
+const DEFAULT_PERMALINK_LIFETIME_SECONDS: i64 = 7 * 24 * 60 * 60;
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PermalinkRequest {
+    pub channel_id: String,
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    /// Line number in the day's logs, starting at 1
+    pub line: u64,
+    /// How long the permalink stays valid, has to be positive. Defaults to 7 days
+    pub expires_in_seconds: Option<i64>,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PermalinkResponse {
+    /// Token to be used with `/permalink/{token}`
+    pub token: String,
+    #[schemars(with = "String")]
+    pub expires_at: DateTime<Utc>,
+}
+
+pub async fn create_permalink(
+    app: State<App>,
+    Json(request): Json<PermalinkRequest>,
+) -> Result<Json<PermalinkResponse>, Error> {
+    let secret = app
+        .config
+        .permalink_secret
+        .as_deref()
+        .ok_or_else(|| Error::InvalidParam("Permalinks are not configured".to_owned()))?;
+
+    let date = NaiveDate::from_ymd_opt(request.year, request.month, request.day)
+        .ok_or_else(|| Error::InvalidDate("Invalid date".to_owned()))?;
+    if request.line == 0 {
+        return Err(Error::InvalidParam("Line numbers start at 1".to_owned()));
+    }
+
+    let lifetime = request
+        .expires_in_seconds
+        .unwrap_or(DEFAULT_PERMALINK_LIFETIME_SECONDS);
+    if lifetime <= 0 {
+        return Err(Error::InvalidParam(
+            "expiresInSeconds has to be positive".to_owned(),
+        ));
+    }
+    let expires_at = Utc::now()
+        .checked_add_signed(chrono::Duration::seconds(lifetime))
+        .ok_or_else(|| Error::InvalidParam("Expiration out of range".to_owned()))?;
+
+    let permalink = Permalink {
+        channel_id: request.channel_id,
+        date,
+        line: request.line,
+        expires_at: expires_at.timestamp(),
+    };
+
+    Ok(Json(PermalinkResponse {
+        token: permalink.sign(secret),
+        expires_at,
+    }))
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReimportRequest {
+    /// The justlog logs folder on the server
+    pub source_dir: String,
+    pub channel_id: String,
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    /// Only reimport PRIVMSG (chat) messages
+    #[serde(default)]
+    pub privmsg_only: bool,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReimportResponse {
+    /// Amount of lines written for the day
+    pub lines: u64,
+}
+
+pub async fn reimport_day(
+    app: State<App>,
+    Json(request): Json<ReimportRequest>,
+) -> Result<Json<ReimportResponse>, Error> {
+    let date = NaiveDate::from_ymd_opt(request.year, request.month, request.day)
+        .ok_or_else(|| Error::InvalidDate("Invalid date".to_owned()))?;
+    if date >= Utc::now().date_naive() {
+        return Err(Error::InvalidDate(
+            "Only past days can be reimported".to_owned(),
+        ));
+    }
+
+    let options = MigratorOptions {
+        privmsg_only: request.privmsg_only,
+        read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+        invalid_utf8: Default::default(),
+        report_duplicate_ids: false,
+        timestamp_policy: app.config.timestamp_policy,
+    };
+    let migrator = Migrator::new(
+        (*app.db).clone(),
+        request.source_dir,
+        vec![request.channel_id],
+        options,
+    )
+    .await?;
+
+    let lines = migrator
+        .reimport_day(date, false)
+        .await?
+        .ok_or(Error::NotFound)?;
+    Ok(Json(ReimportResponse { lines }))
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminStats {
+    pub uptime_seconds: u64,
+    pub channel_count: usize,
+    /// Messages logged since startup
+    pub messages_received: u64,
+    /// Messages waiting to be written to the database
+    pub pending_messages: usize,
+    pub users_cache: UsersCacheStats,
+    pub storage: StorageStats,
+    pub irc_shards: usize,
+    /// IRC shard (starting at 0) of every logged channel by its id
+    pub channel_shards: BTreeMap<String, usize>,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UsersCacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Ratio of lookups answered from the cache, `null` if there were none yet
+    pub hit_rate: Option<f64>,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageStats {
+    pub messages: u64,
+    pub bytes_on_disk: u64,
+}
+
+pub async fn get_stats(app: State<App>) -> Result<Json<AdminStats>, Error> {
+    let (messages, bytes_on_disk) = read_storage_size(&app.db, &app.config.clickhouse_db).await?;
+    let cache = app.users.stats();
+    let cache_lookups = cache.hits + cache.misses;
+
+    let irc_shards = app.config.irc_shards.max(1);
+    let channel_shards: BTreeMap<_, _> = app
+        .config
+        .channels
+        .read()
+        .unwrap()
+        .iter()
+        .map(|channel_id| (channel_id.clone(), shard_for(channel_id, irc_shards)))
+        .collect();
+
+    Ok(Json(AdminStats {
+        uptime_seconds: app.started_at.elapsed().as_secs(),
+        channel_count: channel_shards.len(),
+        messages_received: messages_received_total(),
+        pending_messages: app.flush_buffer.message_count().await,
+        users_cache: UsersCacheStats {
+            entries: cache.entries,
+            hits: cache.hits,
+            misses: cache.misses,
+            hit_rate: (cache_lookups > 0).then(|| cache.hits as f64 / cache_lookups as f64),
+        },
+        storage: StorageStats {
+            messages,
+            bytes_on_disk,
+        },
+        irc_shards,
+        channel_shards,
+    }))
+}
@@ -0,0 +1,68 @@
+use super::schema::{BanGroup, ChannelBan};
+use chrono::Duration;
+
+/// Groups bans (in ascending order) which follow the previous one within the window, e.g. during a raid cleanup
+pub fn group_bans(bans: Vec<ChannelBan>, window: Duration) -> Vec<BanGroup> {
+    let mut groups: Vec<BanGroup> = Vec::new();
+
+    for ban in bans {
+        match groups.last_mut() {
+            Some(group) if ban.timestamp - group.end <= window => {
+                group.end = ban.timestamp;
+                group.bans.push(ban);
+            }
+            _ => groups.push(BanGroup {
+                start: ban.timestamp,
+                end: ban.timestamp,
+                bans: vec![ban],
+            }),
+        }
+    }
+
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::group_bans;
+    use crate::web::schema::ChannelBan;
+    use chrono::{DateTime, Duration};
+    use pretty_assertions::assert_eq;
+
+    fn ban(user: &str, seconds: i64) -> ChannelBan {
+        ChannelBan {
+            user_id: user.to_owned(),
+            user: user.to_owned(),
+            timestamp: DateTime::from_timestamp(1709251274 + seconds, 0).unwrap(),
+            duration: None,
+        }
+    }
+
+    #[test]
+    fn group_consecutive_bans() {
+        let bans = vec![
+            ban("a", 0),
+            ban("b", 30),
+            ban("c", 80),
+            ban("d", 300),
+            ban("e", 360),
+        ];
+
+        let groups = group_bans(bans, Duration::seconds(60));
+        let users: Vec<Vec<&str>> = groups
+            .iter()
+            .map(|group| group.bans.iter().map(|ban| ban.user.as_str()).collect())
+            .collect();
+        assert_eq!(vec![vec!["a", "b", "c"], vec!["d", "e"]], users);
+        assert_eq!(ban("a", 0).timestamp, groups[0].start);
+        assert_eq!(ban("c", 80).timestamp, groups[0].end);
+    }
+
+    #[test]
+    fn group_without_bans() {
+        assert_eq!(
+            Vec::<crate::web::schema::BanGroup>::new(),
+            group_bans(vec![], Duration::seconds(60))
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use crate::error::Error;
+use axum::{
+    body::Body,
+    extract::{Query, Request},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use futures::StreamExt;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+static CHECKSUM_HEADER: HeaderName = HeaderName::from_static("x-content-sha256");
+/// Largest response body which is buffered to be checksummed
+const MAX_CHECKSUM_BODY_SIZE: usize = 128 * 1024 * 1024;
+
+/// Only the `checksum` param is read here, so that the header doesn't depend on how the other params are parsed
+#[derive(Deserialize)]
+struct ChecksumParam {
+    checksum: Option<String>,
+}
+
+/// Adds the SHA-256 checksum of the response body as a header if the `checksum` param is set.
+/// The body is hashed while it is read, but it has to be buffered to send the header before it
+pub async fn add_checksum(request: Request, next: Next) -> Result<Response, Error> {
+    let requested = Query::<ChecksumParam>::try_from_uri(request.uri())
+        .is_ok_and(|Query(param)| param.checksum.is_some());
+    let response = next.run(request).await;
+
+    if !requested || !response.status().is_success() {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let (checksum, buf) = read_hashed(body, MAX_CHECKSUM_BODY_SIZE).await?;
+    parts.headers.insert(
+        CHECKSUM_HEADER.clone(),
+        HeaderValue::from_str(&checksum).expect("Hex digest is a valid header value"),
+    );
+
+    Ok(Response::from_parts(parts, Body::from(buf)))
+}
+
+/// Reads the whole body and its hex encoded SHA-256 digest, rejecting bodies larger than `max_size`
+async fn read_hashed(body: Body, max_size: usize) -> Result<(String, Vec<u8>), Error> {
+    let mut stream = body.into_data_stream();
+    let mut hasher = Sha256::new();
+    let mut buf = Vec::new();
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|err| {
+            error!(error = %err, "Could not read response body for checksum");
+            Error::Internal
+        })?;
+        if buf.len() + chunk.len() > max_size {
+            return Err(Error::TooLarge(
+                "The response is too large to add a checksum. Request a shorter range or leave out the checksum param".to_owned(),
+            ));
+        }
+        hasher.update(&chunk);
+        buf.extend_from_slice(&chunk);
+    }
+
+    Ok((format!("{:x}", hasher.finalize()), buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read_hashed;
+    use crate::error::Error;
+    use axum::body::Body;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn hash_body() {
+        let (checksum, buf) = read_hashed(Body::from("abc"), 1024).await.unwrap();
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            checksum
+        );
+        assert_eq!(b"abc".to_vec(), buf);
+    }
+
+    #[tokio::test]
+    async fn reject_large_body() {
+        let result = read_hashed(Body::from("abc"), 2).await;
+        assert!(matches!(result, Err(Error::TooLarge(_))));
+    }
+}
@@ -0,0 +1,39 @@
+use crate::error::Error;
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use futures::StreamExt;
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::Semaphore, time::timeout};
+use tracing::warn;
+
+/// How long a request waits for a free slot before being rejected
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn limit_concurrency(
+    State(semaphore): State<Arc<Semaphore>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, Error> {
+    let permit = match timeout(QUEUE_TIMEOUT, semaphore.acquire_owned()).await {
+        Ok(permit) => permit.expect("Semaphore should never be closed"),
+        Err(_) => {
+            warn!("Rejecting logs request, too many requests are being processed");
+            return Err(Error::Overloaded);
+        }
+    };
+
+    let response = next.run(request).await;
+
+    // The permit is held until the body has been fully streamed, as that is where most of the work happens
+    let (parts, body) = response.into_parts();
+    let body = body.into_data_stream().map(move |chunk| {
+        let _permit = &permit;
+        chunk
+    });
+
+    Ok(Response::from_parts(parts, Body::from_stream(body)))
+}
@@ -0,0 +1,112 @@
+use crate::db::schema::StructuredMessage;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Position in a channel's logs, given as the timestamp (in milliseconds) and id of the last line already read.
+/// Lines are ordered by their timestamp and then by their id
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeltaCursor {
+    pub channel_id: String,
+    pub timestamp: u64,
+    pub id: Uuid,
+}
+
+impl DeltaCursor {
+    /// Cursor before all lines sent since the given time
+    pub fn starting_at(channel_id: String, time: DateTime<Utc>) -> Self {
+        Self {
+            channel_id,
+            timestamp: time.timestamp_millis() as u64,
+            id: Uuid::nil(),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let payload = serde_json::to_vec(self).expect("Cursors can be serialized");
+        URL_SAFE_NO_PAD.encode(payload)
+    }
+
+    pub fn decode(token: &str) -> Option<Self> {
+        let payload = URL_SAFE_NO_PAD.decode(token).ok()?;
+        serde_json::from_slice(&payload).ok()
+    }
+
+    pub fn time(&self) -> Option<DateTime<Utc>> {
+        DateTime::from_timestamp_millis(self.timestamp as i64)
+    }
+
+    /// Whether the given line comes after the cursor
+    pub fn precedes(&self, msg: &StructuredMessage) -> bool {
+        (self.timestamp, self.id) < (msg.timestamp, msg.uuid().unwrap_or_default())
+    }
+
+    /// Moves the cursor past the given lines (in ascending order)
+    pub fn advance<'a, 'm: 'a>(
+        &self,
+        messages: impl IntoIterator<Item = &'a StructuredMessage<'m>>,
+    ) -> Self {
+        let mut next = self.clone();
+        for msg in messages {
+            next.timestamp = msg.timestamp;
+            next.id = msg.uuid().unwrap_or_default();
+        }
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeltaCursor;
+    use crate::db::schema::{StructuredMessage, UnstructuredMessage};
+    use pretty_assertions::assert_eq;
+    use uuid::Uuid;
+
+    fn cursor() -> DeltaCursor {
+        DeltaCursor {
+            channel_id: "22484632".to_owned(),
+            timestamp: 1686873600000,
+            id: Uuid::parse_str("d2e4e2a4-8f2a-4c4b-9b1e-0a9c4e5b6f70").unwrap(),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        assert_eq!(Some(cursor()), DeltaCursor::decode(&cursor().encode()));
+    }
+
+    #[test]
+    fn reject_malformed() {
+        assert_eq!(None, DeltaCursor::decode("not a cursor"));
+        assert_eq!(
+            None,
+            DeltaCursor::decode(&base64::Engine::encode(
+                &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+                "22484632:2023-06-16:120"
+            ))
+        );
+    }
+
+    #[test]
+    fn advance_to_last_line() {
+        let raw = "@badges=;color=;display-name=Supibot;emotes=;id=7b3e7a1c-2b5f-4f3a-9d3e-1c2b3a4d5e6f;room-id=22484632;tmi-sent-ts=1686873601000;user-id=68136884 :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :+join";
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "68136884",
+            timestamp: 1686873601000,
+            raw,
+        };
+        let msg = StructuredMessage::from_unstructured(&unstructured).unwrap();
+
+        let next = cursor().advance([&msg]);
+        assert_eq!(1686873601000, next.timestamp);
+        assert_eq!(msg.uuid().unwrap(), next.id);
+    }
+
+    #[test]
+    fn advance_without_lines() {
+        assert_eq!(cursor(), cursor().advance([]));
+    }
+}
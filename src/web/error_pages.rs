@@ -0,0 +1,100 @@
+use crate::{app::App, error::ErrorResponse};
+use axum::{
+    extract::{Request, State},
+    http::{header::ACCEPT, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+};
+
+/// Replaces JSON error responses with a minimal HTML page for clients which prefer HTML, such as browsers
+pub async fn negotiate_error_format(app: State<App>, request: Request, next: Next) -> Response {
+    let prefers_html = app.config.html_error_pages && prefers_html(request.headers());
+    let mut response = next.run(request).await;
+
+    if !prefers_html {
+        return response;
+    }
+
+    match response.extensions_mut().remove::<ErrorResponse>() {
+        Some(error) => {
+            let status = response.status();
+            (status, Html(render_page(status, &error))).into_response()
+        }
+        None => response,
+    }
+}
+
+/// Whether HTML is listed before JSON in the `Accept` header
+fn prefers_html(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(
+            |media_type| match media_type.split(';').next().unwrap_or_default().trim() {
+                "text/html" => Some(true),
+                "application/json" => Some(false),
+                _ => None,
+            },
+        )
+        .unwrap_or(false)
+}
+
+fn render_page(status: StatusCode, error: &ErrorResponse) -> String {
+    let title = format!(
+        "{} {}",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default()
+    );
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n</head>\n<body>\n<h1>{title}</h1>\n<p>{}</p>\n<p><a href=\"/\">Back to the logs</a></p>\n</body>\n</html>\n",
+        escape_html(&error.error)
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_html, prefers_html};
+    use axum::http::{header::ACCEPT, HeaderMap, HeaderValue};
+    use pretty_assertions::assert_eq;
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn negotiate_html() {
+        assert!(prefers_html(&accept(
+            "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"
+        )));
+        assert!(!prefers_html(&accept("application/json, text/html")));
+        assert!(!prefers_html(&accept("*/*")));
+        assert!(!prefers_html(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn escape_error_text() {
+        assert_eq!(
+            "Invalid param: &lt;script&gt;&quot;&amp;&#39;",
+            escape_html("Invalid param: <script>\"&'")
+        );
+    }
+}
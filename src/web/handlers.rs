@@ -1,33 +1,80 @@
 use super::{
-    responders::logs::LogsResponse,
+    ban_summary::group_bans,
+    delta_cursor::DeltaCursor,
+    permalink::Permalink,
+    responders::logs::{LogsResponse, LogsResponseType},
     schema::{
-        AvailableLogs, AvailableLogsParams, Channel, ChannelIdType, ChannelLogsByDatePath,
-        ChannelParam, ChannelsList, LogsParams, LogsPathChannel, SearchParams, UserLogPathParams,
-        UserLogsPath, UserParam,
+        AvailableLogs, AvailableLogsParams, AvailableUsersParams, BanSummary, BanSummaryParams,
+        Channel, ChannelIdType, ChannelLogLinePath, ChannelLogsByDatePath, ChannelLogsByDayPath,
+        ChannelMessagePath, ChannelParam, ChannelsList, ChunkParams, DayParams, DeltaParams,
+        GrepUserParams, LineCount, LineRangeParams, LogDayInfo, LogsParams, LogsPathChannel,
+        PermalinkPath, RecentMessages, RecentMessagesParams, RecentMessagesPath, SearchParams,
+        TagsParams, TailParams, TopUser, TopUsers, TopUsersParams, UserBans, UserBansParams,
+        UserDayActivity, UserDayDiff, UserDayDiffParams, UserExists, UserLogPathParams,
+        UserLogsParams, UserLogsPath, UserNthLinePath, UserParam, UserPath,
     },
 };
 use crate::{
     app::App,
+    db::schema::{StructuredMessage, MESSAGES_STRUCTURED_TABLE},
     db::{
-        self, read_available_channel_logs, read_available_user_logs, read_channel,
-        read_random_channel_line, read_random_user_line, read_user,
+        self, count_channel_lines, count_user_lines, find_channel_user_ids, has_channel_logs,
+        has_user_logs, read_available_channel_logs, read_available_user_logs, read_channel,
+        read_channel_bans, read_channel_latest, read_channel_line, read_channel_lines_before,
+        read_channel_since, read_latest_migration, read_message_by_id, read_partition_size,
+        read_random_channel_line, read_random_user_line, read_secondary_indexes, read_top_users,
+        read_user, read_user_bans, read_user_count_by_month, read_user_nth_line,
     },
     error::Error,
-    logs::{schema::LogRangeParams, stream::LogsStream},
+    logs::{
+        schema::{
+            message::{FullMessage, ResponseMessage},
+            LogRangeParams,
+        },
+        stream::LogsStream,
+    },
     web::schema::LogsPathDate,
-    Result,
+    Result, ShutdownRx,
 };
 use aide::axum::IntoApiResponse;
 use axum::{
     extract::{Path, Query, RawQuery, State},
-    response::{IntoResponse, Redirect, Response},
+    http::{header::CONTENT_DISPOSITION, HeaderMap, HeaderName, HeaderValue},
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Redirect, Response, Sse,
+    },
     Json,
 };
 use axum_extra::{headers::CacheControl, TypedHeader};
-use chrono::{Days, Months, NaiveDate, NaiveTime, Utc};
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, NaiveTime, Utc};
+use chrono_tz::Tz;
+use futures::{StreamExt, TryStreamExt};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use std::time::Duration;
-use tracing::debug;
+use std::{collections::VecDeque, convert::Infallible, str::FromStr, time::Duration};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, error};
+use uuid::Uuid;
+
+const RECENT_MESSAGES_MAX_COUNT: u64 = 800;
+const RECENT_MESSAGES_MAX_AGE_HOURS: i64 = 24;
+const DEFAULT_CHUNK_SIZE: u64 = 500;
+const MAX_CHUNK_SIZE: u64 = 10_000;
+const HAS_MORE_HEADER: &str = "x-has-more";
+const LINE_COUNT_HEADER: &str = "x-line-count";
+const CURSOR_HEADER: &str = "x-cursor";
+const MAX_CONTEXT_BEFORE: u64 = 5;
+const CONTEXT_MAX_MESSAGES: usize = 200;
+const CONTEXT_QUERY_CONCURRENCY: usize = 8;
+const CURSOR_MAX_AGE_DAYS: u64 = 7;
+const LAST_EVENT_ID_HEADER: &str = "last-event-id";
+const DEFAULT_TAIL_LINES: u64 = 50;
+const MAX_TAIL_LINES: u64 = 1000;
+const DEFAULT_USER_BANS: u64 = 100;
+const MAX_USER_BANS: u64 = 1000;
+const USER_CHANNELS_QUERY_CONCURRENCY: usize = 8;
+const DEFAULT_AVAILABLE_USERS: u64 = 1000;
+const MAX_AVAILABLE_USERS: u64 = 10000;
 
 pub async fn get_channels(app: State<App>) -> impl IntoApiResponse {
     let channel_ids = app.config.channels.read().unwrap().clone();
@@ -52,16 +99,27 @@ pub async fn get_channel_logs(
         channel,
     }): Path<LogsPathChannel>,
     range_params: Option<Query<LogRangeParams>>,
+    Query(tags_params): Query<TagsParams>,
+    Query(grep_user_params): Query<GrepUserParams>,
     RawQuery(query): RawQuery,
+    headers: HeaderMap,
     app: State<App>,
 ) -> Result<Response> {
     let channel_id = match channel_id_type {
-        ChannelIdType::Name => app.get_user_id_by_name(&channel).await?,
+        ChannelIdType::Name => app.get_channel_id_by_name(&channel).await?,
         ChannelIdType::Id => channel.clone(),
     };
 
     if let Some(Query(params)) = range_params {
-        let logs = get_channel_logs_inner(&app, &channel_id, params).await?;
+        let logs = get_channel_logs_inner(
+            &app,
+            &channel_id,
+            params,
+            &tags_params,
+            grep_user_params.user(),
+            &headers,
+        )
+        .await?;
         Ok(logs.into_response())
     } else {
         let available_logs = read_available_channel_logs(&app.db, &channel_id).await?;
@@ -80,27 +138,603 @@ pub async fn get_channel_logs(
 pub async fn get_channel_logs_by_date(
     app: State<App>,
     Path(channel_log_params): Path<ChannelLogsByDatePath>,
-    Query(logs_params): Query<LogsParams>,
+    Query(mut logs_params): Query<LogsParams>,
+    Query(chunk_params): Query<ChunkParams>,
+    Query(tags_params): Query<TagsParams>,
+    Query(grep_user_params): Query<GrepUserParams>,
+    headers: HeaderMap,
 ) -> Result<impl IntoApiResponse> {
     debug!("Params: {logs_params:?}");
+    let grep_user = grep_user_params.user();
 
     let channel_id = match channel_log_params.channel_info.channel_id_type {
         ChannelIdType::Name => {
-            app.get_user_id_by_name(&channel_log_params.channel_info.channel)
+            app.get_channel_id_by_name(&channel_log_params.channel_info.channel)
                 .await?
         }
         ChannelIdType::Id => channel_log_params.channel_info.channel.clone(),
     };
 
-    let LogsPathDate { year, month, day } = channel_log_params.date;
+    let (from, to) = day_range(&channel_log_params.date)?;
 
-    let from = NaiveDate::from_ymd_opt(year.parse()?, month.parse()?, day.parse()?)
-        .ok_or_else(|| Error::InvalidParam("Invalid date".to_owned()))?
-        .and_time(NaiveTime::default())
-        .and_utc();
+    let mut response_headers = HeaderMap::new();
+    if let Some(chunk) = chunk_params.chunk {
+        if grep_user.is_some() {
+            return Err(Error::InvalidParam(
+                "grepUser cannot be combined with chunks".to_owned(),
+            ));
+        }
+        let chunk_size = chunk_params
+            .chunk_size
+            .unwrap_or(DEFAULT_CHUNK_SIZE)
+            .clamp(1, MAX_CHUNK_SIZE);
+        let offset = chunk
+            .checked_mul(chunk_size)
+            .ok_or_else(|| Error::InvalidParam("Chunk out of range".to_owned()))?;
+        logs_params.limit = Some(chunk_size);
+        logs_params.offset = Some(offset);
+
+        // Counted the same way as the lines of the day, including messages which have not been flushed yet
+        let line_count =
+            count_channel_lines(&app.db, &channel_id, from, to, &app.flush_buffer).await?;
+        let has_more = line_count > offset.saturating_add(chunk_size);
+        response_headers.insert(
+            HeaderName::from_static(HAS_MORE_HEADER),
+            HeaderValue::from_static(if has_more { "true" } else { "false" }),
+        );
+    }
+
+    let params = LogRangeParams {
+        from,
+        to,
+        logs_params,
+    };
+
+    let logs = get_channel_logs_inner(&app, &channel_id, params, &tags_params, grep_user, &headers)
+        .await?;
+    Ok((response_headers, logs))
+}
+
+/// Lines `[startLine, endLine)` of a day's channel logs
+pub async fn get_channel_lines_between(
+    app: State<App>,
+    Path(channel_log_params): Path<ChannelLogsByDatePath>,
+    Query(mut logs_params): Query<LogsParams>,
+    Query(LineRangeParams {
+        start_line,
+        end_line,
+    }): Query<LineRangeParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_log_params.channel_info.channel_id_type {
+        ChannelIdType::Name => {
+            app.get_channel_id_by_name(&channel_log_params.channel_info.channel)
+                .await?
+        }
+        ChannelIdType::Id => channel_log_params.channel_info.channel,
+    };
+
+    app.check_opted_out(&channel_id, None)?;
+
+    if start_line == 0 {
+        return Err(Error::InvalidParam("Line numbers start at 1".to_owned()));
+    }
+    if end_line <= start_line {
+        return Err(Error::InvalidParam(
+            "endLine has to be after startLine".to_owned(),
+        ));
+    }
+    if end_line - start_line > MAX_CHUNK_SIZE {
+        return Err(Error::TooLarge(format!(
+            "At most {MAX_CHUNK_SIZE} lines can be requested at once"
+        )));
+    }
+
+    let (from, to) = day_range(&channel_log_params.date)?;
+    let line_count = count_channel_lines(&app.db, &channel_id, from, to, &app.flush_buffer).await?;
+    if end_line > line_count + 1 {
+        return Err(Error::InvalidParam(format!(
+            "The day only has {line_count} lines"
+        )));
+    }
+
+    // The line numbers refer to the whole day, regardless of the other params
+    logs_params.reverse = false;
+    logs_params.message_type = None;
+    logs_params.exclude_shared = false;
+    logs_params.first_msg_only = false;
+    logs_params.min_sub_months = None;
+    logs_params.offset = Some(start_line - 1);
+    logs_params.limit = Some(end_line - start_line);
+
+    let stream = read_channel(
+        &app.db,
+        &channel_id,
+        LogRangeParams {
+            from,
+            to,
+            logs_params,
+        },
+        &app.flush_buffer,
+    )
+    .await?;
+
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(
+        HeaderName::from_static(LINE_COUNT_HEADER),
+        HeaderValue::from(line_count),
+    );
+
+    let logs = LogsResponse {
+        stream,
+        response_type: logs_params
+            .response_type(&headers, app.config.default_response_formats.channel),
+        anonymize: logs_params.anonymize,
+    };
+    Ok((response_headers, logs))
+}
+
+/// Metadata of a day's channel logs without the logs themselves.
+/// There is no `Content-Length`, as the size depends on the format the logs are rendered in
+pub async fn head_channel_logs_by_date(
+    app: State<App>,
+    Path(channel_log_params): Path<ChannelLogsByDatePath>,
+) -> Result<impl IntoApiResponse> {
+    let (count, cache) = count_channel_day_lines(&app, channel_log_params).await?;
+
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(
+        HeaderName::from_static(LINE_COUNT_HEADER),
+        HeaderValue::from(count),
+    );
+
+    Ok((cache, response_headers, ()))
+}
+
+pub async fn get_channel_line_count_by_date(
+    app: State<App>,
+    Path(channel_log_params): Path<ChannelLogsByDatePath>,
+) -> Result<impl IntoApiResponse> {
+    let (count, cache) = count_channel_day_lines(&app, channel_log_params).await?;
+    Ok((cache, Json(LineCount { count })))
+}
+
+pub async fn get_channel_logs_info_by_date(
+    app: State<App>,
+    Path(channel_log_params): Path<ChannelLogsByDatePath>,
+) -> Result<impl IntoApiResponse> {
+    let (from, _) = day_range(&channel_log_params.date)?;
+    let (line_count, _) = count_channel_day_lines(&app, channel_log_params).await?;
+
+    let partition = from.format("%Y%m").to_string();
+    let db_name = &app.config.clickhouse_db;
+    let partition_size = read_partition_size(&app.db, db_name, &partition).await?;
+
+    let info = LogDayInfo {
+        table: MESSAGES_STRUCTURED_TABLE,
+        schema_version: read_latest_migration(&app.db).await?,
+        secondary_indexes: read_secondary_indexes(&app.db, db_name).await?,
+        line_count,
+        partition,
+        partition_rows: partition_size.rows,
+        partition_compressed_bytes: partition_size.compressed_bytes,
+        partition_uncompressed_bytes: partition_size.uncompressed_bytes,
+    };
+    Ok((no_cache_header(), Json(info)))
+}
+
+/// Amount of lines in the channel's logs of the day, with the cache header for responses about it
+async fn count_channel_day_lines(
+    app: &App,
+    channel_log_params: ChannelLogsByDatePath,
+) -> Result<(u64, TypedHeader<CacheControl>)> {
+    let channel_id = match channel_log_params.channel_info.channel_id_type {
+        ChannelIdType::Name => {
+            app.get_channel_id_by_name(&channel_log_params.channel_info.channel)
+                .await?
+        }
+        ChannelIdType::Id => channel_log_params.channel_info.channel,
+    };
+
+    app.check_opted_out(&channel_id, None)?;
+
+    let (from, to) = day_range(&channel_log_params.date)?;
+    let count = count_channel_lines(&app.db, &channel_id, from, to, &app.flush_buffer).await?;
+    if count == 0 {
+        let is_joined = app.config.channels.read().unwrap().contains(&channel_id);
+        if !is_joined && !has_channel_logs(&app.db, &channel_id).await? {
+            return Err(Error::ChannelNotFound);
+        }
+    }
+
+    let cache = if Utc::now() < to {
+        no_cache_header()
+    } else {
+        cache_header(36000)
+    };
+    Ok((count, cache))
+}
+
+pub async fn get_channel_line_by_date(
+    app: State<App>,
+    Path(ChannelLogLinePath {
+        channel_info,
+        date,
+        line,
+    }): Path<ChannelLogLinePath>,
+    Query(logs_params): Query<LogsParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_info.channel_id_type {
+        ChannelIdType::Name => app.get_channel_id_by_name(&channel_info.channel).await?,
+        ChannelIdType::Id => channel_info.channel,
+    };
+
+    app.check_opted_out(&channel_id, None)?;
+
+    let line: u64 = line.parse()?;
+    let offset = line
+        .checked_sub(1)
+        .ok_or_else(|| Error::InvalidParam("Line numbers start at 1".to_owned()))?;
+
+    let (from, to) = day_range(&date)?;
+
+    let msg = read_channel_line(&app.db, &channel_id, from, to, offset, &app.flush_buffer).await?;
+    let stream = LogsStream::new_provided(vec![msg])?;
+
+    let logs = LogsResponse {
+        stream,
+        response_type: logs_params
+            .response_type(&headers, app.config.default_response_formats.channel),
+        anonymize: logs_params.anonymize,
+    };
+
+    let cache = if Utc::now() < to {
+        no_cache_header()
+    } else {
+        cache_header(36000)
+    };
+
+    Ok((cache, logs))
+}
+
+pub async fn get_channel_message_by_id(
+    app: State<App>,
+    Path(ChannelMessagePath {
+        channel_info,
+        message_id,
+    }): Path<ChannelMessagePath>,
+    Query(logs_params): Query<LogsParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_info.channel_id_type {
+        ChannelIdType::Name => app.get_channel_id_by_name(&channel_info.channel).await?,
+        ChannelIdType::Id => channel_info.channel,
+    };
+
+    app.check_opted_out(&channel_id, None)?;
+
+    let message_id = Uuid::parse_str(&message_id)
+        .map_err(|_| Error::InvalidFormat("Invalid message id".to_owned()))?;
+
+    let msg = read_message_by_id(
+        &app.db,
+        &channel_id,
+        &message_id.to_string(),
+        &app.flush_buffer,
+    )
+    .await?;
+    app.check_opted_out(&channel_id, Some(&msg.user_id))?;
+
+    let stream = LogsStream::new_provided(vec![msg])?;
+
+    let logs = LogsResponse {
+        stream,
+        response_type: logs_params
+            .response_type(&headers, app.config.default_response_formats.channel),
+        anonymize: logs_params.anonymize,
+    };
+    Ok((cache_header(36000), logs))
+}
+
+pub async fn get_channel_logs_delta(
+    app: State<App>,
+    Path(LogsPathChannel {
+        channel_id_type,
+        channel,
+    }): Path<LogsPathChannel>,
+    Query(delta_params): Query<DeltaParams>,
+    Query(logs_params): Query<LogsParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_channel_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    app.check_opted_out(&channel_id, None)?;
+
+    let cursor = match delta_params.cursor {
+        Some(token) => parse_cursor(&token, &channel_id)?,
+        None => {
+            let today = Utc::now().date_naive();
+            DeltaCursor::starting_at(
+                channel_id.clone(),
+                today.and_time(NaiveTime::default()).and_utc(),
+            )
+        }
+    };
+
+    let limit = logs_params
+        .limit
+        .unwrap_or(MAX_CHUNK_SIZE)
+        .min(MAX_CHUNK_SIZE);
+    let messages = read_channel_since(
+        &app.db,
+        &channel_id,
+        cursor.timestamp,
+        cursor.id,
+        limit,
+        &app.flush_buffer,
+    )
+    .await?;
+
+    let next_cursor = cursor.advance(&messages);
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(
+        HeaderName::from_static(CURSOR_HEADER),
+        HeaderValue::from_str(&next_cursor.encode()).expect("Cursors are base64"),
+    );
+
+    let logs = LogsResponse {
+        // An empty delta is an expected result here rather than a missing log
+        stream: LogsStream::Provided(messages.into_iter()),
+        response_type: logs_params
+            .response_type(&headers, app.config.default_response_formats.channel),
+        anonymize: logs_params.anonymize,
+    };
+    Ok((no_cache_header(), response_headers, logs))
+}
+
+pub async fn tail_channel_logs(
+    app: State<App>,
+    Path(LogsPathChannel {
+        channel_id_type,
+        channel,
+    }): Path<LogsPathChannel>,
+    Query(TailParams { lines }): Query<TailParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_channel_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    app.check_opted_out(&channel_id, None)?;
+
+    // Subscribe before reading the stored lines, so that no messages are missed in between
+    let live_rx = app.flush_buffer.subscribe();
+
+    let last_event_id = headers
+        .get(LAST_EVENT_ID_HEADER)
+        .and_then(|value| value.to_str().ok());
+    let (cursor, stored_messages) = match last_event_id {
+        Some(token) => {
+            let cursor = parse_cursor(token, &channel_id)?;
+            let messages = read_channel_since(
+                &app.db,
+                &channel_id,
+                cursor.timestamp,
+                cursor.id,
+                MAX_CHUNK_SIZE,
+                &app.flush_buffer,
+            )
+            .await?;
+            (cursor, messages)
+        }
+        None => {
+            let today = Utc::now().date_naive();
+            let from = today.and_time(NaiveTime::default()).and_utc();
+            let lines = lines.unwrap_or(DEFAULT_TAIL_LINES).min(MAX_TAIL_LINES);
+
+            let messages =
+                read_channel_latest(&app.db, &channel_id, from, lines, &app.flush_buffer).await?;
+            (DeltaCursor::starting_at(channel_id.clone(), from), messages)
+        }
+    };
+
+    let tail = Tail {
+        catching_up: stored_messages.len() as u64 == MAX_CHUNK_SIZE,
+        pending: stored_messages.into(),
+        shutdown_rx: app.shutdown_rx.clone(),
+        app: app.0,
+        channel_id,
+        cursor,
+        live_rx,
+    };
+    let events = futures::stream::unfold(tail, |mut tail| async move {
+        let event = tail.next_event().await?;
+        Some((Ok::<_, Infallible>(event), tail))
+    });
+
+    Ok((
+        no_cache_header(),
+        Sse::new(events).keep_alive(KeepAlive::default()),
+    ))
+}
+
+/// State of a channel's live logs stream
+struct Tail {
+    app: App,
+    channel_id: String,
+    cursor: DeltaCursor,
+    live_rx: broadcast::Receiver<StructuredMessage<'static>>,
+    shutdown_rx: ShutdownRx,
+    /// Lines to send before receiving new ones
+    pending: VecDeque<StructuredMessage<'static>>,
+    /// Whether there are more stored lines to read after the pending ones
+    catching_up: bool,
+}
+
+impl Tail {
+    /// Waits for the next line, ending the stream on shutdown
+    async fn next_event(&mut self) -> Option<Event> {
+        loop {
+            if let Some(msg) = self.pending.pop_front() {
+                self.cursor = self.cursor.advance([&msg]);
+                match message_event(&msg, &self.cursor) {
+                    Some(event) => return Some(event),
+                    None => continue,
+                }
+            }
+
+            if self.catching_up {
+                self.read_stored().await?;
+                continue;
+            }
+
+            let received = tokio::select! {
+                received = self.live_rx.recv() => received,
+                _ = self.shutdown_rx.changed() => return None,
+            };
+            match received {
+                // Lines which were already read from the stored logs are skipped
+                Ok(msg) if msg.channel_id == self.channel_id && self.cursor.precedes(&msg) => {
+                    self.pending.push_back(msg);
+                }
+                Ok(_) => (),
+                // Lines which were missed while falling behind are read from the stored logs instead
+                Err(RecvError::Lagged(count)) => {
+                    debug!(
+                        "Live logs of channel {} lagged by {count} lines",
+                        self.channel_id
+                    );
+                    self.catching_up = true;
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    async fn read_stored(&mut self) -> Option<()> {
+        match read_channel_since(
+            &self.app.db,
+            &self.channel_id,
+            self.cursor.timestamp,
+            self.cursor.id,
+            MAX_CHUNK_SIZE,
+            &self.app.flush_buffer,
+        )
+        .await
+        {
+            Ok(messages) => {
+                self.catching_up = messages.len() as u64 == MAX_CHUNK_SIZE;
+                self.pending.extend(messages);
+                Some(())
+            }
+            Err(err) => {
+                error!(
+                    channel_id = %self.channel_id,
+                    error = %err,
+                    "Could not read stored logs of channel"
+                );
+                None
+            }
+        }
+    }
+}
+
+fn message_event(msg: &StructuredMessage, cursor: &DeltaCursor) -> Option<Event> {
+    let event = FullMessage::from_structured(msg)
+        .and_then(|message| Ok(Event::default().id(cursor.encode()).json_data(message)?));
+    match event {
+        Ok(event) => Some(event),
+        Err(err) => {
+            error!(?msg, error = %err, "Could not serialize message");
+            None
+        }
+    }
+}
+
+/// Decodes a cursor of the channel's logs, rejecting ones which are too old to be resumed
+fn parse_cursor(token: &str, channel_id: &str) -> Result<DeltaCursor> {
+    let today = Utc::now().date_naive();
+    let (cursor, date) = DeltaCursor::decode(token)
+        .filter(|cursor| cursor.channel_id == channel_id)
+        .and_then(|cursor| {
+            let date = cursor.time()?.date_naive();
+            Some((cursor, date))
+        })
+        .filter(|(_, date)| *date <= today)
+        .ok_or_else(|| Error::InvalidFormat("Invalid cursor".to_owned()))?;
+
+    let oldest = today
+        .checked_sub_days(Days::new(CURSOR_MAX_AGE_DAYS))
+        .ok_or_else(|| Error::InvalidDate("Date out of range".to_owned()))?;
+    if date < oldest {
+        return Err(Error::InvalidParam("Cursor expired".to_owned()));
+    }
+
+    Ok(cursor)
+}
+
+pub async fn get_permalink_line(
+    app: State<App>,
+    Path(PermalinkPath { token }): Path<PermalinkPath>,
+    Query(logs_params): Query<LogsParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoApiResponse> {
+    let secret = app
+        .config
+        .permalink_secret
+        .as_deref()
+        .ok_or(Error::NotFound)?;
+    let permalink =
+        Permalink::verify(&token, secret, Utc::now().timestamp()).ok_or(Error::NotFound)?;
+
+    app.check_opted_out(&permalink.channel_id, None)?;
+
+    let from = permalink.date.and_time(NaiveTime::default()).and_utc();
     let to = from
         .checked_add_days(Days::new(1))
-        .ok_or_else(|| Error::InvalidParam("Date out of range".to_owned()))?;
+        .ok_or_else(|| Error::InvalidDate("Date out of range".to_owned()))?;
+
+    let msg = read_channel_line(
+        &app.db,
+        &permalink.channel_id,
+        from,
+        to,
+        permalink.line.saturating_sub(1),
+        &app.flush_buffer,
+    )
+    .await?;
+    let stream = LogsStream::new_provided(vec![msg])?;
+
+    let logs = LogsResponse {
+        stream,
+        response_type: logs_params
+            .response_type(&headers, app.config.default_response_formats.channel),
+        anonymize: logs_params.anonymize,
+    };
+    Ok((no_cache_header(), logs))
+}
+
+pub async fn get_channel_logs_by_day(
+    app: State<App>,
+    Path(ChannelLogsByDayPath { channel_info, day }): Path<ChannelLogsByDayPath>,
+    Query(DayParams { timezone }): Query<DayParams>,
+    Query(logs_params): Query<LogsParams>,
+    Query(tags_params): Query<TagsParams>,
+    Query(grep_user_params): Query<GrepUserParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_info.channel_id_type {
+        ChannelIdType::Name => app.get_channel_id_by_name(&channel_info.channel).await?,
+        ChannelIdType::Id => channel_info.channel,
+    };
+
+    let timezone = parse_timezone(timezone.as_deref())?;
+    let (from, to) = named_day_range(&day, timezone, Utc::now())?;
 
     let params = LogRangeParams {
         from,
@@ -108,21 +742,81 @@ pub async fn get_channel_logs_by_date(
         logs_params,
     };
 
-    get_channel_logs_inner(&app, &channel_id, params).await
+    get_channel_logs_inner(
+        &app,
+        &channel_id,
+        params,
+        &tags_params,
+        grep_user_params.user(),
+        &headers,
+    )
+    .await
+}
+
+/// Reads the lines of the user with the given id or login from the channel's logs, without resolving it through the Twitch API
+async fn grep_channel_user(
+    app: &App,
+    channel_id: &str,
+    user: &str,
+    params: LogRangeParams,
+) -> Result<LogsStream> {
+    let user_ids = find_channel_user_ids(
+        &app.db,
+        channel_id,
+        user,
+        params.from,
+        params.to,
+        &app.flush_buffer,
+    )
+    .await?;
+    let user_ids: Vec<&str> = user_ids
+        .iter()
+        .map(String::as_str)
+        .filter(|user_id| !app.config.opt_out.contains_key(*user_id))
+        .collect();
+
+    if user_ids.is_empty() {
+        return Ok(LogsStream::empty());
+    }
+    read_user(&app.db, channel_id, &user_ids, params, &app.flush_buffer).await
 }
 
 async fn get_channel_logs_inner(
     app: &App,
     channel_id: &str,
     channel_log_params: LogRangeParams,
+    tags_params: &TagsParams,
+    grep_user: Option<&str>,
+    headers: &HeaderMap,
 ) -> Result<impl IntoApiResponse> {
     app.check_opted_out(channel_id, None)?;
+    check_range_months(app, channel_log_params.from, channel_log_params.to)?;
 
-    let stream = read_channel(&app.db, channel_id, channel_log_params, &app.flush_buffer).await?;
+    let result = match grep_user {
+        Some(user) => grep_channel_user(app, channel_id, user, channel_log_params).await,
+        None => read_channel(&app.db, channel_id, channel_log_params, &app.flush_buffer).await,
+    };
+    let stream = match result {
+        Ok(stream) => stream,
+        // Logged channels without messages in the range get an empty response
+        Err(Error::NotFound) => {
+            let is_joined = app.config.channels.read().unwrap().contains(channel_id);
+            if is_joined || has_channel_logs(&app.db, channel_id).await? {
+                LogsStream::empty()
+            } else {
+                return Err(Error::ChannelNotFound);
+            }
+        }
+        Err(err) => return Err(err),
+    };
 
     let logs = LogsResponse {
-        response_type: channel_log_params.logs_params.response_type(),
+        response_type: channel_log_params
+            .logs_params
+            .response_type(headers, app.config.default_response_formats.channel)
+            .with_tags(tags_params.tags()),
         stream,
+        anonymize: channel_log_params.logs_params.anonymize,
     };
 
     let cache = if Utc::now() < channel_log_params.to {
@@ -137,19 +831,41 @@ async fn get_channel_logs_inner(
 pub async fn get_user_logs_by_name(
     path: Path<UserLogPathParams>,
     range_params: Option<Query<LogRangeParams>>,
+    user_logs_params: Query<UserLogsParams>,
     query: RawQuery,
+    headers: HeaderMap,
     app: State<App>,
 ) -> Result<impl IntoApiResponse> {
-    get_user_logs(path, range_params, query, false, app).await
+    get_user_logs(
+        path,
+        range_params,
+        user_logs_params,
+        query,
+        headers,
+        false,
+        app,
+    )
+    .await
 }
 
 pub async fn get_user_logs_id(
     path: Path<UserLogPathParams>,
     range_params: Option<Query<LogRangeParams>>,
+    user_logs_params: Query<UserLogsParams>,
     query: RawQuery,
+    headers: HeaderMap,
     app: State<App>,
 ) -> Result<impl IntoApiResponse> {
-    get_user_logs(path, range_params, query, true, app).await
+    get_user_logs(
+        path,
+        range_params,
+        user_logs_params,
+        query,
+        headers,
+        true,
+        app,
+    )
+    .await
 }
 
 async fn get_user_logs(
@@ -159,12 +875,14 @@ async fn get_user_logs(
         user,
     }): Path<UserLogPathParams>,
     range_params: Option<Query<LogRangeParams>>,
+    Query(user_logs_params): Query<UserLogsParams>,
     RawQuery(query): RawQuery,
+    headers: HeaderMap,
     user_is_id: bool,
     app: State<App>,
 ) -> Result<impl IntoApiResponse> {
     let channel_id = match channel_id_type {
-        ChannelIdType::Name => app.get_user_id_by_name(&channel).await?,
+        ChannelIdType::Name => app.get_channel_id_by_name(&channel).await?,
         ChannelIdType::Id => channel.clone(),
     };
     let user_id = if user_is_id {
@@ -176,7 +894,15 @@ async fn get_user_logs(
     app.check_opted_out(&channel_id, Some(&user_id))?;
 
     if let Some(Query(params)) = range_params {
-        let logs = get_user_logs_inner(&app, &channel_id, &user_id, params).await?;
+        let logs = get_user_logs_inner(
+            &app,
+            &channel_id,
+            &user_id,
+            &user_logs_params,
+            params,
+            &headers,
+        )
+        .await?;
         Ok(logs.into_response())
     } else {
         let available_logs = read_available_user_logs(&app.db, &channel_id, &user_id).await?;
@@ -198,30 +924,36 @@ pub async fn get_user_logs_by_date_name(
     app: State<App>,
     path: Path<UserLogsPath>,
     params: Query<LogsParams>,
+    user_logs_params: Query<UserLogsParams>,
+    headers: HeaderMap,
 ) -> Result<impl IntoApiResponse> {
     let user_id = app.get_user_id_by_name(&path.user).await?;
 
-    get_user_logs_by_date(app, path, params, user_id).await
+    get_user_logs_by_date(app, path, params, user_logs_params, headers, user_id).await
 }
 
 pub async fn get_user_logs_by_date_id(
     app: State<App>,
     path: Path<UserLogsPath>,
     params: Query<LogsParams>,
+    user_logs_params: Query<UserLogsParams>,
+    headers: HeaderMap,
 ) -> Result<impl IntoApiResponse> {
     let user_id = path.user.clone();
-    get_user_logs_by_date(app, path, params, user_id).await
+    get_user_logs_by_date(app, path, params, user_logs_params, headers, user_id).await
 }
 
 async fn get_user_logs_by_date(
     app: State<App>,
     Path(user_logs_path): Path<UserLogsPath>,
     Query(logs_params): Query<LogsParams>,
+    Query(user_logs_params): Query<UserLogsParams>,
+    headers: HeaderMap,
     user_id: String,
 ) -> Result<impl IntoApiResponse> {
     let channel_id = match user_logs_path.channel_info.channel_id_type {
         ChannelIdType::Name => {
-            app.get_user_id_by_name(&user_logs_path.channel_info.channel)
+            app.get_channel_id_by_name(&user_logs_path.channel_info.channel)
                 .await?
         }
         ChannelIdType::Id => user_logs_path.channel_info.channel.clone(),
@@ -229,16 +961,16 @@ async fn get_user_logs_by_date(
 
     app.check_opted_out(&channel_id, Some(&user_id))?;
 
-    let year = user_logs_path.year.parse()?;
-    let month = user_logs_path.month.parse()?;
+    let year = parse_date_part(&user_logs_path.year)?;
+    let month = parse_date_part(&user_logs_path.month)?;
 
     let from = NaiveDate::from_ymd_opt(year, month, 1)
-        .ok_or_else(|| Error::InvalidParam("Invalid date".to_owned()))?
+        .ok_or_else(|| Error::InvalidDate("Invalid date".to_owned()))?
         .and_time(NaiveTime::default())
         .and_utc();
     let to = from
         .checked_add_months(Months::new(1))
-        .ok_or_else(|| Error::InvalidParam("Date out of range".to_owned()))?;
+        .ok_or_else(|| Error::InvalidDate("Date out of range".to_owned()))?;
 
     let params = LogRangeParams {
         from,
@@ -246,21 +978,143 @@ async fn get_user_logs_by_date(
         logs_params,
     };
 
-    get_user_logs_inner(&app, &channel_id, &user_id, params).await
+    get_user_logs_inner(
+        &app,
+        &channel_id,
+        &user_id,
+        &user_logs_params,
+        params,
+        &headers,
+    )
+    .await
 }
 
-async fn get_user_logs_inner(
-    app: &App,
-    channel_id: &str,
-    user_id: &str,
-    log_params: LogRangeParams,
+pub async fn download_user_logs_by_name(
+    app: State<App>,
+    Path(path): Path<UserLogPathParams>,
+    query: Query<LogsParams>,
 ) -> Result<impl IntoApiResponse> {
-    let stream = read_user(&app.db, channel_id, user_id, log_params, &app.flush_buffer).await?;
+    let user_id = app.get_user_id_by_name(&path.user).await?;
+    download_user_logs(app, path, user_id, query).await
+}
 
-    let logs = LogsResponse {
-        stream,
-        response_type: log_params.logs_params.response_type(),
-    };
+pub async fn download_user_logs_by_id(
+    app: State<App>,
+    Path(path): Path<UserLogPathParams>,
+    query: Query<LogsParams>,
+) -> Result<impl IntoApiResponse> {
+    let user_id = path.user.clone();
+    download_user_logs(app, path, user_id, query).await
+}
+
+/// All of the user's logs in the channel as a plain text attachment
+async fn download_user_logs(
+    app: State<App>,
+    path: UserLogPathParams,
+    user_id: String,
+    Query(logs_params): Query<LogsParams>,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match path.channel_id_type {
+        ChannelIdType::Name => app.get_channel_id_by_name(&path.channel).await?,
+        ChannelIdType::Id => path.channel.clone(),
+    };
+
+    app.check_opted_out(&channel_id, Some(&user_id))?;
+
+    let params = LogRangeParams {
+        from: DateTime::UNIX_EPOCH,
+        to: Utc::now(),
+        logs_params,
+    };
+    let stream = read_user(&app.db, &channel_id, &[&user_id], params, &app.flush_buffer)
+        .await
+        .map_err(|err| match err {
+            Error::NotFound => Error::UserNotFound,
+            err => err,
+        })?;
+
+    let file_name = format!("{}-{}.txt", path.channel, path.user);
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_str(&format!("attachment; filename=\"{file_name}\""))
+            .map_err(|_| Error::InvalidParam("Invalid channel or user name".to_owned()))?,
+    );
+
+    let logs = LogsResponse {
+        stream,
+        response_type: LogsResponseType::Text(
+            logs_params.text_format,
+            logs_params.first_line_number(),
+        ),
+        anonymize: logs_params.anonymize,
+    };
+    Ok((response_headers, logs))
+}
+
+async fn get_user_logs_inner(
+    app: &App,
+    channel_id: &str,
+    user_id: &str,
+    user_logs_params: &UserLogsParams,
+    log_params: LogRangeParams,
+    headers: &HeaderMap,
+) -> Result<impl IntoApiResponse> {
+    check_range_months(app, log_params.from, log_params.to)?;
+
+    let mut user_ids = vec![user_id];
+    for extra_id in user_logs_params.ids() {
+        if !user_ids.contains(&extra_id) {
+            app.check_opted_out(channel_id, Some(extra_id))?;
+            user_ids.push(extra_id);
+        }
+    }
+
+    let stream = match read_user(
+        &app.db,
+        channel_id,
+        &user_ids,
+        log_params,
+        &app.flush_buffer,
+    )
+    .await
+    {
+        Ok(stream) => stream,
+        // Users who were logged in the channel before get an empty response
+        Err(Error::NotFound) => {
+            if has_user_logs(&app.db, channel_id, &user_ids).await? {
+                LogsStream::empty()
+            } else {
+                return Err(Error::UserNotFound);
+            }
+        }
+        Err(err) => return Err(err),
+    };
+
+    let stream = match user_logs_params.context_before {
+        Some(count) if count > 0 => {
+            with_context_before(
+                app,
+                channel_id,
+                stream,
+                count.min(MAX_CONTEXT_BEFORE),
+                log_params.logs_params.reverse,
+            )
+            .await?
+        }
+        _ => stream,
+    };
+
+    let response_type = log_params
+        .logs_params
+        .response_type(headers, app.config.default_response_formats.user)
+        .with_tags(user_logs_params.tags().map(str::to_owned).collect());
+
+    let logs = LogsResponse {
+        stream,
+        response_type,
+        anonymize: log_params.logs_params.anonymize,
+    };
 
     let cache = if Utc::now() < log_params.to {
         no_cache_header()
@@ -271,13 +1125,52 @@ async fn get_user_logs_inner(
     Ok((cache, logs))
 }
 
+/// Adds the channel lines sent right before the first messages of the stream
+async fn with_context_before(
+    app: &App,
+    channel_id: &str,
+    mut stream: LogsStream,
+    count: u64,
+    reverse: bool,
+) -> Result<LogsStream> {
+    let mut messages = Vec::new();
+    while let Some(chunk) = stream.try_next().await? {
+        messages.extend(chunk);
+        if messages.len() > CONTEXT_MAX_MESSAGES {
+            return Err(Error::InvalidParam(format!(
+                "contextBefore only works for up to {CONTEXT_MAX_MESSAGES} messages, use a shorter range or a limit"
+            )));
+        }
+    }
+    if reverse {
+        messages.reverse();
+    }
+
+    let contexts: Vec<_> = futures::stream::iter(messages.iter().map(|msg| {
+        read_channel_lines_before(&app.db, channel_id, msg.timestamp, count, &app.flush_buffer)
+    }))
+    .buffered(CONTEXT_QUERY_CONCURRENCY)
+    .try_collect()
+    .await?;
+
+    // Context lines can overlap with each other or with the user's own messages
+    messages.extend(contexts.into_iter().flatten());
+    messages.sort_by_cached_key(|msg| (msg.timestamp, msg.id()));
+    messages.dedup();
+
+    if reverse {
+        messages.reverse();
+    }
+    Ok(LogsStream::Provided(messages.into_iter()))
+}
+
 pub async fn list_available_logs(
     Query(AvailableLogsParams { user, channel }): Query<AvailableLogsParams>,
     app: State<App>,
 ) -> Result<impl IntoApiResponse> {
     let channel_id = match channel {
         ChannelParam::ChannelId(id) => id,
-        ChannelParam::Channel(name) => app.get_user_id_by_name(&name).await?,
+        ChannelParam::Channel(name) => app.get_channel_id_by_name(&name).await?,
     };
 
     let available_logs = if let Some(user) = user {
@@ -306,9 +1199,10 @@ pub async fn random_channel_line(
         channel,
     }): Path<LogsPathChannel>,
     Query(logs_params): Query<LogsParams>,
+    headers: HeaderMap,
 ) -> Result<impl IntoApiResponse> {
     let channel_id = match channel_id_type {
-        ChannelIdType::Name => app.get_user_id_by_name(&channel).await?,
+        ChannelIdType::Name => app.get_channel_id_by_name(&channel).await?,
         ChannelIdType::Id => channel,
     };
 
@@ -317,7 +1211,9 @@ pub async fn random_channel_line(
 
     let logs = LogsResponse {
         stream,
-        response_type: logs_params.response_type(),
+        response_type: logs_params
+            .response_type(&headers, app.config.default_response_formats.channel),
+        anonymize: logs_params.anonymize,
     };
     Ok((no_cache_header(), logs))
 }
@@ -330,9 +1226,10 @@ pub async fn random_user_line_by_name(
         user,
     }): Path<UserLogPathParams>,
     query: Query<LogsParams>,
+    headers: HeaderMap,
 ) -> Result<impl IntoApiResponse> {
     let user_id = app.get_user_id_by_name(&user).await?;
-    random_user_line(app, channel_id_type, channel, user_id, query).await
+    random_user_line(app, channel_id_type, channel, user_id, query, headers).await
 }
 
 pub async fn random_user_line_by_id(
@@ -343,8 +1240,9 @@ pub async fn random_user_line_by_id(
         user,
     }): Path<UserLogPathParams>,
     query: Query<LogsParams>,
+    headers: HeaderMap,
 ) -> Result<impl IntoApiResponse> {
-    random_user_line(app, channel_id_type, channel, user, query).await
+    random_user_line(app, channel_id_type, channel, user, query, headers).await
 }
 
 async fn random_user_line(
@@ -353,9 +1251,10 @@ async fn random_user_line(
     channel: String,
     user_id: String,
     Query(logs_params): Query<LogsParams>,
+    headers: HeaderMap,
 ) -> Result<impl IntoApiResponse> {
     let channel_id = match channel_id_type {
-        ChannelIdType::Name => app.get_user_id_by_name(&channel).await?,
+        ChannelIdType::Name => app.get_channel_id_by_name(&channel).await?,
         ChannelIdType::Id => channel,
     };
 
@@ -366,11 +1265,415 @@ async fn random_user_line(
 
     let logs = LogsResponse {
         stream,
-        response_type: logs_params.response_type(),
+        response_type: logs_params
+            .response_type(&headers, app.config.default_response_formats.user),
+        anonymize: logs_params.anonymize,
     };
     Ok((no_cache_header(), logs))
 }
 
+pub async fn user_nth_line_by_name(
+    app: State<App>,
+    Path(UserNthLinePath { user_path, n }): Path<UserNthLinePath>,
+    query: Query<LogsParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoApiResponse> {
+    let user_id = app.get_user_id_by_name(&user_path.user).await?;
+    user_nth_line(app, user_path, user_id, n, query, headers).await
+}
+
+pub async fn user_nth_line_by_id(
+    app: State<App>,
+    Path(UserNthLinePath { user_path, n }): Path<UserNthLinePath>,
+    query: Query<LogsParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoApiResponse> {
+    let user_id = user_path.user.clone();
+    user_nth_line(app, user_path, user_id, n, query, headers).await
+}
+
+async fn user_nth_line(
+    app: State<App>,
+    user_path: UserLogPathParams,
+    user_id: String,
+    n: String,
+    Query(logs_params): Query<LogsParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match user_path.channel_id_type {
+        ChannelIdType::Name => app.get_channel_id_by_name(&user_path.channel).await?,
+        ChannelIdType::Id => user_path.channel,
+    };
+
+    app.check_opted_out(&channel_id, Some(&user_id))?;
+
+    let position: i64 = n.parse()?;
+    let msg =
+        read_user_nth_line(&app.db, &channel_id, &user_id, position, &app.flush_buffer).await?;
+    let stream = LogsStream::new_provided(vec![msg])?;
+
+    let logs = LogsResponse {
+        stream,
+        response_type: logs_params
+            .response_type(&headers, app.config.default_response_formats.user),
+        anonymize: logs_params.anonymize,
+    };
+    Ok((no_cache_header(), logs))
+}
+
+pub async fn user_count_by_month_by_name(
+    app: State<App>,
+    Path(UserLogPathParams {
+        channel_id_type,
+        channel,
+        user,
+    }): Path<UserLogPathParams>,
+) -> Result<impl IntoApiResponse> {
+    let user_id = app.get_user_id_by_name(&user).await?;
+    user_count_by_month(app, channel_id_type, channel, user_id).await
+}
+
+pub async fn user_count_by_month_by_id(
+    app: State<App>,
+    Path(UserLogPathParams {
+        channel_id_type,
+        channel,
+        user,
+    }): Path<UserLogPathParams>,
+) -> Result<impl IntoApiResponse> {
+    user_count_by_month(app, channel_id_type, channel, user).await
+}
+
+async fn user_count_by_month(
+    app: State<App>,
+    channel_id_type: ChannelIdType,
+    channel: String,
+    user_id: String,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_channel_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    app.check_opted_out(&channel_id, Some(&user_id))?;
+
+    let counts = read_user_count_by_month(&app.db, &channel_id, &user_id).await?;
+    if counts.is_empty() {
+        return Err(Error::NotFound);
+    }
+
+    Ok((cache_header(600), Json(counts)))
+}
+
+pub async fn user_exists_by_name(
+    app: State<App>,
+    Path(UserLogPathParams {
+        channel_id_type,
+        channel,
+        user,
+    }): Path<UserLogPathParams>,
+) -> Result<impl IntoApiResponse> {
+    let user_id = app.get_user_id_by_name(&user).await?;
+    user_exists(app, channel_id_type, channel, user_id).await
+}
+
+pub async fn user_exists_by_id(
+    app: State<App>,
+    Path(UserLogPathParams {
+        channel_id_type,
+        channel,
+        user,
+    }): Path<UserLogPathParams>,
+) -> Result<impl IntoApiResponse> {
+    user_exists(app, channel_id_type, channel, user).await
+}
+
+async fn user_exists(
+    app: State<App>,
+    channel_id_type: ChannelIdType,
+    channel: String,
+    user_id: String,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_channel_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    app.check_opted_out(&channel_id, Some(&user_id))?;
+
+    let mut exists = has_user_logs(&app.db, &channel_id, &[&user_id]).await?;
+    if !exists {
+        exists = !app
+            .flush_buffer
+            .messages_by_channel_and_users(0..u64::MAX, &channel_id, &[&user_id])
+            .await
+            .is_empty();
+    }
+
+    Ok((cache_header(60), Json(UserExists { exists })))
+}
+
+pub async fn user_day_diff_by_name(
+    app: State<App>,
+    Path(UserLogPathParams {
+        channel_id_type,
+        channel,
+        user,
+    }): Path<UserLogPathParams>,
+    Query(params): Query<UserDayDiffParams>,
+) -> Result<impl IntoApiResponse> {
+    let user_id = app.get_user_id_by_name(&user).await?;
+    user_day_diff(app, channel_id_type, channel, user_id, params).await
+}
+
+pub async fn user_day_diff_by_id(
+    app: State<App>,
+    Path(UserLogPathParams {
+        channel_id_type,
+        channel,
+        user,
+    }): Path<UserLogPathParams>,
+    Query(params): Query<UserDayDiffParams>,
+) -> Result<impl IntoApiResponse> {
+    user_day_diff(app, channel_id_type, channel, user, params).await
+}
+
+async fn user_day_diff(
+    app: State<App>,
+    channel_id_type: ChannelIdType,
+    channel: String,
+    user_id: String,
+    UserDayDiffParams { a, b }: UserDayDiffParams,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_channel_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    app.check_opted_out(&channel_id, Some(&user_id))?;
+
+    let mut days = Vec::with_capacity(2);
+    let mut is_complete = true;
+    for day in [a, b] {
+        let (from, to) = named_day_range(&day, Tz::UTC, Utc::now())?;
+        let messages =
+            count_user_lines(&app.db, &channel_id, &user_id, from, to, &app.flush_buffer).await?;
+        is_complete &= to <= Utc::now();
+        days.push(UserDayActivity {
+            date: from.date_naive(),
+            messages,
+        });
+    }
+    let b = days.pop().unwrap();
+    let a = days.pop().unwrap();
+
+    let cache = if is_complete {
+        cache_header(36000)
+    } else {
+        no_cache_header()
+    };
+    Ok((cache, Json(UserDayDiff::new(a, b))))
+}
+
+pub async fn top_users(
+    app: State<App>,
+    Path(LogsPathChannel {
+        channel_id_type,
+        channel,
+    }): Path<LogsPathChannel>,
+    Query(TopUsersParams { month, limit }): Query<TopUsersParams>,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_channel_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    app.check_opted_out(&channel_id, None)?;
+
+    let (from, to) = month_range(month.as_deref())?;
+    let limit = limit.unwrap_or(10).min(100);
+
+    let counts = read_top_users(&app.db, &channel_id, from, to, limit, 0).await?;
+    let users = resolve_user_counts(&app, counts).await?;
+
+    let cache = if Utc::now() < to {
+        cache_header(600)
+    } else {
+        cache_header(36000)
+    };
+
+    Ok((cache, Json(TopUsers { users })))
+}
+
+/// Longest time range a ban summary can cover
+const MAX_BAN_SUMMARY_DAYS: i64 = 31;
+const DEFAULT_BAN_SUMMARY_WINDOW_SECONDS: u64 = 60;
+
+pub async fn ban_summary(
+    app: State<App>,
+    Path(LogsPathChannel {
+        channel_id_type,
+        channel,
+    }): Path<LogsPathChannel>,
+    Query(params): Query<BanSummaryParams>,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_channel_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    app.check_opted_out(&channel_id, None)?;
+
+    let to = params.to.unwrap_or_else(Utc::now);
+    let from = params.from.unwrap_or(to - chrono::Duration::days(1));
+    if to <= from {
+        return Err(Error::InvalidParam("to has to be after from".to_owned()));
+    }
+    if to - from > chrono::Duration::days(MAX_BAN_SUMMARY_DAYS) {
+        return Err(Error::TooLarge(format!(
+            "At most {MAX_BAN_SUMMARY_DAYS} days can be summarized at once"
+        )));
+    }
+
+    let window_seconds = params
+        .window_seconds
+        .unwrap_or(DEFAULT_BAN_SUMMARY_WINDOW_SECONDS)
+        .min(i64::MAX as u64 / 1000) as i64;
+
+    let bans = read_channel_bans(&app.db, &channel_id, from, to).await?;
+    let groups = group_bans(bans, chrono::Duration::seconds(window_seconds));
+
+    let cache = if Utc::now() < to {
+        no_cache_header()
+    } else {
+        cache_header(36000)
+    };
+
+    Ok((cache, Json(BanSummary { groups })))
+}
+
+pub async fn available_users(
+    app: State<App>,
+    Path(LogsPathChannel {
+        channel_id_type,
+        channel,
+    }): Path<LogsPathChannel>,
+    Query(AvailableUsersParams {
+        month,
+        limit,
+        offset,
+    }): Query<AvailableUsersParams>,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_channel_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    app.check_opted_out(&channel_id, None)?;
+
+    let (from, to) = month_range(month.as_deref())?;
+
+    let limit = limit
+        .unwrap_or(DEFAULT_AVAILABLE_USERS)
+        .min(MAX_AVAILABLE_USERS);
+
+    let counts = read_top_users(&app.db, &channel_id, from, to, limit, offset.unwrap_or(0)).await?;
+    let users = resolve_user_counts(&app, counts).await?;
+
+    let cache = if Utc::now() < to {
+        cache_header(600)
+    } else {
+        cache_header(36000)
+    };
+
+    Ok((cache, Json(TopUsers { users })))
+}
+
+/// Parses a `YYYY-MM` month (defaulting to the current one) into its time range
+fn month_range(month: Option<&str>) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+    let from = match month {
+        Some(month) => NaiveDate::parse_from_str(&format!("{month}-01"), "%Y-%m-%d")
+            .map_err(|_| Error::InvalidDate(month.to_owned()))?,
+        None => Utc::now().date_naive().with_day(1).unwrap(),
+    }
+    .and_time(NaiveTime::default())
+    .and_utc();
+    let to = from
+        .checked_add_months(Months::new(1))
+        .ok_or_else(|| Error::InvalidDate("Date out of range".to_owned()))?;
+
+    Ok((from, to))
+}
+
+/// Drops opted out users and resolves the names of the rest
+async fn resolve_user_counts(app: &App, counts: Vec<(String, u64)>) -> Result<Vec<TopUser>> {
+    let counts: Vec<_> = counts
+        .into_iter()
+        .filter(|(user_id, _)| !app.config.opt_out.contains_key(user_id))
+        .collect();
+    if counts.is_empty() {
+        return Err(Error::NotFound);
+    }
+
+    let user_ids = counts.iter().map(|(user_id, _)| user_id.clone()).collect();
+    let mut names = app.get_users(user_ids, vec![], false).await?;
+
+    Ok(counts
+        .into_iter()
+        .map(|(user_id, count)| TopUser {
+            name: names.remove(&user_id),
+            user_id,
+            count,
+        })
+        .collect())
+}
+
+pub async fn recent_messages(
+    app: State<App>,
+    Path(RecentMessagesPath { channel }): Path<RecentMessagesPath>,
+    Query(RecentMessagesParams { limit }): Query<RecentMessagesParams>,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = app.get_channel_id_by_name(&channel).await?;
+    app.check_opted_out(&channel_id, None)?;
+
+    let to = Utc::now();
+    let params = LogRangeParams {
+        from: to - chrono::Duration::hours(RECENT_MESSAGES_MAX_AGE_HOURS),
+        to,
+        logs_params: LogsParams {
+            reverse: true,
+            limit: Some(
+                limit
+                    .unwrap_or(RECENT_MESSAGES_MAX_COUNT)
+                    .min(RECENT_MESSAGES_MAX_COUNT),
+            ),
+            ..Default::default()
+        },
+    };
+
+    let mut messages = match read_channel(&app.db, &channel_id, params, &app.flush_buffer).await {
+        Ok(stream) => stream
+            .try_collect::<Vec<_>>()
+            .await?
+            .into_iter()
+            .flatten()
+            .map(|msg| msg.to_raw_irc())
+            .collect(),
+        Err(Error::NotFound) => Vec::new(),
+        Err(err) => return Err(err),
+    };
+    messages.reverse();
+
+    Ok((
+        no_cache_header(),
+        Json(RecentMessages {
+            messages,
+            error: None,
+            error_code: None,
+        }),
+    ))
+}
+
 pub async fn search_user_logs_by_name(
     app: State<App>,
     Path(UserLogPathParams {
@@ -379,9 +1682,10 @@ pub async fn search_user_logs_by_name(
         user,
     }): Path<UserLogPathParams>,
     params: Query<SearchParams>,
+    headers: HeaderMap,
 ) -> Result<impl IntoApiResponse> {
     let user_id = app.get_user_id_by_name(&user).await?;
-    search_user_logs(app, channel_id_type, channel, user_id, params).await
+    search_user_logs(app, channel_id_type, channel, user_id, params, headers).await
 }
 
 pub async fn search_user_logs_by_id(
@@ -392,8 +1696,9 @@ pub async fn search_user_logs_by_id(
         user,
     }): Path<UserLogPathParams>,
     params: Query<SearchParams>,
+    headers: HeaderMap,
 ) -> Result<impl IntoApiResponse> {
-    search_user_logs(app, channel_id_type, channel, user, params).await
+    search_user_logs(app, channel_id_type, channel, user, params, headers).await
 }
 
 async fn search_user_logs(
@@ -402,30 +1707,141 @@ async fn search_user_logs(
     channel: String,
     user_id: String,
     params: Query<SearchParams>,
+    headers: HeaderMap,
 ) -> Result<impl IntoApiResponse> {
     let channel_id = match channel_id_type {
-        ChannelIdType::Name => app.get_user_id_by_name(&channel).await?,
+        ChannelIdType::Name => app.get_channel_id_by_name(&channel).await?,
         ChannelIdType::Id => channel,
     };
 
     app.check_opted_out(&channel_id, Some(&user_id))?;
+    check_range_months(
+        &app,
+        params.from.unwrap_or(DateTime::UNIX_EPOCH),
+        params.to.unwrap_or_else(Utc::now),
+    )?;
 
-    let stream = db::search_user_logs(
-        &app.db,
-        &channel_id,
-        &user_id,
-        &params.q,
-        params.logs_params,
-    )
-    .await?;
+    let stream = db::search_user_logs(&app.db, &channel_id, &user_id, &params).await?;
 
     let logs = LogsResponse {
         stream,
-        response_type: params.logs_params.response_type(),
+        response_type: params
+            .logs_params
+            .response_type(&headers, app.config.default_response_formats.user),
+        anonymize: params.logs_params.anonymize,
     };
     Ok(logs)
 }
 
+pub async fn user_bans_by_name(
+    app: State<App>,
+    Path(UserPath { user }): Path<UserPath>,
+    Query(params): Query<UserBansParams>,
+) -> Result<impl IntoApiResponse> {
+    let user_id = app.get_user_id_by_name(&user).await?;
+    user_bans(app, user_id, params).await
+}
+
+pub async fn user_bans_by_id(
+    app: State<App>,
+    Path(UserPath { user }): Path<UserPath>,
+    Query(params): Query<UserBansParams>,
+) -> Result<impl IntoApiResponse> {
+    user_bans(app, user, params).await
+}
+
+async fn user_bans(
+    app: State<App>,
+    user_id: String,
+    params: UserBansParams,
+) -> Result<impl IntoApiResponse> {
+    if app.config.opt_out.contains_key(&user_id) {
+        return Err(Error::UserOptedOut);
+    }
+
+    let channel_ids = logged_channel_ids(&app);
+    let limit = params.limit.unwrap_or(DEFAULT_USER_BANS).min(MAX_USER_BANS);
+    let bans = read_user_bans(
+        &app.db,
+        &channel_ids,
+        &user_id,
+        limit,
+        params.offset.unwrap_or(0),
+    )
+    .await?;
+
+    Ok((cache_header(600), Json(UserBans { bans })))
+}
+
+/// Ids of the logged channels which are not opted out, sorted
+fn logged_channel_ids(app: &App) -> Vec<String> {
+    let mut channel_ids: Vec<String> = app
+        .config
+        .channels
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|channel_id| !app.config.opt_out.contains_key(*channel_id))
+        .cloned()
+        .collect();
+    channel_ids.sort_unstable();
+    channel_ids
+}
+
+pub async fn user_channels_by_name(
+    app: State<App>,
+    Path(UserPath { user }): Path<UserPath>,
+) -> Result<impl IntoApiResponse> {
+    let user_id = app.get_user_id_by_name(&user).await?;
+    user_channels(app, user_id).await
+}
+
+pub async fn user_channels_by_id(
+    app: State<App>,
+    Path(UserPath { user }): Path<UserPath>,
+) -> Result<impl IntoApiResponse> {
+    user_channels(app, user).await
+}
+
+async fn user_channels(app: State<App>, user_id: String) -> Result<impl IntoApiResponse> {
+    if app.config.opt_out.contains_key(&user_id) {
+        return Err(Error::UserOptedOut);
+    }
+
+    // A lookup per channel only reads the (channel_id, user_id) prefix of the primary key
+    let db = &app.db;
+    let user_ids = [user_id.as_str()];
+    let user_ids = &user_ids;
+    let channel_logs: Vec<(String, bool)> = futures::stream::iter(
+        logged_channel_ids(&app)
+            .into_iter()
+            .map(|channel_id| async move {
+                let has_logs = has_user_logs(db, &channel_id, user_ids).await?;
+                Ok::<_, Error>((channel_id, has_logs))
+            }),
+    )
+    .buffered(USER_CHANNELS_QUERY_CONCURRENCY)
+    .try_collect()
+    .await?;
+    let channel_ids: Vec<String> = channel_logs
+        .into_iter()
+        .filter(|(_, has_logs)| *has_logs)
+        .map(|(channel_id, _)| channel_id)
+        .collect();
+    let mut names = app.get_users(channel_ids.clone(), vec![], false).await?;
+
+    let json = Json(ChannelsList {
+        channels: channel_ids
+            .into_iter()
+            .filter_map(|user_id| {
+                let name = names.remove(&user_id)?;
+                Some(Channel { name, user_id })
+            })
+            .collect(),
+    });
+    Ok((cache_header(600), json))
+}
+
 pub async fn optout(app: State<App>) -> Json<String> {
     let mut rng = thread_rng();
     let optout_code: String = (0..5).map(|_| rng.sample(Alphanumeric) as char).collect();
@@ -446,6 +1862,92 @@ pub async fn optout(app: State<App>) -> Json<String> {
     Json(optout_code)
 }
 
+/// Rejects ranges longer than the configured maximum amount of months
+fn check_range_months(app: &App, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<()> {
+    let Some(max_months) = app.config.max_range_months else {
+        return Ok(());
+    };
+
+    let max_to = from.checked_add_months(Months::new(max_months));
+    if max_to.is_some_and(|max_to| to <= max_to) {
+        Ok(())
+    } else {
+        Err(Error::InvalidParam(format!(
+            "The requested range is longer than {max_months} months, which is the most that can be requested at once. Narrow it down with the from and to params, or request the logs month by month"
+        )))
+    }
+}
+
+/// Range of a day given as `today`, `yesterday` or a `YYYY-MM-DD` date (UTC)
+fn parse_timezone(timezone: Option<&str>) -> Result<Tz> {
+    match timezone {
+        Some(timezone) => timezone
+            .parse()
+            .map_err(|_| Error::InvalidParam(format!("Unknown timezone {timezone}"))),
+        None => Ok(Tz::UTC),
+    }
+}
+
+/// Range of the day in the timezone. `today` and `yesterday` are relative to `now`
+fn named_day_range(
+    day: &str,
+    timezone: Tz,
+    now: DateTime<Utc>,
+) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+    let today = now.with_timezone(&timezone).date_naive();
+    let date = match day {
+        "today" => today,
+        "yesterday" => today
+            .checked_sub_days(Days::new(1))
+            .ok_or_else(|| Error::InvalidDate("Date out of range".to_owned()))?,
+        _ => NaiveDate::parse_from_str(day, "%Y-%m-%d")
+            .map_err(|_| Error::InvalidDate(day.to_owned()))?,
+    };
+    let next_date = date
+        .checked_add_days(Days::new(1))
+        .ok_or_else(|| Error::InvalidDate("Date out of range".to_owned()))?;
+
+    Ok((
+        start_of_day(date, timezone)?,
+        start_of_day(next_date, timezone)?,
+    ))
+}
+
+/// Midnight does not exist in some timezones on days where the clocks are moved forward,
+/// in which case the day starts with the first full hour
+fn start_of_day(date: NaiveDate, timezone: Tz) -> Result<DateTime<Utc>> {
+    (0..24)
+        .find_map(|hour| {
+            date.and_hms_opt(hour, 0, 0)?
+                .and_local_timezone(timezone)
+                .earliest()
+        })
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(|| Error::InvalidDate("Date out of range".to_owned()))
+}
+
+fn day_range(date: &LogsPathDate) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+    let from = NaiveDate::from_ymd_opt(
+        parse_date_part(&date.year)?,
+        parse_date_part(&date.month)?,
+        parse_date_part(&date.day)?,
+    )
+    .ok_or_else(|| Error::InvalidDate("Invalid date".to_owned()))?
+    .and_time(NaiveTime::default())
+    .and_utc();
+    let to = from
+        .checked_add_days(Days::new(1))
+        .ok_or_else(|| Error::InvalidDate("Date out of range".to_owned()))?;
+
+    Ok((from, to))
+}
+
+fn parse_date_part<T: FromStr>(value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| Error::InvalidDate(value.to_owned()))
+}
+
 fn cache_header(secs: u64) -> TypedHeader<CacheControl> {
     TypedHeader(
         CacheControl::new()
@@ -457,3 +1959,54 @@ fn cache_header(secs: u64) -> TypedHeader<CacheControl> {
 pub fn no_cache_header() -> TypedHeader<CacheControl> {
     TypedHeader(CacheControl::new().with_no_cache())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::named_day_range;
+    use chrono::{DateTime, Utc};
+    use chrono_tz::Tz;
+    use pretty_assertions::assert_eq;
+
+    fn time(value: &str) -> DateTime<Utc> {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn resolve_named_days() {
+        let now = time("2023-06-16T20:25:17Z");
+
+        assert_eq!(
+            (time("2023-06-16T00:00:00Z"), time("2023-06-17T00:00:00Z")),
+            named_day_range("today", Tz::UTC, now).unwrap()
+        );
+        assert_eq!(
+            (time("2023-06-15T00:00:00Z"), time("2023-06-16T00:00:00Z")),
+            named_day_range("yesterday", Tz::UTC, now).unwrap()
+        );
+        assert_eq!(
+            (time("2023-01-02T00:00:00Z"), time("2023-01-03T00:00:00Z")),
+            named_day_range("2023-01-02", Tz::UTC, now).unwrap()
+        );
+        assert!(named_day_range("tomorrow", Tz::UTC, now).is_err());
+    }
+
+    #[test]
+    fn resolve_named_days_in_timezone() {
+        // Already the next day in Berlin
+        let now = time("2023-06-16T23:30:00Z");
+
+        assert_eq!(
+            (time("2023-06-16T22:00:00Z"), time("2023-06-17T22:00:00Z")),
+            named_day_range("today", Tz::Europe__Berlin, now).unwrap()
+        );
+        assert_eq!(
+            (time("2023-06-15T22:00:00Z"), time("2023-06-16T22:00:00Z")),
+            named_day_range("yesterday", Tz::Europe__Berlin, now).unwrap()
+        );
+        // The clocks are moved forward, so the day is 23 hours long
+        assert_eq!(
+            (time("2023-03-25T23:00:00Z"), time("2023-03-26T22:00:00Z")),
+            named_day_range("2023-03-26", Tz::Europe__Berlin, now).unwrap()
+        );
+    }
+}
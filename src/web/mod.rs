@@ -1,6 +1,13 @@
 mod admin;
+mod ban_summary;
+mod checksum;
+mod concurrency_limit;
+mod delta_cursor;
+mod error_pages;
 mod frontend;
 mod handlers;
+mod permalink;
+mod request_timeout;
 mod responders;
 pub mod schema;
 mod trace_layer;
@@ -27,8 +34,12 @@ use std::{
     net::{AddrParseError, SocketAddr},
     str::FromStr,
     sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc::Sender, Semaphore},
 };
-use tokio::{net::TcpListener, sync::mpsc::Sender};
 use tower_http::{
     compression::CompressionLayer, cors::CorsLayer, normalize_path::NormalizePath,
     trace::TraceLayer, CompressionLevel,
@@ -49,8 +60,23 @@ pub async fn run(app: App, mut shutdown_rx: ShutdownRx, bot_tx: Sender<BotMessag
     let listen_address =
         parse_listen_addr(&app.config.listen_address).expect("Invalid listen address");
 
+    responders::logs::init_serialization_pool(app.config.serialization_threads)
+        .expect("Could not create serialization thread pool");
+
     let cors = CorsLayer::permissive();
 
+    let compress = app.config.response_compression;
+    let compression_level = app
+        .config
+        .response_compression_quality
+        .map_or(CompressionLevel::Fastest, CompressionLevel::Precise);
+    let compression = CompressionLayer::new()
+        .quality(compression_level)
+        .gzip(compress)
+        .deflate(compress)
+        .br(compress)
+        .zstd(compress);
+
     let mut api = OpenApi::default();
 
     let admin_routes = ApiRouter::new()
@@ -65,23 +91,59 @@ pub async fn run(app: App, mut shutdown_rx: ShutdownRx, bot_tx: Sender<BotMessag
                 op.tag("Admin").description("Leave the specified channels")
             }),
         )
-        .route_layer(middleware::from_fn_with_state(app.clone(), admin_auth))
-        .layer(Extension(bot_tx));
-
-    let app = ApiRouter::new()
-        .nest("/admin", admin_routes)
         .api_route(
-            "/channels",
-            get_with(handlers::get_channels, |op| {
-                op.description("List logged channels")
+            "/channel-aliases",
+            post_with(admin::add_channel_alias, |mut op| {
+                admin::admin_auth_doc(&mut op);
+                op.tag("Admin")
+                    .description("Serve the logs of a channel under an additional name")
+            })
+            .delete_with(admin::remove_channel_alias, |mut op| {
+                admin::admin_auth_doc(&mut op);
+                op.tag("Admin").description("Remove a channel alias")
             }),
         )
         .api_route(
-            "/list",
-            get_with(handlers::list_available_logs, |op| {
-                op.description("List available logs")
+            "/permalinks",
+            post_with(admin::create_permalink, |mut op| {
+                admin::admin_auth_doc(&mut op);
+                op.tag("Admin").description(
+                    "Create a signed, expiring permalink to a single line in a channel's logs",
+                )
             }),
         )
+        .api_route(
+            "/reimport",
+            post_with(admin::reimport_day, |mut op| {
+                admin::admin_auth_doc(&mut op);
+                op.tag("Admin").description(
+                    "Replace the stored logs of a channel on a single day with the ones from a justlog logs folder",
+                )
+            }),
+        )
+        .api_route(
+            "/reload",
+            post_with(admin::reload_config, |mut op| {
+                admin::admin_auth_doc(&mut op);
+                op.tag("Admin").description(
+                    "Re-read the config file and apply the changed settings which don't need a restart",
+                )
+            }),
+        )
+        .api_route(
+            "/stats",
+            get_with(admin::get_stats, |mut op| {
+                admin::admin_auth_doc(&mut op);
+                op.tag("Admin").description(
+                    "Get runtime statistics: uptime, logged channels and messages, caches and storage size",
+                )
+            }),
+        )
+        .route_layer(middleware::from_fn_with_state(app.clone(), admin_auth))
+        .layer(Extension(bot_tx));
+
+    // Routes which read potentially large amounts of logs
+    let log_routes = ApiRouter::new()
         .api_route(
             "/:channel_id_type/:channel",
             get_with(handlers::get_channel_logs, |op| {
@@ -105,6 +167,16 @@ pub async fn run(app: App, mut shutdown_rx: ShutdownRx, bot_tx: Sender<BotMessag
             "/:channel_id_type/:channel/:year/:month/:day",
             get_with(handlers::get_channel_logs_by_date, |op| {
                 op.description("Get channel logs from the given day")
+            })
+            .head_with(handlers::head_channel_logs_by_date, |op| {
+                op.description("Get the amount of lines in the channel logs of the given day (`X-Line-Count` header) without the logs")
+            }),
+        )
+        .api_route(
+            // The day is named `year`, as the router requires parameters at the same position to have the same name
+            "/:channel_id_type/:channel/:year",
+            get_with(handlers::get_channel_logs_by_day, |op| {
+                op.description("Get channel logs from the given day. The day can be `today`, `yesterday` or a `YYYY-MM-DD` date, in the timezone of the `timezone` param (UTC by default)")
             }),
         )
         .api_route(
@@ -119,6 +191,116 @@ pub async fn run(app: App, mut shutdown_rx: ShutdownRx, bot_tx: Sender<BotMessag
                 op.description("Get user logs in a channel from the given month")
             }),
         )
+        .api_route(
+            "/:channel_id_type/:channel/user/:user/download",
+            get_with(handlers::download_user_logs_by_name, |op| {
+                op.description("Download all of the user's logs in a channel as a text file. The `textFormat` param sets how lines are rendered")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/userid/:user/download",
+            get_with(handlers::download_user_logs_by_id, |op| {
+                op.description("Download all of the user's logs in a channel as a text file. The `textFormat` param sets how lines are rendered")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/user/:user/search",
+            get_with(handlers::search_user_logs_by_name, |op| {
+                op.description("Search user logs using the provided text, regex, message type and time range filters")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/userid/:user/search",
+            get_with(handlers::search_user_logs_by_id, |op| {
+                op.description("Search user logs using the provided text, regex, message type and time range filters")
+            }),
+        )
+        .api_route(
+            "/api/v2/recent-messages/:channel",
+            get_with(handlers::recent_messages, |op| {
+                op.description("Get the latest messages in a channel from the last 24 hours, in the format of the recent-messages service. Can be used as a recent messages source in Chatterino")
+            }),
+        );
+    let log_routes = log_routes.route_layer(middleware::from_fn(checksum::add_checksum));
+    // Applied first, so that the time spent waiting for a free slot is not counted
+    let log_routes = match app.config.log_request_timeout_seconds {
+        Some(seconds) => log_routes.route_layer(middleware::from_fn_with_state(
+            Duration::from_secs(seconds),
+            request_timeout::limit_duration,
+        )),
+        None => log_routes,
+    };
+    let log_routes = match app.config.max_concurrent_log_requests {
+        Some(max_requests) => log_routes.route_layer(middleware::from_fn_with_state(
+            Arc::new(Semaphore::new(max_requests)),
+            concurrency_limit::limit_concurrency,
+        )),
+        None => log_routes,
+    };
+
+    let app = ApiRouter::new()
+        .nest("/admin", admin_routes)
+        .merge(log_routes)
+        .api_route(
+            "/channels",
+            get_with(handlers::get_channels, |op| {
+                op.description("List logged channels")
+            }),
+        )
+        .api_route(
+            "/list",
+            get_with(handlers::list_available_logs, |op| {
+                op.description("List available logs")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/:year/:month/:day/between",
+            get_with(handlers::get_channel_lines_between, |op| {
+                op.description("Get the lines from `startLine` up to (not including) `endLine` of the channel logs of the given day. Line numbers start at 1, the `X-Line-Count` header has the amount of lines of the day")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/:year/:month/:day/count",
+            get_with(handlers::get_channel_line_count_by_date, |op| {
+                op.description("Get the amount of lines in the channel logs of the given day")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/:year/:month/:day/info",
+            get_with(handlers::get_channel_logs_info_by_date, |op| {
+                op.description("Get how the channel logs of the given day are stored: schema version, indexes, line count and the size of the containing partition")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/:year/:month/:day/line/:line",
+            get_with(handlers::get_channel_line_by_date, |op| {
+                op.description("Get a single line from the channel logs of the given day. Line numbers start at 1")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/message/:message_id",
+            get_with(handlers::get_channel_message_by_id, |op| {
+                op.description("Get a single message by its Twitch message id")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/delta",
+            get_with(handlers::get_channel_logs_delta, |op| {
+                op.description("Get the channel's lines following the position of a cursor. The cursor for the next request is returned in the `X-Cursor` header")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/tail",
+            get_with(handlers::tail_channel_logs, |op| {
+                op.description("Stream the channel's latest lines and then every new line as server-sent events with the JSON message as data. The event ids can be sent as `Last-Event-ID` to resume after reconnecting")
+            }),
+        )
+        .api_route(
+            "/permalink/:token",
+            get_with(handlers::get_permalink_line, |op| {
+                op.description("Get the log line referenced by a permalink")
+            }),
+        )
         .api_route(
             "/:channel_id_type/:channel/random",
             get_with(handlers::random_channel_line, |op| {
@@ -138,15 +320,99 @@ pub async fn run(app: App, mut shutdown_rx: ShutdownRx, bot_tx: Sender<BotMessag
             }),
         )
         .api_route(
-            "/:channel_id_type/:channel/user/:user/search",
-            get_with(handlers::search_user_logs_by_name, |op| {
-                op.description("Search user logs using the provided query")
+            "/:channel_id_type/:channel/userid/:user/nth/:n",
+            get_with(handlers::user_nth_line_by_id, |op| {
+                op.description("Get the n-th line of the user's logs in a channel, counting from 1. Negative numbers count back from the latest line, e.g. -1 for the latest")
             }),
         )
         .api_route(
-            "/:channel_id_type/:channel/userid/:user/search",
-            get_with(handlers::search_user_logs_by_id, |op| {
-                op.description("Search user logs using the provided query")
+            "/:channel_id_type/:channel/user/:user/nth/:n",
+            get_with(handlers::user_nth_line_by_name, |op| {
+                op.description("Get the n-th line of the user's logs in a channel, counting from 1. Negative numbers count back from the latest line, e.g. -1 for the latest")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/user/:user/diff",
+            get_with(handlers::user_day_diff_by_name, |op| {
+                op.description("Compare the amount of messages a user sent in a channel on two days")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/userid/:user/diff",
+            get_with(handlers::user_day_diff_by_id, |op| {
+                op.description("Compare the amount of messages a user sent in a channel on two days")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/user/:user/exists",
+            get_with(handlers::user_exists_by_name, |op| {
+                op.description("Check whether there are any logs of a user in a channel")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/userid/:user/exists",
+            get_with(handlers::user_exists_by_id, |op| {
+                op.description("Check whether there are any logs of a user in a channel")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/top-users",
+            get_with(handlers::top_users, |op| {
+                op.description(
+                    "Get the users who sent the most messages in a channel in the given month",
+                )
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/ban-summary",
+            get_with(handlers::ban_summary, |op| {
+                op.description(
+                    "Get the bans and timeouts in a channel, with bans following each other closely grouped together",
+                )
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/available-users",
+            get_with(handlers::available_users, |op| {
+                op.description(
+                    "Get the users who sent messages in a channel in the given month, with their message counts, starting with the most active ones",
+                )
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/user/:user/count-by-month",
+            get_with(handlers::user_count_by_month_by_name, |op| {
+                op.description("Get the amount of messages a user sent in a channel in every month")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/userid/:user/count-by-month",
+            get_with(handlers::user_count_by_month_by_id, |op| {
+                op.description("Get the amount of messages a user sent in a channel in every month")
+            }),
+        )
+        .api_route(
+            "/user/:user/bans",
+            get_with(handlers::user_bans_by_name, |op| {
+                op.description("Get the bans and timeouts of a user in all logged channels")
+            }),
+        )
+        .api_route(
+            "/userid/:user/bans",
+            get_with(handlers::user_bans_by_id, |op| {
+                op.description("Get the bans and timeouts of a user in all logged channels")
+            }),
+        )
+        .api_route(
+            "/user/:user/channels",
+            get_with(handlers::user_channels_by_name, |op| {
+                op.description("List the logged channels the user has sent messages in")
+            }),
+        )
+        .api_route(
+            "/userid/:user/channels",
+            get_with(handlers::user_channels_by_id, |op| {
+                op.description("List the logged channels the user has sent messages in")
             }),
         )
         //.api_route("/optout", post(handlers::optout))
@@ -155,6 +421,10 @@ pub async fn run(app: App, mut shutdown_rx: ShutdownRx, bot_tx: Sender<BotMessag
         .route("/openapi.json", get(serve_openapi))
         .route("/assets/*asset", get(frontend::static_asset))
         .fallback(frontend::static_asset)
+        .layer(middleware::from_fn_with_state(
+            app.clone(),
+            error_pages::negotiate_error_format,
+        ))
         .layer(middleware::from_fn(capabilities_header_middleware))
         .layer(
             TraceLayer::new_for_http()
@@ -171,10 +441,10 @@ pub async fn run(app: App, mut shutdown_rx: ShutdownRx, bot_tx: Sender<BotMessag
         .layer(Extension(Arc::new(api)))
         .with_state(app)
         .layer(cors)
-        .layer(CompressionLayer::new().quality(CompressionLevel::Fastest));
+        .layer(compression);
     let app = NormalizePath::trim_trailing_slash(app);
 
-    info!("Listening on {listen_address}");
+    info!(%listen_address, "Listening");
 
     let listener = TcpListener::bind(&listen_address)
         .await
@@ -0,0 +1,116 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::NaiveDate;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A signed reference to a single line in a channel's logs
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Permalink {
+    pub channel_id: String,
+    pub date: NaiveDate,
+    /// Line number in the day's logs, starting at 1
+    pub line: u64,
+    /// Unix timestamp (in seconds) after which the permalink is no longer valid
+    pub expires_at: i64,
+}
+
+impl Permalink {
+    pub fn sign(&self, secret: &str) -> String {
+        let payload = serde_json::to_vec(self).expect("Permalinks can be serialized");
+
+        let mut mac = new_mac(secret);
+        mac.update(&payload);
+        let signature = mac.finalize().into_bytes();
+
+        format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(payload),
+            URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+
+    /// Returns the permalink if the token was signed with the given secret and has not expired at `now`
+    pub fn verify(token: &str, secret: &str, now: i64) -> Option<Self> {
+        let (payload, signature) = token.split_once('.')?;
+        let payload = URL_SAFE_NO_PAD.decode(payload).ok()?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+
+        let mut mac = new_mac(secret);
+        mac.update(&payload);
+        mac.verify_slice(&signature).ok()?;
+
+        let permalink: Self = serde_json::from_slice(&payload).ok()?;
+        if permalink.expires_at < now {
+            return None;
+        }
+
+        Some(permalink)
+    }
+}
+
+fn new_mac(secret: &str) -> HmacSha256 {
+    HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Permalink;
+    use chrono::NaiveDate;
+    use pretty_assertions::assert_eq;
+
+    fn permalink() -> Permalink {
+        Permalink {
+            channel_id: "22484632".to_owned(),
+            date: NaiveDate::from_ymd_opt(2023, 6, 16).unwrap(),
+            line: 42,
+            expires_at: 1_700_000_000,
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let token = permalink().sign("secret");
+        assert_eq!(
+            Some(permalink()),
+            Permalink::verify(&token, "secret", 1_600_000_000)
+        );
+    }
+
+    #[test]
+    fn reject_wrong_secret() {
+        let token = permalink().sign("secret");
+        assert_eq!(None, Permalink::verify(&token, "other", 1_600_000_000));
+    }
+
+    #[test]
+    fn reject_expired() {
+        let token = permalink().sign("secret");
+        assert_eq!(None, Permalink::verify(&token, "secret", 1_800_000_000));
+    }
+
+    #[test]
+    fn reject_tampered_payload() {
+        let token = permalink().sign("secret");
+        let (_, signature) = token.split_once('.').unwrap();
+
+        let tampered = Permalink {
+            line: 43,
+            ..permalink()
+        }
+        .sign("secret");
+        let (tampered_payload, _) = tampered.split_once('.').unwrap();
+
+        assert_eq!(
+            None,
+            Permalink::verify(
+                &format!("{tampered_payload}.{signature}"),
+                "secret",
+                1_600_000_000
+            )
+        );
+    }
+}
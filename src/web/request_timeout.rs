@@ -0,0 +1,51 @@
+use crate::error::Error;
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use futures::{stream, FutureExt, StreamExt};
+use std::{task::Poll, time::Duration};
+use tokio::time::{sleep_until, timeout_at, Instant};
+use tracing::warn;
+
+/// Cancels requests which take longer than the given duration.
+/// If the response has already started, its body is cut off, as the status can't be changed anymore
+pub async fn limit_duration(
+    State(limit): State<Duration>,
+    request: Request,
+    next: Next,
+) -> Result<Response, Error> {
+    let deadline = Instant::now() + limit;
+    let uri = request.uri().clone();
+
+    let response = match timeout_at(deadline, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!(%uri, "Request timed out before responding");
+            return Err(Error::Timeout);
+        }
+    };
+
+    let (parts, body) = response.into_parts();
+    let mut body = body.into_data_stream();
+    let mut expiry = Box::pin(sleep_until(deadline));
+    let mut expired = false;
+
+    let body = stream::poll_fn(move |cx| {
+        if expired {
+            return Poll::Ready(None);
+        }
+        if expiry.poll_unpin(cx).is_ready() {
+            warn!(%uri, "Request timed out while streaming the response");
+            expired = true;
+            // Dropping the inner body stops reading and serializing the logs
+            body = Body::empty().into_data_stream();
+            return Poll::Ready(Some(Err(axum::Error::new(Error::Timeout))));
+        }
+        body.poll_next_unpin(cx)
+    });
+
+    Ok(Response::from_parts(parts, Body::from_stream(body)))
+}
@@ -0,0 +1,132 @@
+use crate::{db::schema::StructuredMessage, logs::stream::LogsStream, Result};
+use futures::{stream::TryChunks, Future, Stream, StreamExt, TryStreamExt};
+use std::{
+    borrow::Cow,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::pin;
+
+const CHUNK_SIZE: usize = 3000;
+const BASE_COLUMNS: [&str; 5] = ["timestamp", "channel", "username", "userID", "text"];
+
+pub struct CsvLogsStream {
+    inner: TryChunks<LogsStream>,
+    tags: Vec<String>,
+    header_written: bool,
+}
+
+impl CsvLogsStream {
+    pub fn new(stream: LogsStream, tags: Vec<String>) -> Self {
+        let inner = stream.try_chunks(CHUNK_SIZE);
+        Self {
+            inner,
+            tags,
+            header_written: false,
+        }
+    }
+
+    fn header(&self) -> String {
+        let columns = BASE_COLUMNS
+            .iter()
+            .copied()
+            .chain(self.tags.iter().map(String::as_str));
+        let mut output = String::new();
+        write_row(&mut output, columns);
+        output
+    }
+
+    fn write_message(&self, output: &mut String, msg: &StructuredMessage) {
+        let timestamp = chrono::DateTime::from_timestamp_millis(msg.timestamp as i64)
+            .unwrap_or_default()
+            .to_rfc3339();
+        let text = msg.user_friendly_text();
+
+        let all_tags = if self.tags.is_empty() {
+            vec![]
+        } else {
+            msg.all_tags(false)
+        };
+        let tag_values = self.tags.iter().map(|name| {
+            all_tags
+                .iter()
+                .find(|(tag, _)| tag.as_str() == name)
+                .map(|(_, value)| value.as_ref())
+                .unwrap_or_default()
+        });
+
+        let columns = [
+            timestamp.as_str(),
+            msg.channel_login.as_ref(),
+            msg.user_login.as_ref(),
+            msg.user_id.as_ref(),
+            text.as_ref(),
+        ]
+        .into_iter()
+        .chain(tag_values);
+        write_row(output, columns);
+    }
+}
+
+impl Stream for CsvLogsStream {
+    type Item = Result<String>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let fut = self.inner.next();
+        pin!(fut);
+
+        let poll = fut.poll(cx);
+        poll.map(|item| {
+            let mut output = String::new();
+            if !self.header_written {
+                output.push_str(&self.header());
+                self.header_written = true;
+            }
+
+            match item {
+                Some(Ok(chunk)) => {
+                    output.reserve(chunk.len() * 64);
+                    for msg in chunk.into_iter().flatten() {
+                        self.write_message(&mut output, &msg);
+                    }
+                    Some(Ok(output))
+                }
+                Some(Err(err)) => Some(Err(err.1)),
+                // Empty logs still get the header
+                None if !output.is_empty() => Some(Ok(output)),
+                None => None,
+            }
+        })
+    }
+}
+
+fn write_row<'a>(output: &mut String, columns: impl Iterator<Item = &'a str>) {
+    for (i, column) in columns.enumerate() {
+        if i > 0 {
+            output.push(',');
+        }
+        output.push_str(&escape_field(column));
+    }
+    output.push_str("\r\n");
+}
+
+fn escape_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\r', '\n']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::escape_field;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn escape_csv_fields() {
+        assert_eq!("hello", escape_field("hello"));
+        assert_eq!("\"a, b\"", escape_field("a, b"));
+        assert_eq!("\"say \"\"hi\"\"\"", escape_field("say \"hi\""));
+    }
+}
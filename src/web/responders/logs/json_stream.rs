@@ -1,19 +1,20 @@
+use super::serialization_pool;
 use crate::{
     db::schema::StructuredMessage,
     logs::{
-        schema::message::{BasicMessage, FullMessage, ResponseMessage},
+        schema::message::{BasicMessage, FullMessage, ParsedTagsMessage, ResponseMessage},
         stream::LogsStream,
     },
     Result,
 };
-use futures::{stream::TryChunks, Future, Stream, StreamExt, TryStreamExt};
+use futures::{Stream, StreamExt};
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 use std::{
     collections::VecDeque,
+    mem,
     pin::Pin,
     task::{Context, Poll},
 };
-use tokio::pin;
 use tracing::error;
 
 const HEADER: &str = r#"{"messages":["#;
@@ -25,23 +26,92 @@ const CHUNK_SIZE: usize = 3000;
 pub enum JsonResponseType {
     Basic,
     Full,
+    /// Full messages with parsed `color` and `badges` fields
+    ParsedTags,
+}
+
+/// Which IRC tags are included in the `tags` field of JSON messages
+#[derive(Default)]
+pub enum TagsFilter {
+    #[default]
+    All,
+    /// Leave out the field
+    Omit,
+    Only(Vec<String>),
+}
+
+impl TagsFilter {
+    /// `none` leaves out all tags, otherwise only the listed ones are kept
+    pub fn new(tags: Vec<String>) -> Self {
+        match tags.as_slice() {
+            [] => Self::All,
+            [tag] if tag == "none" => Self::Omit,
+            _ => Self::Only(tags),
+        }
+    }
+
+    pub fn apply(&self, message: &mut BasicMessage) {
+        match self {
+            TagsFilter::All => (),
+            TagsFilter::Omit => message.tags = None,
+            TagsFilter::Only(names) => {
+                if let Some(tags) = &mut message.tags {
+                    tags.retain(|tag, _| names.iter().any(|name| name == tag));
+                }
+            }
+        }
+    }
+}
+
+/// Which optional fields are included in JSON messages
+#[derive(Default)]
+pub struct JsonFields {
+    pub tags: TagsFilter,
+    /// Leave out the raw IRC line
+    pub omit_raw: bool,
+    /// Add the position of each message as the `line` field, starting at this number
+    pub first_line_number: Option<u64>,
+}
+
+impl JsonFields {
+    pub(super) fn apply<'a, T: ResponseMessage<'a>>(&self, message: &mut T) {
+        self.tags.apply(message.basic_mut());
+        if self.omit_raw {
+            message.omit_raw();
+        }
+    }
 }
 
 pub struct JsonLogsStream {
-    inner: TryChunks<LogsStream>,
+    inner: LogsStream,
+    /// Messages which were read but not serialized yet
+    pending: Vec<StructuredMessage<'static>>,
     is_start: bool,
     is_end: bool,
     response_type: JsonResponseType,
+    fields: JsonFields,
+    /// Number of the next serialized message, if lines are numbered
+    next_line_number: Option<u64>,
 }
 
 impl JsonLogsStream {
-    pub fn new(stream: LogsStream, response_type: JsonResponseType) -> Self {
-        let inner = stream.try_chunks(CHUNK_SIZE);
+    pub fn new(stream: LogsStream, response_type: JsonResponseType, fields: JsonFields) -> Self {
         Self {
-            inner,
+            inner: stream,
+            pending: Vec::new(),
             is_start: true,
             is_end: false,
             response_type,
+            next_line_number: fields.first_line_number,
+            fields,
+        }
+    }
+
+    fn serialize_batch(&mut self, messages: &[StructuredMessage<'_>]) -> Vec<u8> {
+        match self.response_type {
+            JsonResponseType::Basic => self.serialize_chunk::<BasicMessage>(messages),
+            JsonResponseType::Full => self.serialize_chunk::<FullMessage>(messages),
+            JsonResponseType::ParsedTags => self.serialize_chunk::<ParsedTagsMessage>(messages),
         }
     }
 
@@ -49,12 +119,24 @@ impl JsonLogsStream {
         &mut self,
         messages: &'a [StructuredMessage<'a>],
     ) -> Vec<u8> {
+        let first_line_number = self.next_line_number;
+        if let Some(line_number) = &mut self.next_line_number {
+            *line_number += messages.len() as u64;
+        }
+
         let mut messages: VecDeque<T> = messages
             .iter()
-            .filter_map(|msg| match T::from_structured(msg) {
-                Ok(parsed) => Some(parsed),
+            .enumerate()
+            .filter_map(|(i, msg)| match T::from_structured(msg) {
+                Ok(mut parsed) => {
+                    self.fields.apply(&mut parsed);
+                    if let Some(first_line_number) = first_line_number {
+                        parsed.basic_mut().line = Some(first_line_number + i as u64);
+                    }
+                    Some(parsed)
+                }
                 Err(err) => {
-                    error!("Could not parse message {msg:?} from DB: {err}");
+                    error!(?msg, error = %err, "Could not parse message from DB");
                     None
                 }
             })
@@ -71,14 +153,16 @@ impl JsonLogsStream {
             }
         }
 
-        let serialized_messages: Vec<_> = messages
-            .into_par_iter()
-            .map(|message| {
-                let mut message_buf = Vec::with_capacity(JSON_MESSAGE_SIZE);
-                serde_json::to_writer(&mut message_buf, &message).unwrap();
-                message_buf
-            })
-            .collect();
+        let serialized_messages: Vec<_> = serialization_pool().install(|| {
+            messages
+                .into_par_iter()
+                .map(|message| {
+                    let mut message_buf = Vec::with_capacity(JSON_MESSAGE_SIZE);
+                    serde_json::to_writer(&mut message_buf, &message).unwrap();
+                    message_buf
+                })
+                .collect()
+        });
 
         for message_buf in serialized_messages {
             buf.push(b',');
@@ -92,40 +176,89 @@ impl JsonLogsStream {
 impl Stream for JsonLogsStream {
     type Item = Result<Vec<u8>>;
 
+    /// Serializes at most `CHUNK_SIZE` messages per poll, so that the work stops soon after the response is dropped
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         if self.is_end {
             return Poll::Ready(None);
         }
 
-        let fut = self.inner.next();
-        pin!(fut);
-
-        match fut.poll(cx) {
-            Poll::Ready(Some(result)) => match result {
-                Ok(chunks) => {
-                    let mut buf = Vec::new();
-                    for chunk in chunks {
-                        let chunk_buf = match self.response_type {
-                            JsonResponseType::Basic => self.serialize_chunk::<BasicMessage>(&chunk),
-                            JsonResponseType::Full => self.serialize_chunk::<FullMessage>(&chunk),
-                        };
-                        buf.extend_from_slice(&chunk_buf);
-                    }
+        loop {
+            if self.pending.len() >= CHUNK_SIZE {
+                let batch: Vec<_> = self.pending.drain(..CHUNK_SIZE).collect();
+                return Poll::Ready(Some(Ok(self.serialize_batch(&batch))));
+            }
+
+            match self.inner.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(messages))) => self.pending.extend(messages),
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) => {
+                    self.is_end = true;
+                    let batch = mem::take(&mut self.pending);
 
-                    Poll::Ready(Some(Ok(buf)))
+                    let buf = if !batch.is_empty() {
+                        let mut buf = self.serialize_batch(&batch);
+                        buf.extend_from_slice(FOOTER.as_bytes());
+                        buf
+                    } else if self.is_start {
+                        // No lines were retrieved
+                        format!("{HEADER}{FOOTER}").into_bytes()
+                    } else {
+                        FOOTER.as_bytes().to_vec()
+                    };
+                    return Poll::Ready(Some(Ok(buf)));
                 }
-                Err(err) => Poll::Ready(Some(Err(err.1))),
-            },
-            Poll::Ready(None) => {
-                self.is_end = true;
-                // No lines were retrieved
-                if self.is_start {
-                    Poll::Ready(None)
-                } else {
-                    Poll::Ready(Some(Ok(FOOTER.as_bytes().to_vec())))
+                Poll::Pending if !self.pending.is_empty() => {
+                    let batch = mem::take(&mut self.pending);
+                    return Poll::Ready(Some(Ok(self.serialize_batch(&batch))));
                 }
+                Poll::Pending => return Poll::Pending,
             }
-            Poll::Pending => Poll::Pending,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{JsonFields, JsonLogsStream, JsonResponseType};
+    use crate::{
+        db::schema::{StructuredMessage, UnstructuredMessage},
+        logs::stream::LogsStream,
+    };
+    use futures::TryStreamExt;
+    use pretty_assertions::assert_eq;
+
+    async fn serialize(fields: JsonFields) -> serde_json::Value {
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "68136884",
+            timestamp: 1686947117960,
+            raw: "@badges=;color=;display-name=Supibot;emotes=;room-id=22484632;tmi-sent-ts=1686947117960;user-id=68136884 :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :+join",
+        };
+        let structured = StructuredMessage::from_unstructured(&unstructured)
+            .unwrap()
+            .into_owned();
+
+        let stream = LogsStream::new_provided(vec![structured]).unwrap();
+        let chunks: Vec<Vec<u8>> = JsonLogsStream::new(stream, JsonResponseType::Full, fields)
+            .try_collect()
+            .await
+            .unwrap();
+
+        let body: serde_json::Value = serde_json::from_slice(&chunks.concat()).unwrap();
+        body["messages"][0].clone()
+    }
+
+    #[tokio::test]
+    async fn omit_raw_line() {
+        let message = serialize(JsonFields::default()).await;
+        assert!(message.get("raw").is_some());
+
+        let message = serialize(JsonFields {
+            omit_raw: true,
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(Some("+join"), message["text"].as_str());
+        assert!(message.get("raw").is_none());
+    }
+}
@@ -1,50 +1,127 @@
+mod csv_stream;
 mod json_stream;
 mod ndjson_stream;
 mod text_stream;
 
-pub use json_stream::JsonResponseType;
+pub use json_stream::{JsonFields, JsonResponseType, TagsFilter};
+pub use text_stream::TextFormat;
 
 use self::{
-    json_stream::JsonLogsStream, ndjson_stream::NdJsonLogsStream, text_stream::TextLogsStream,
+    csv_stream::CsvLogsStream, json_stream::JsonLogsStream, ndjson_stream::NdJsonLogsStream,
+    text_stream::TextLogsStream,
+};
+use crate::{
+    config::ResponseFormat,
+    logs::{schema::message::ParsedTagsMessage, stream::LogsStream},
 };
-use crate::logs::{schema::message::FullMessage, stream::LogsStream};
 use aide::OperationOutput;
 use axum::{
     body::Body,
-    http::HeaderValue,
+    http::{HeaderMap, HeaderValue},
     response::{IntoResponse, IntoResponseParts, Response},
     Json,
 };
 use futures::TryStreamExt;
 use indexmap::IndexMap;
 use mime_guess::mime::{APPLICATION_JSON, TEXT_PLAIN_UTF_8};
-use reqwest::header::CONTENT_TYPE;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use reqwest::header::{ACCEPT, CONTENT_TYPE, VARY};
 use schemars::JsonSchema;
+use std::sync::OnceLock;
+
+static SERIALIZATION_POOL: OnceLock<ThreadPool> = OnceLock::new();
 
 pub struct LogsResponse {
     pub stream: LogsStream,
     pub response_type: LogsResponseType,
+    /// Replace the users of all messages with pseudonyms (`anonymize` param)
+    pub anonymize: bool,
 }
 
 pub enum LogsResponseType {
     Raw,
-    Text,
-    Json(JsonResponseType),
-    NdJson,
+    /// With line numbers starting at the given one
+    Text(TextFormat, Option<u64>),
+    Json(JsonResponseType, JsonFields),
+    NdJson(JsonFields),
+    /// With the given tags as additional columns
+    Csv {
+        tags: Vec<String>,
+    },
+}
+
+impl LogsResponseType {
+    /// Picks the first supported media type listed in the `Accept` header
+    pub fn from_accept_header(headers: &HeaderMap) -> Option<Self> {
+        headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .find_map(|media_type| {
+                let media_type = media_type.split(';').next().unwrap_or_default().trim();
+                match media_type {
+                    "application/json" => {
+                        Some(Self::Json(JsonResponseType::Full, JsonFields::default()))
+                    }
+                    "application/x-ndjson" => Some(Self::NdJson(JsonFields::default())),
+                    "text/plain" => Some(Self::Text(TextFormat::default(), None)),
+                    "text/csv" => Some(Self::Csv { tags: vec![] }),
+                    _ => None,
+                }
+            })
+    }
+
+    /// Applies the `tags` param: the tags included in JSON messages, or the additional columns of CSV responses
+    pub fn with_tags(self, tags: Vec<String>) -> Self {
+        match self {
+            Self::Json(response_type, fields) => Self::Json(
+                response_type,
+                JsonFields {
+                    tags: TagsFilter::new(tags),
+                    ..fields
+                },
+            ),
+            Self::NdJson(fields) => Self::NdJson(JsonFields {
+                tags: TagsFilter::new(tags),
+                ..fields
+            }),
+            Self::Csv { .. } => Self::Csv { tags },
+            response_type => response_type,
+        }
+    }
+}
+
+impl From<ResponseFormat> for LogsResponseType {
+    fn from(format: ResponseFormat) -> Self {
+        match format {
+            ResponseFormat::Text => Self::Text(TextFormat::default(), None),
+            ResponseFormat::Json => Self::Json(JsonResponseType::Full, JsonFields::default()),
+            ResponseFormat::NdJson => Self::NdJson(JsonFields::default()),
+            ResponseFormat::Csv => Self::Csv { tags: vec![] },
+            ResponseFormat::Raw => Self::Raw,
+        }
+    }
 }
 
 /// Used for schema only, actual serialization is manual
 #[derive(JsonSchema)]
 pub struct JsonLogsResponse<'a> {
     #[allow(dead_code)]
-    pub messages: Vec<FullMessage<'a>>,
+    pub messages: Vec<ParsedTagsMessage<'a>>,
 }
 
 impl IntoResponse for LogsResponse {
     fn into_response(self) -> Response {
-        match self.response_type {
+        let stream = if self.anonymize {
+            self.stream.anonymized()
+        } else {
+            self.stream
+        };
+
+        let mut response = match self.response_type {
             LogsResponseType::Raw => {
-                let stream = self.stream.map_ok(|chunk| {
+                let stream = stream.map_ok(|chunk| {
                     let mut buf = String::new();
                     for msg in chunk {
                         buf.push_str(&msg.to_raw_irc());
@@ -59,32 +136,68 @@ impl IntoResponse for LogsResponse {
                 )
                     .into_response()
             }
-            LogsResponseType::Text => {
-                let stream = TextLogsStream::new(self.stream);
+            LogsResponseType::Text(format, first_line_number) => {
+                let stream = TextLogsStream::new(stream, format, first_line_number);
                 (
                     set_content_type(&TEXT_PLAIN_UTF_8),
                     Body::from_stream(stream),
                 )
                     .into_response()
             }
-            LogsResponseType::Json(response_type) => {
-                let stream = JsonLogsStream::new(self.stream, response_type);
+            LogsResponseType::Json(response_type, fields) => {
+                let stream = JsonLogsStream::new(stream, response_type, fields);
                 (
                     set_content_type(&APPLICATION_JSON),
                     Body::from_stream(stream),
                 )
                     .into_response()
             }
-            LogsResponseType::NdJson => {
-                let stream = NdJsonLogsStream::new(self.stream);
+            LogsResponseType::NdJson(fields) => {
+                let stream = NdJsonLogsStream::new(stream, fields);
                 (
                     set_content_type(&"application/x-ndjson"),
                     Body::from_stream(stream),
                 )
                     .into_response()
             }
-        }
+            LogsResponseType::Csv { tags } => {
+                let stream = CsvLogsStream::new(stream, tags);
+                (
+                    set_content_type(&"text/csv; charset=utf-8"),
+                    Body::from_stream(stream),
+                )
+                    .into_response()
+            }
+        };
+
+        // The response type can depend on the `Accept` header
+        response
+            .headers_mut()
+            .insert(VARY, HeaderValue::from_static("Accept"));
+        response
+    }
+}
+
+pub fn init_serialization_pool(threads: Option<usize>) -> anyhow::Result<()> {
+    let pool = build_serialization_pool(threads)?;
+    SERIALIZATION_POOL
+        .set(pool)
+        .map_err(|_| anyhow::anyhow!("Serialization pool is already initialized"))
+}
+
+/// Dedicated pool for serializing messages, so that log responses don't compete with other parallel work
+fn serialization_pool() -> &'static ThreadPool {
+    SERIALIZATION_POOL.get_or_init(|| {
+        build_serialization_pool(None).expect("Could not build serialization thread pool")
+    })
+}
+
+fn build_serialization_pool(threads: Option<usize>) -> anyhow::Result<ThreadPool> {
+    let mut builder = ThreadPoolBuilder::new().thread_name(|i| format!("rustlog-serializer-{i}"));
+    if let Some(threads) = threads {
+        builder = builder.num_threads(threads);
     }
+    Ok(builder.build()?)
 }
 
 fn set_content_type(content_type: &'static impl AsRef<str>) -> impl IntoResponseParts {
@@ -1,17 +1,19 @@
+use super::{json_stream::JsonFields, serialization_pool};
 use crate::{
+    db::schema::StructuredMessage,
     logs::{
         schema::message::{BasicMessage, ResponseMessage},
         stream::LogsStream,
     },
     Result,
 };
-use futures::{stream::TryChunks, Future, Stream, StreamExt, TryStreamExt};
+use futures::{Stream, StreamExt};
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 use std::{
+    mem,
     pin::Pin,
     task::{Context, Poll},
 };
-use tokio::pin;
 use tracing::error;
 
 /// Rough estimation of how big a single message is in JSON format
@@ -19,58 +21,129 @@ const JSON_MESSAGE_SIZE: usize = 1024;
 const CHUNK_SIZE: usize = 3000;
 
 pub struct NdJsonLogsStream {
-    inner: TryChunks<LogsStream>,
+    inner: LogsStream,
+    /// Messages which were read but not serialized yet
+    pending: Vec<StructuredMessage<'static>>,
+    is_end: bool,
+    fields: JsonFields,
 }
 
 impl NdJsonLogsStream {
-    pub fn new(stream: LogsStream) -> Self {
-        let inner = stream.try_chunks(CHUNK_SIZE);
-        Self { inner }
+    pub fn new(stream: LogsStream, fields: JsonFields) -> Self {
+        Self {
+            inner: stream,
+            pending: Vec::new(),
+            is_end: false,
+            fields,
+        }
     }
 }
 
+fn serialize_messages(messages: &[StructuredMessage<'_>], fields: &JsonFields) -> Vec<u8> {
+    let messages: Vec<BasicMessage> = messages
+        .iter()
+        .filter_map(|msg| match BasicMessage::from_structured(msg) {
+            Ok(mut parsed) => {
+                fields.apply(&mut parsed);
+                Some(parsed)
+            }
+            Err(err) => {
+                error!(?msg, error = %err, "Could not parse message from DB");
+                None
+            }
+        })
+        .collect();
+
+    let mut buf = Vec::with_capacity(JSON_MESSAGE_SIZE * messages.len());
+
+    let serialized_messages: Vec<_> = serialization_pool().install(|| {
+        messages
+            .into_par_iter()
+            .map(|message| {
+                let mut message_buf = Vec::with_capacity(JSON_MESSAGE_SIZE);
+                serde_json::to_writer(&mut message_buf, &message).unwrap();
+                message_buf
+            })
+            .collect()
+    });
+
+    for message_buf in serialized_messages {
+        buf.extend(message_buf);
+        buf.extend(b"\r\n");
+    }
+
+    buf
+}
+
 impl Stream for NdJsonLogsStream {
     type Item = Result<Vec<u8>>;
 
+    /// Serializes at most `CHUNK_SIZE` messages per poll, so that the work stops soon after the response is dropped
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let fut = self.inner.next();
-        pin!(fut);
+        loop {
+            if self.pending.len() >= CHUNK_SIZE {
+                let batch: Vec<_> = self.pending.drain(..CHUNK_SIZE).collect();
+                return Poll::Ready(Some(Ok(serialize_messages(&batch, &self.fields))));
+            }
+            if self.is_end {
+                return Poll::Ready(None);
+            }
 
-        fut.poll(cx).map(|maybe_result| {
-            maybe_result.map(|result| match result {
-                Ok(chunk) => {
-                    let messages: Vec<BasicMessage> = chunk
-                        .iter()
-                        .flatten()
-                        .filter_map(|msg| match BasicMessage::from_structured(msg) {
-                            Ok(parsed) => Some(parsed),
-                            Err(err) => {
-                                error!("Could not parse message {msg:?} from DB: {err}");
-                                None
-                            }
-                        })
-                        .collect();
+            match self.inner.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(messages))) => self.pending.extend(messages),
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) => {
+                    self.is_end = true;
+                    if !self.pending.is_empty() {
+                        let batch = mem::take(&mut self.pending);
+                        return Poll::Ready(Some(Ok(serialize_messages(&batch, &self.fields))));
+                    }
+                }
+                Poll::Pending if !self.pending.is_empty() => {
+                    let batch = mem::take(&mut self.pending);
+                    return Poll::Ready(Some(Ok(serialize_messages(&batch, &self.fields))));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
 
-                    let mut buf = Vec::with_capacity(JSON_MESSAGE_SIZE * messages.len());
+#[cfg(test)]
+mod tests {
+    use super::{JsonFields, NdJsonLogsStream};
+    use crate::{
+        db::schema::{StructuredMessage, UnstructuredMessage},
+        logs::stream::LogsStream,
+    };
+    use futures::TryStreamExt;
+    use pretty_assertions::assert_eq;
 
-                    let serialized_messages: Vec<_> = messages
-                        .into_par_iter()
-                        .map(|message| {
-                            let mut message_buf = Vec::with_capacity(JSON_MESSAGE_SIZE);
-                            serde_json::to_writer(&mut message_buf, &message).unwrap();
-                            message_buf
-                        })
-                        .collect();
+    #[tokio::test]
+    async fn omit_raw_line() {
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "68136884",
+            timestamp: 1686947117960,
+            raw: "@badges=;color=;display-name=Supibot;emotes=;room-id=22484632;tmi-sent-ts=1686947117960;user-id=68136884 :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :+join",
+        };
+        let structured = StructuredMessage::from_unstructured(&unstructured)
+            .unwrap()
+            .into_owned();
 
-                    for message_buf in serialized_messages {
-                        buf.extend(message_buf);
-                        buf.extend(b"\r\n");
-                    }
+        let stream = LogsStream::new_provided(vec![structured]).unwrap();
+        let fields = JsonFields {
+            omit_raw: true,
+            ..Default::default()
+        };
+        let chunks: Vec<Vec<u8>> = NdJsonLogsStream::new(stream, fields)
+            .try_collect()
+            .await
+            .unwrap();
 
-                    Ok(buf)
-                }
-                Err(err) => Err(err.1),
-            })
-        })
+        let body = String::from_utf8(chunks.concat()).unwrap();
+        let line: serde_json::Value = serde_json::from_str(body.trim_end()).unwrap();
+        assert_eq!(Some("+join"), line["text"].as_str());
+        assert!(line.get("raw").is_none());
     }
 }
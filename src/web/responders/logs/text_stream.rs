@@ -1,5 +1,7 @@
-use crate::{logs::stream::LogsStream, Result};
+use crate::{db::schema::StructuredMessage, logs::stream::LogsStream, Result};
 use futures::{stream::TryChunks, Future, Stream, StreamExt, TryStreamExt};
+use schemars::JsonSchema;
+use serde::Deserialize;
 use std::{
     fmt::Write,
     pin::Pin,
@@ -10,22 +12,96 @@ use tokio::pin;
 const CHUNK_SIZE: usize = 3000;
 const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
 
+/// How lines are rendered in plain text responses
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TextFormat {
+    /// `[2023-06-16 20:25:17] #channel username: text`
+    #[default]
+    Full,
+    /// `username: text`
+    Compact,
+    /// `text`
+    MessageOnly,
+    /// `+3s username: text`, with the time between the previous line and this one (also when the lines are reversed)
+    Replay,
+}
+
 pub struct TextLogsStream {
     inner: TryChunks<LogsStream>,
+    format: TextFormat,
+    /// Timestamp of the last written message, used by the replay format
+    last_timestamp: Option<u64>,
+    /// Number of the next line, if lines are numbered
+    next_line_number: Option<u64>,
 }
 
 impl TextLogsStream {
-    pub fn new(stream: LogsStream) -> Self {
+    pub fn new(stream: LogsStream, format: TextFormat, first_line_number: Option<u64>) -> Self {
         let inner = stream.try_chunks(CHUNK_SIZE);
-        Self { inner }
+        Self {
+            inner,
+            format,
+            last_timestamp: None,
+            next_line_number: first_line_number,
+        }
     }
 }
 
+fn write_message(
+    output: &mut String,
+    msg: &StructuredMessage,
+    format: TextFormat,
+    last_timestamp: &mut Option<u64>,
+    next_line_number: &mut Option<u64>,
+) {
+    if let Some(line_number) = next_line_number {
+        let _ = write!(output, "{line_number} ");
+        *line_number += 1;
+    }
+
+    let text = msg.user_friendly_text();
+    let username = &msg.user_login;
+    let previous_timestamp = last_timestamp.replace(msg.timestamp);
+
+    let _ = match format {
+        TextFormat::Full => {
+            let timestamp = chrono::DateTime::from_timestamp_millis(msg.timestamp as i64)
+                .unwrap_or_default()
+                .format(TIMESTAMP_FORMAT);
+            let channel = &msg.channel_login;
+
+            if !username.is_empty() {
+                write!(output, "[{timestamp}] #{channel} {username}: {text}\r\n")
+            } else {
+                write!(output, "[{timestamp}] #{channel} {text}\r\n")
+            }
+        }
+        TextFormat::Compact if !username.is_empty() => write!(output, "{username}: {text}\r\n"),
+        TextFormat::Compact | TextFormat::MessageOnly => write!(output, "{text}\r\n"),
+        TextFormat::Replay => {
+            let delta = previous_timestamp
+                .map(|previous| msg.timestamp.abs_diff(previous) / 1000)
+                .unwrap_or_default();
+
+            if !username.is_empty() {
+                write!(output, "+{delta}s {username}: {text}\r\n")
+            } else {
+                write!(output, "+{delta}s {text}\r\n")
+            }
+        }
+    };
+}
+
 impl Stream for TextLogsStream {
     type Item = Result<String>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let fut = self.inner.next();
+        let this = &mut *self;
+        let format = this.format;
+        let last_timestamp = &mut this.last_timestamp;
+        let next_line_number = &mut this.next_line_number;
+        let fut = this.inner.next();
         pin!(fut);
 
         fut.poll(cx).map(|item| {
@@ -33,21 +109,8 @@ impl Stream for TextLogsStream {
                 Ok(chunk) => {
                     let mut output = String::with_capacity(chunk.len() * 16);
 
-                    for msg in chunk.into_iter().flatten() {
-                        let timestamp =
-                            chrono::DateTime::from_timestamp_millis(msg.timestamp as i64)
-                                .unwrap_or_default()
-                                .format(TIMESTAMP_FORMAT);
-                        let text = msg.user_friendly_text();
-                        let channel = &msg.channel_login;
-                        let username = &msg.user_login;
-
-                        if !username.is_empty() {
-                            let _ =
-                                write!(output, "[{timestamp}] #{channel} {username}: {text}\r\n");
-                        } else {
-                            let _ = write!(output, "[{timestamp}] #{channel} {text}\r\n");
-                        }
+                    for msg in chunk.iter().flatten() {
+                        write_message(&mut output, msg, format, last_timestamp, next_line_number);
                     }
 
                     Ok(output)
@@ -57,3 +120,107 @@ impl Stream for TextLogsStream {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{write_message, TextFormat};
+    use crate::db::schema::{StructuredMessage, UnstructuredMessage};
+    use pretty_assertions::assert_eq;
+
+    fn render(raw: &str, format: TextFormat) -> String {
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "68136884",
+            timestamp: 1686947117960,
+            raw,
+        };
+        let structured = StructuredMessage::from_unstructured(&unstructured).unwrap();
+
+        let mut output = String::new();
+        write_message(&mut output, &structured, format, &mut None, &mut None);
+        output
+    }
+
+    #[test]
+    fn render_formats() {
+        let raw = "@badges=;color=;display-name=Supibot;emotes=;room-id=22484632;tmi-sent-ts=1686947117960;user-id=68136884 :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :+join";
+
+        assert_eq!(
+            "[2023-06-16 20:25:17] #forsen supibot: +join\r\n",
+            render(raw, TextFormat::Full)
+        );
+        assert_eq!("supibot: +join\r\n", render(raw, TextFormat::Compact));
+        assert_eq!("+join\r\n", render(raw, TextFormat::MessageOnly));
+        assert_eq!("+0s supibot: +join\r\n", render(raw, TextFormat::Replay));
+    }
+
+    #[test]
+    fn render_replay_deltas() {
+        let messages = [
+            (1686947117960, "+join"),
+            (1686947121100, "+join"),
+            (1686947181000, "+leave"),
+        ];
+
+        let render_replay = |messages: &[(u64, &str)]| {
+            let mut output = String::new();
+            let mut last_timestamp = None;
+            for &(timestamp, text) in messages {
+                let raw = format!("@badges=;color=;display-name=Supibot;emotes=;room-id=22484632;tmi-sent-ts={timestamp};user-id=68136884 :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :{text}");
+                let unstructured = UnstructuredMessage {
+                    channel_id: "22484632",
+                    user_id: "68136884",
+                    timestamp,
+                    raw: &raw,
+                };
+                let structured = StructuredMessage::from_unstructured(&unstructured).unwrap();
+                write_message(
+                    &mut output,
+                    &structured,
+                    TextFormat::Replay,
+                    &mut last_timestamp,
+                    &mut None,
+                );
+            }
+            output
+        };
+
+        assert_eq!(
+            "+0s supibot: +join\r\n+3s supibot: +join\r\n+59s supibot: +leave\r\n",
+            render_replay(&messages)
+        );
+
+        let mut reversed = messages;
+        reversed.reverse();
+        assert_eq!(
+            "+0s supibot: +leave\r\n+59s supibot: +join\r\n+3s supibot: +join\r\n",
+            render_replay(&reversed)
+        );
+    }
+
+    #[test]
+    fn render_line_numbers() {
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "68136884",
+            timestamp: 1686947117960,
+            raw: "@badges=;color=;display-name=Supibot;emotes=;room-id=22484632;tmi-sent-ts=1686947117960;user-id=68136884 :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :+join",
+        };
+        let structured = StructuredMessage::from_unstructured(&unstructured).unwrap();
+
+        let mut output = String::new();
+        let mut next_line_number = Some(20);
+        for _ in 0..2 {
+            write_message(
+                &mut output,
+                &structured,
+                TextFormat::Compact,
+                &mut None,
+                &mut next_line_number,
+            );
+        }
+
+        assert_eq!("20 supibot: +join\r\n21 supibot: +join\r\n", output);
+        assert_eq!(Some(22), next_line_number);
+    }
+}
@@ -1,7 +1,10 @@
-use super::responders::logs::{JsonResponseType, LogsResponseType};
+use super::responders::logs::{JsonFields, JsonResponseType, LogsResponseType, TextFormat};
+use crate::{config::ResponseFormat, db::schema::MessageType};
+use axum::http::HeaderMap;
+use chrono::{DateTime, NaiveDate, Utc};
 use schemars::JsonSchema;
 use serde::{Deserialize, Deserializer, Serialize};
-use std::fmt::Display;
+use std::{cmp::Ordering, fmt::Display, str::FromStr};
 
 #[derive(Serialize, JsonSchema)]
 pub struct ChannelsList {
@@ -50,6 +53,45 @@ pub struct ChannelLogsByDatePath {
     pub date: LogsPathDate,
 }
 
+#[derive(Deserialize, JsonSchema)]
+pub struct ChannelLogLinePath {
+    #[serde(flatten)]
+    pub channel_info: LogsPathChannel,
+    #[serde(flatten)]
+    pub date: LogsPathDate,
+    /// Line number in the day's logs, starting at 1
+    pub line: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ChannelMessagePath {
+    #[serde(flatten)]
+    pub channel_info: LogsPathChannel,
+    /// Twitch message id (the `id` tag)
+    pub message_id: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct PermalinkPath {
+    pub token: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ChannelLogsByDayPath {
+    #[serde(flatten)]
+    pub channel_info: LogsPathChannel,
+    /// `today`, `yesterday` or a date in the `YYYY-MM-DD` format
+    // Named `year` to not conflict with the `/:year/:month/:day` route
+    #[serde(rename = "year")]
+    pub day: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct DayParams {
+    /// IANA name of the timezone the day is resolved in, e.g. `Europe/Berlin`. UTC by default
+    pub timezone: Option<String>,
+}
+
 #[derive(Deserialize, JsonSchema)]
 pub struct LogsPathDate {
     pub year: String,
@@ -63,7 +105,7 @@ pub struct LogsPathChannel {
     pub channel: String,
 }
 
-#[derive(Deserialize, Debug, JsonSchema, Clone, Copy)]
+#[derive(Deserialize, Debug, JsonSchema, Clone, Copy, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct LogsParams {
     #[serde(default, deserialize_with = "deserialize_bool_param")]
@@ -76,26 +118,144 @@ pub struct LogsParams {
     pub reverse: bool,
     #[serde(default, deserialize_with = "deserialize_bool_param")]
     pub ndjson: bool,
+    #[serde(default, deserialize_with = "deserialize_bool_param")]
+    pub csv: bool,
+    /// How lines are rendered in plain text responses
+    #[serde(default, alias = "text_format")]
+    pub text_format: TextFormat,
+    /// Include the `color`, `badges`, `badge-info` subscriber months, `first-msg` and `emote-only` tags of chat messages as structured fields in JSON responses
+    #[serde(
+        default,
+        alias = "parse_tags",
+        deserialize_with = "deserialize_bool_param"
+    )]
+    pub parse_tags: bool,
+    /// Only include messages of this type (IRC command, e.g. `PRIVMSG` or `CLEARCHAT`).
+    /// Filtered in the database, so it also works for raw responses
+    #[serde(
+        default,
+        rename = "type",
+        deserialize_with = "deserialize_message_type"
+    )]
+    #[schemars(with = "Option<String>")]
+    pub message_type: Option<MessageType>,
+    /// Leave out messages shown through shared chat which were sent in another channel
+    #[serde(default, deserialize_with = "deserialize_bool_param")]
+    pub exclude_shared: bool,
+    /// Only include the first messages users sent in the channel (`first-msg` tag)
+    #[serde(
+        default,
+        alias = "first_msg_only",
+        deserialize_with = "deserialize_bool_param"
+    )]
+    pub first_msg_only: bool,
+    /// Only include messages of users subscribed for at least this many months (`badge-info` tag)
+    #[serde(default, alias = "min_sub_months")]
+    pub min_sub_months: Option<u32>,
+    /// Leave out the `raw` IRC line of messages in JSON and NDJSON responses
+    #[serde(
+        default,
+        alias = "omit_raw",
+        deserialize_with = "deserialize_bool_param"
+    )]
+    pub omit_raw: bool,
+    /// Replace the ids, logins and display names of users with pseudonyms, which are consistent within the response.
+    /// Mentions of users in message texts are kept
+    #[serde(default, deserialize_with = "deserialize_bool_param")]
+    pub anonymize: bool,
+    /// Add the SHA-256 checksum of the (uncompressed) response body as the `X-Content-SHA256` header.
+    /// The response is only sent once it is complete, so this is slower and uses more memory for large ranges.
+    /// Responses larger than 128 MiB are rejected with `413`
+    #[serde(default, deserialize_with = "deserialize_bool_param")]
+    pub checksum: bool,
+    /// Number the lines of text and JSON responses with their 0-based index, starting at `offset` (0 by default).
+    /// Without filters, this is the position of a line within the requested range
+    #[serde(
+        default,
+        alias = "line_numbers",
+        deserialize_with = "deserialize_bool_param"
+    )]
+    pub line_numbers: bool,
     pub limit: Option<u64>,
     pub offset: Option<u64>,
 }
 
 impl LogsParams {
-    pub fn response_type(&self) -> LogsResponseType {
+    /// Uses the query params if any were given, otherwise the `Accept` header, falling back to the route's default format
+    pub fn response_type(
+        &self,
+        headers: &HeaderMap,
+        default_format: ResponseFormat,
+    ) -> LogsResponseType {
+        let response_type = self
+            .explicit_response_type()
+            .or_else(|| LogsResponseType::from_accept_header(headers))
+            .unwrap_or_else(|| default_format.into());
+
+        match response_type {
+            LogsResponseType::Text(..) => {
+                LogsResponseType::Text(self.text_format, self.first_line_number())
+            }
+            LogsResponseType::Json(json_type, fields) => {
+                let json_type = match json_type {
+                    JsonResponseType::Full if self.parse_tags => JsonResponseType::ParsedTags,
+                    json_type => json_type,
+                };
+                let fields = JsonFields {
+                    omit_raw: self.omit_raw,
+                    first_line_number: self.first_line_number(),
+                    ..fields
+                };
+                LogsResponseType::Json(json_type, fields)
+            }
+            LogsResponseType::NdJson(fields) => LogsResponseType::NdJson(JsonFields {
+                omit_raw: self.omit_raw,
+                ..fields
+            }),
+            response_type => response_type,
+        }
+    }
+
+    /// Number of the first line if lines should be numbered
+    pub fn first_line_number(&self) -> Option<u64> {
+        self.line_numbers.then(|| self.offset.unwrap_or(0))
+    }
+
+    fn explicit_response_type(&self) -> Option<LogsResponseType> {
         if self.raw {
-            LogsResponseType::Raw
+            Some(LogsResponseType::Raw)
         } else if self.json_basic {
-            LogsResponseType::Json(JsonResponseType::Basic)
+            Some(LogsResponseType::Json(
+                JsonResponseType::Basic,
+                JsonFields::default(),
+            ))
         } else if self.json {
-            LogsResponseType::Json(JsonResponseType::Full)
+            Some(LogsResponseType::Json(
+                JsonResponseType::Full,
+                JsonFields::default(),
+            ))
         } else if self.ndjson {
-            LogsResponseType::NdJson
+            Some(LogsResponseType::NdJson(JsonFields::default()))
+        } else if self.csv {
+            Some(LogsResponseType::Csv { tags: vec![] })
         } else {
-            LogsResponseType::Text
+            None
         }
     }
 }
 
+fn deserialize_message_type<'de, D>(deserializer: D) -> Result<Option<MessageType>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<&str>::deserialize(deserializer)?
+        .map(|value| {
+            MessageType::from_str(&value.to_ascii_uppercase())
+                .map_err(|_| serde::de::Error::custom(format!("Unknown message type {value}")))
+        })
+        .transpose()
+}
+
 fn deserialize_bool_param<'de, D>(deserializer: D) -> Result<bool, D::Error>
 where
     D: Deserializer<'de>,
@@ -103,9 +263,116 @@ where
     Ok(Option::<&str>::deserialize(deserializer)?.is_some())
 }
 
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ChunkParams {
+    /// Return only this chunk (starting at 0) of the logs. The `X-Has-More` response header tells if there are further chunks
+    pub chunk: Option<u64>,
+    /// Amount of lines per chunk. Defaults to 500, at most 10000
+    pub chunk_size: Option<u64>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct LineRangeParams {
+    /// First line to return, starting at 1
+    #[serde(alias = "start_line")]
+    pub start_line: u64,
+    /// Line after the last one to return
+    #[serde(alias = "end_line")]
+    pub end_line: u64,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GrepUserParams {
+    /// Only return the lines of the user with this login or id
+    #[serde(alias = "grep_user")]
+    pub grep_user: Option<String>,
+}
+
+impl GrepUserParams {
+    /// The user to filter by, an empty param is ignored
+    pub fn user(&self) -> Option<&str> {
+        self.grep_user.as_deref().filter(|user| !user.is_empty())
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct DeltaParams {
+    /// Cursor from the `X-Cursor` header of a previous response. Without it, today's logs are returned
+    pub cursor: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct TailParams {
+    /// Amount of today's latest lines to send before the new ones. Defaults to 50, at most 1000
+    pub lines: Option<u64>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UserLogsParams {
+    /// Comma separated list of additional user ids whose logs should be merged into the response,
+    /// e.g. for users who recreated their account
+    pub ids: Option<String>,
+    /// Comma separated list of tags to include as additional columns in CSV responses,
+    /// or to keep in the `tags` of JSON messages (`none` to leave them out)
+    pub tags: Option<String>,
+    /// Include this many channel lines (at most 5) sent within a year right before each of the user's messages.
+    /// Only works for responses with at most 200 of the user's messages
+    #[serde(alias = "context_before")]
+    pub context_before: Option<u64>,
+}
+
+impl UserLogsParams {
+    pub fn ids(&self) -> impl Iterator<Item = &str> {
+        split_list(self.ids.as_deref())
+    }
+
+    pub fn tags(&self) -> impl Iterator<Item = &str> {
+        split_list(self.tags.as_deref())
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct TagsParams {
+    /// Comma separated list of tags to include as additional columns in CSV responses,
+    /// or to keep in the `tags` of JSON messages (`none` to leave them out)
+    pub tags: Option<String>,
+}
+
+impl TagsParams {
+    pub fn tags(&self) -> Vec<String> {
+        split_list(self.tags.as_deref())
+            .map(str::to_owned)
+            .collect()
+    }
+}
+
+fn split_list(list: Option<&str>) -> impl Iterator<Item = &str> {
+    list.unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
 #[derive(Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct SearchParams {
-    pub q: String,
+    /// Case insensitive text to search for
+    #[serde(alias = "search")]
+    pub q: Option<String>,
+    /// Regular expression the message text has to match
+    pub regex: Option<String>,
+    #[schemars(with = "Option<String>")]
+    /// RFC 3339 start date
+    pub from: Option<DateTime<Utc>>,
+    #[schemars(with = "Option<String>")]
+    /// RFC 3339 end date
+    pub to: Option<DateTime<Utc>>,
+    /// Only include cheers with at least this amount of bits
+    pub min_bits: Option<u64>,
     #[serde(flatten)]
     pub logs_params: LogsParams,
 }
@@ -158,9 +425,312 @@ pub enum ChannelParam {
     ChannelId(String),
 }
 
+#[derive(Deserialize, JsonSchema)]
+pub struct TopUsersParams {
+    /// Month in the `YYYY-MM` format. Defaults to the current month
+    pub month: Option<String>,
+    /// Amount of users to return. Defaults to 10, at most 100
+    pub limit: Option<u64>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct AvailableUsersParams {
+    /// Month in the `YYYY-MM` format. Defaults to the current month
+    pub month: Option<String>,
+    /// Amount of users to return, starting with the most active ones. Defaults to 1000, at most 10000
+    pub limit: Option<u64>,
+    /// Amount of users to skip, for paging through them
+    pub offset: Option<u64>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct TopUsers {
+    pub users: Vec<TopUser>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct TopUser {
+    #[serde(rename = "userID")]
+    pub user_id: String,
+    /// Current login of the user, if it could be resolved
+    pub name: Option<String>,
+    pub count: u64,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct LineCount {
+    pub count: u64,
+}
+
+/// How the logs of a day are stored
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct LogDayInfo {
+    /// ClickHouse table the logs are stored in
+    pub table: &'static str,
+    /// Highest numbered database migration that was run
+    pub schema_version: Option<String>,
+    /// Secondary indexes on the table, e.g. `message_id_index`
+    pub secondary_indexes: Vec<String>,
+    pub line_count: u64,
+    /// Monthly partition (`YYYYMM`) containing the day. Partitions are shared by all channels
+    pub partition: String,
+    pub partition_rows: u64,
+    /// Size of the partition's data on disk, after compression
+    pub partition_compressed_bytes: u64,
+    pub partition_uncompressed_bytes: u64,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct RecentMessagesPath {
+    /// Channel login
+    pub channel: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct RecentMessagesParams {
+    /// Maximum amount of messages to return. Defaults to 800
+    pub limit: Option<u64>,
+}
+
+/// Same format as the recent-messages service (https://recent-messages.robotty.de)
+#[derive(Serialize, JsonSchema)]
+pub struct RecentMessages {
+    /// Raw IRC messages, oldest first
+    pub messages: Vec<String>,
+    pub error: Option<String>,
+    pub error_code: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct UserPath {
+    pub user: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct UserBans {
+    /// Bans and timeouts of the user in all logged channels, oldest first
+    pub bans: Vec<UserBan>,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UserBan {
+    #[serde(rename = "channelID")]
+    pub channel_id: String,
+    pub channel: String,
+    #[schemars(with = "String")]
+    pub timestamp: DateTime<Utc>,
+    /// Timeout duration in seconds, `null` for permanent bans
+    pub duration: Option<u64>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct UserBansParams {
+    /// Amount of bans to return. Defaults to 100, at most 1000
+    pub limit: Option<u64>,
+    /// Amount of bans to skip, for paging through them
+    pub offset: Option<u64>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BanSummaryParams {
+    /// RFC 3339 start date. Defaults to 24 hours before `to`
+    #[schemars(with = "Option<String>")]
+    pub from: Option<DateTime<Utc>>,
+    /// RFC 3339 end date. Defaults to now
+    #[schemars(with = "Option<String>")]
+    pub to: Option<DateTime<Utc>>,
+    /// Bans at most this many seconds apart are grouped together. Defaults to 60
+    #[serde(alias = "window_seconds")]
+    pub window_seconds: Option<u64>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct BanSummary {
+    /// Groups of bans and timeouts, oldest first
+    pub groups: Vec<BanGroup>,
+}
+
+#[derive(Serialize, JsonSchema, Debug, PartialEq)]
+pub struct BanGroup {
+    /// Time of the first ban in the group
+    #[schemars(with = "String")]
+    pub start: DateTime<Utc>,
+    /// Time of the last ban in the group
+    #[schemars(with = "String")]
+    pub end: DateTime<Utc>,
+    pub bans: Vec<ChannelBan>,
+}
+
+#[derive(Serialize, JsonSchema, Debug, PartialEq)]
+pub struct ChannelBan {
+    #[serde(rename = "userID")]
+    pub user_id: String,
+    /// Login of the user at the time of the ban
+    pub user: String,
+    #[schemars(with = "String")]
+    pub timestamp: DateTime<Utc>,
+    /// Timeout duration in seconds, `null` for permanent bans
+    pub duration: Option<u64>,
+}
+
 #[derive(Deserialize, JsonSchema)]
 pub struct UserLogPathParams {
     pub channel_id_type: ChannelIdType,
     pub channel: String,
     pub user: String,
 }
+
+#[derive(Serialize, JsonSchema)]
+pub struct UserExists {
+    pub exists: bool,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct UserDayDiffParams {
+    /// First day, `today`, `yesterday` or a date in the `YYYY-MM-DD` format (UTC)
+    pub a: String,
+    /// Second day, in the same format
+    pub b: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UserDayDiff {
+    pub a: UserDayActivity,
+    pub b: UserDayActivity,
+    /// Messages on day `b` minus messages on day `a`
+    pub difference: i64,
+    /// Relative change from day `a` to day `b`, `null` if there were no messages on day `a`
+    pub change_percent: Option<f64>,
+    /// Human readable description of the difference
+    pub summary: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct UserDayActivity {
+    #[schemars(with = "String")]
+    pub date: NaiveDate,
+    /// Amount of lines in the user's logs on the day
+    pub messages: u64,
+}
+
+impl UserDayDiff {
+    pub fn new(a: UserDayActivity, b: UserDayActivity) -> Self {
+        let difference = b.messages as i64 - a.messages as i64;
+        let change_percent =
+            (a.messages > 0).then(|| difference as f64 / a.messages as f64 * 100.0);
+
+        let summary = match difference.cmp(&0) {
+            Ordering::Equal => format!("Same amount of messages on {} as on {}", b.date, a.date),
+            Ordering::Greater => {
+                format!(
+                    "{difference} more messages on {} than on {}",
+                    b.date, a.date
+                )
+            }
+            Ordering::Less => format!(
+                "{} fewer messages on {} than on {}",
+                -difference, b.date, a.date
+            ),
+        };
+
+        Self {
+            a,
+            b,
+            difference,
+            change_percent,
+            summary,
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct UserNthLinePath {
+    #[serde(flatten)]
+    pub user_path: UserLogPathParams,
+    /// Position of the message in all of the user's logs in the channel, starting at 1.
+    /// Negative positions count back from the latest message, which is `-1`
+    pub n: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GrepUserParams, LogsParams, UserDayActivity, UserDayDiff};
+    use chrono::NaiveDate;
+    use pretty_assertions::assert_eq;
+
+    fn day_diff(a: u64, b: u64) -> UserDayDiff {
+        let activity = |day, messages| UserDayActivity {
+            date: NaiveDate::from_ymd_opt(2023, 6, day).unwrap(),
+            messages,
+        };
+        UserDayDiff::new(activity(15, a), activity(16, b))
+    }
+
+    #[test]
+    fn line_numbers_start_at_offset() {
+        let params = LogsParams {
+            line_numbers: true,
+            ..Default::default()
+        };
+        assert_eq!(Some(0), params.first_line_number());
+
+        let params = LogsParams {
+            offset: Some(20),
+            ..params
+        };
+        assert_eq!(Some(20), params.first_line_number());
+
+        assert_eq!(None, LogsParams::default().first_line_number());
+    }
+
+    #[test]
+    fn ignore_empty_grep_user() {
+        let params = |grep_user: Option<&str>| GrepUserParams {
+            grep_user: grep_user.map(str::to_owned),
+        };
+
+        assert_eq!(Some("supibot"), params(Some("supibot")).user());
+        assert_eq!(None, params(Some("")).user());
+        assert_eq!(None, params(None).user());
+    }
+
+    #[test]
+    fn day_diff_with_added_lines() {
+        let diff = day_diff(4, 6);
+        assert_eq!(2, diff.difference);
+        assert_eq!(Some(50.0), diff.change_percent);
+        assert_eq!(
+            "2 more messages on 2023-06-16 than on 2023-06-15",
+            diff.summary
+        );
+    }
+
+    #[test]
+    fn day_diff_with_removed_lines() {
+        let diff = day_diff(4, 1);
+        assert_eq!(-3, diff.difference);
+        assert_eq!(Some(-75.0), diff.change_percent);
+        assert_eq!(
+            "3 fewer messages on 2023-06-16 than on 2023-06-15",
+            diff.summary
+        );
+    }
+
+    #[test]
+    fn day_diff_with_unchanged_lines() {
+        let diff = day_diff(4, 4);
+        assert_eq!(0, diff.difference);
+        assert_eq!(Some(0.0), diff.change_percent);
+        assert_eq!(
+            "Same amount of messages on 2023-06-16 as on 2023-06-15",
+            diff.summary
+        );
+
+        let diff = day_diff(0, 0);
+        assert_eq!(None, diff.change_percent);
+    }
+}
@@ -20,5 +20,9 @@ pub fn on_response(response: &Response, latency: Duration, span: &Span) {
     span.record("http.status", status.as_str());
     span.record("http.latency", ms.to_string().as_str());
 
-    info!("HTTP response {status} processed in {ms}ms");
+    info!(
+        status = status.as_u16(),
+        latency_ms = ms as u64,
+        "HTTP response processed"
+    );
 }